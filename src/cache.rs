@@ -0,0 +1,96 @@
+/// Bounded caches for things Discord won't tell us again
+///
+use std::collections::{HashMap, VecDeque};
+
+use crate::discord;
+
+/// How many messages to remember if the config doesn't say
+pub const DEFAULT_MESSAGE_CACHE_SIZE: usize = 1000;
+
+/// Keeps the most recent messages so that edits and deletes can be compared
+/// with the original. Once full, the oldest message is forgotten.
+pub struct MessageCache {
+    capacity: usize,
+    /// Message IDs, oldest first
+    order: VecDeque<String>,
+    messages: HashMap<String, discord::Message>
+}
+
+impl MessageCache {
+    pub fn new(capacity: usize) -> Self {
+        MessageCache {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            messages: HashMap::with_capacity(capacity)
+        }
+    }
+
+    pub fn insert(&mut self, message: discord::Message) {
+        if self.capacity == 0 {
+            return
+        }
+        if !self.messages.contains_key(&message.id) {
+            if self.order.len() >= self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.messages.remove(&oldest);
+                }
+            }
+            self.order.push_back(message.id.clone());
+        }
+        self.messages.insert(message.id.clone(), message);
+    }
+
+    pub fn get(&self, message_id: &String) -> Option<&discord::Message> {
+        self.messages.get(message_id)
+    }
+
+    pub fn get_mut(&mut self, message_id: &String) -> Option<&mut discord::Message> {
+        self.messages.get_mut(message_id)
+    }
+
+    pub fn remove(&mut self, message_id: &String) -> Option<discord::Message> {
+        let message = self.messages.remove(message_id)?;
+        self.order.retain(|id| id != message_id);
+        Some(message)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(id: &str) -> discord::Message {
+        discord::Message {
+            id: String::from(id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn oldest_messages_are_evicted() {
+        let mut cache = MessageCache::new(2);
+        cache.insert(message("1"));
+        cache.insert(message("2"));
+        cache.insert(message("3"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&String::from("1")).is_none());
+        assert!(cache.get(&String::from("3")).is_some());
+
+        // Updating a message does not make room
+        cache.insert(message("3"));
+        assert!(cache.get(&String::from("2")).is_some());
+
+        assert!(cache.remove(&String::from("2")).is_some());
+        cache.insert(message("4"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&String::from("3")).is_some());
+    }
+}
@@ -0,0 +1,216 @@
+/// The top level of config.json. The config can also be YAML or TOML, and
+/// any string in it can use `${ENV_VAR}` to read from the environment.
+///
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::controller::ConfigSchema;
+use crate::gateway::GatewayConfig;
+use crate::dispatch::DispatchConfig;
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub gateway: GatewayConfig,
+    /// How many messages to keep around for MESSAGE_UPDATE and
+    /// MESSAGE_DELETE rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_cache_size: Option<usize>,
+    /// How many workers run rules, and how many events they queue
+    #[serde(default)]
+    pub dispatch: DispatchConfig,
+    pub guilds: Vec<ConfigSchema>
+}
+
+/// Where the config is read from if neither `--config` nor this is set
+pub const CONFIG_ENV: &str = "GLENNBOT_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "./config.json";
+
+/// `--config <path>` or `--config=<path>`, then `env_path`, then
+/// ./config.json
+pub fn config_path<I: Iterator<Item = String>>(mut args: I, env_path: Option<String>) -> PathBuf {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if let Some(path) = args.next() {
+                return PathBuf::from(path)
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return PathBuf::from(path)
+        }
+    }
+    PathBuf::from(env_path.unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml
+}
+impl Format {
+    /// By extension. Anything that isn't YAML or TOML is JSON.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Json
+        }
+    }
+}
+
+impl Config {
+    /// Reads and parses the config file at `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let config = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Config::parse_as(&config, Format::from_path(path))
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    }
+
+    pub fn parse(config: &str) -> Result<Self, String> {
+        Config::parse_as(config, Format::Json)
+    }
+
+    /// Accepts either the full config object or, like older configs, a bare
+    /// list of guild rules.
+    pub fn parse_as(config: &str, format: Format) -> Result<Self, String> {
+        let mut value = match format {
+            Format::Json => serde_json::from_str::<Value>(config).map_err(|e| e.to_string())?,
+            Format::Yaml => serde_yaml::from_str::<Value>(config).map_err(|e| e.to_string())?,
+            Format::Toml => toml::from_str::<Value>(config).map_err(|e| e.to_string())?
+        };
+        interpolate(&mut value, &|name| env::var(name).ok())?;
+        if value.is_array() {
+            Ok(Config {
+                guilds: serde_json::from_value(value).map_err(|e| e.to_string())?,
+                ..Default::default()
+            })
+        } else {
+            serde_json::from_value(value).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Replaces `${NAME}` in every string value with `lookup(NAME)`. Unset
+/// variables are an error, so a secret can't silently end up empty. `$${`
+/// is a literal `${`.
+fn interpolate(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<(), String> {
+    match value {
+        Value::String(string) if string.contains("${") => {
+            let mut interpolated = String::new();
+            let mut rest = string.as_str();
+            while let Some(start) = rest.find("${") {
+                if rest[..start].ends_with('$') {
+                    interpolated.push_str(&rest[..start - 1]);
+                    interpolated.push_str("${");
+                    rest = &rest[start + 2..];
+                    continue
+                }
+                let end = match rest[start..].find('}') {
+                    Some(end) => start + end,
+                    None => return Err(format!("Unclosed '${{' in '{}'", string))
+                };
+                let name = &rest[start + 2..end];
+                let variable = lookup(name)
+                    .ok_or_else(|| format!("Environment variable '{}' is not set", name))?;
+                interpolated.push_str(&rest[..start]);
+                interpolated.push_str(&variable);
+                rest = &rest[end + 1..];
+            }
+            interpolated.push_str(rest);
+            *string = interpolated;
+        },
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate(value, lookup)?;
+            }
+        },
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                interpolate(value, lookup)?;
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gateway::{Encoding, Intent};
+
+    #[test]
+    fn parse_legacy_config() {
+        let config = Config::parse(r#"[{"rules":[],"guild_id":"1"}]"#).unwrap();
+        assert_eq!(config.guilds.len(), 1);
+        assert!(config.gateway.intents.enable.is_empty());
+        assert_eq!(config.gateway.encoding, Encoding::Json);
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config = Config::parse(r#"{"gateway":{"intents":{"enable":["GUILD_MEMBERS"]},"encoding":"etf"},"guilds":[{"rules":[],"guild_id":"1"}]}"#).unwrap();
+        assert_eq!(config.guilds[0].guild_id.as_deref(), Some("1"));
+        assert_eq!(config.gateway.intents.enable, vec![Intent::GUILD_MEMBERS]);
+        assert_eq!(config.gateway.encoding, Encoding::Etf);
+    }
+
+    #[test]
+    fn parse_yaml_and_toml() {
+        let yaml = r#"
+guilds:
+  - guild_id: "1"
+    rules:
+      - event: MESSAGE_CREATE
+        filters: { content: "^!ping" }
+        action: { type: Echo, options: { content: pong } }
+"#;
+        let toml = r#"
+[[guilds]]
+guild_id = "1"
+
+[[guilds.rules]]
+event = "MESSAGE_CREATE"
+filters = { content = "^!ping" }
+action = { type = "Echo", options = { content = "pong" } }
+"#;
+        for (config, format) in [(yaml, Format::Yaml), (toml, Format::Toml)] {
+            let config = Config::parse_as(config, format).unwrap();
+            assert_eq!(config.guilds[0].guild_id.as_deref(), Some("1"));
+            assert_eq!(config.guilds[0].rules.len(), 1);
+        }
+        assert_eq!(Format::from_path(Path::new("config.yml")), Format::Yaml);
+        assert_eq!(Format::from_path(Path::new("config.json")), Format::Json);
+    }
+
+    #[test]
+    fn interpolate_env_vars() {
+        let lookup = |name: &str| match name {
+            "WEBHOOK_TOKEN" => Some(String::from("secret")),
+            _ => None
+        };
+        let mut value = serde_json::json!({"headers": {"authorization": "Bearer ${WEBHOOK_TOKEN}"}, "list": ["${WEBHOOK_TOKEN}!"]});
+        interpolate(&mut value, &lookup).unwrap();
+        assert_eq!(value, serde_json::json!({"headers": {"authorization": "Bearer secret"}, "list": ["secret!"]}));
+
+        let mut escaped = serde_json::json!("$${NOT_SET} and $${ and ${WEBHOOK_TOKEN}");
+        interpolate(&mut escaped, &lookup).unwrap();
+        assert_eq!(escaped, serde_json::json!("${NOT_SET} and ${ and secret"));
+
+        let mut missing = serde_json::json!("${NOT_SET}");
+        assert_eq!(interpolate(&mut missing, &lookup).unwrap_err(), "Environment variable 'NOT_SET' is not set");
+    }
+
+    #[test]
+    fn config_path_from_args_or_env() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter();
+        assert_eq!(config_path(args(&["--config", "bot.yaml"]), Some(String::from("env.json"))), PathBuf::from("bot.yaml"));
+        assert_eq!(config_path(args(&["--config=bot.toml"]), None), PathBuf::from("bot.toml"));
+        assert_eq!(config_path(args(&[]), Some(String::from("env.json"))), PathBuf::from("env.json"));
+        assert_eq!(config_path(args(&[]), None), PathBuf::from("./config.json"));
+    }
+}
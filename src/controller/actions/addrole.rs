@@ -1,59 +1,60 @@
-use log::*;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize, Serializer, Deserializer};
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
-use tokio::time::delay_for;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::DiscordContext;
-use crate::gateway::{GatewayMessage, GatewayMessageType};
-use crate::controller::actions::{RunAction, GatewayMessageHandler};
+use crate::gateway::GatewayMessage;
+use crate::controller::actions::{RunAction, GatewayMessageHandler, MatchContext, find_role_id, Render};
+use crate::controller::template::TemplateString;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddRoleOptions {
-    pub role_name: Option<String>,
-    pub role_id: Option<String>
+    /// Supports `{{placeholders}}` if allowed_roles is set
+    pub role_name: Option<TemplateString>,
+    /// Supports `{{placeholders}}` if allowed_roles is set
+    pub role_id: Option<TemplateString>,
+    /// Capture group from the rule's filters that holds the role name, e.g.
+    /// `role` for `^!role (?P<role>\w+)`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role_capture: Option<String>,
+    /// The only role names this action may add. Required with
+    /// role_capture, so members can't pick any role they like.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_roles: Option<Vec<String>>
+}
+
+impl Render for AddRoleOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        let role_id = self.role_id.iter().map(|role_id| (String::from("role_id"), role_id));
+        let role_name = self.role_name.iter().map(|role_name| (String::from("role_name"), role_name));
+        role_id.chain(role_name).collect()
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        Ok(AddRoleOptions {
+            role_id: self.role_id.as_ref().map(|role_id| role_id.render(scope)).transpose()?,
+            role_name: self.role_name.as_ref().map(|role_name| role_name.render(scope)).transpose()?,
+            ..self.clone()
+        })
+    }
 }
 
 #[async_trait]
 impl GatewayMessageHandler for AddRoleOptions {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String> {
-        if let None = message.d {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, matched: &MatchContext) -> Result<(), String> {
+        if message.d.is_none() {
             return Ok(());
         }
         if let Some(guild_id) = message.d.clone().unwrap().get_guild_id() {
-            let get_role_id = || -> Option<String> {
-                if self.role_id == None {
-                    if let Some(role_name) = &self.role_name {
-                        if let Some(guild) = context.guild_map.get(&guild_id) {
-                            if let Some(roles) = &guild.roles {
-                                for role in roles {
-                                    if &role.name == role_name {
-                                        return Some(role.id.clone())
-                                    } else {
-                                        continue
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    return None
-                } else {
-                    return Some(self.role_id.clone().unwrap())
-                };
+            let role_name = match (self.role_capture.as_ref(), self.allowed_roles.as_ref()) {
+                (Some(group), Some(_)) => Some(matched.get(group).ok_or_else(|| format!("No capture group named '{}'", group))?.as_str()),
+                (Some(_), None) => return Err(String::from("role_capture needs allowed_roles")),
+                (None, _) => self.role_name.as_ref().map(TemplateString::as_str)
             };
+            let role_id = find_role_id(context, &guild_id, self.role_id.as_ref().map(TemplateString::as_str), role_name, self.allowed_roles.as_ref());
 
-            let user_id = match message.d.clone().unwrap() {
-                GatewayMessageType::MessageCreate(msg) => {
-                    Some(msg.author.id.clone())
-                },
-                GatewayMessageType::MessageReactionAdd(react) => {
-                    Some(react.user_id.clone())
-                },
-                _ => None
-            };
+            let user_id = message.d.as_ref().unwrap().get_user_id();
 
-            match (user_id, get_role_id()) {
+            match (user_id, role_id) {
                 (Some(user_id), Some(role_id)) => {
                     let data = AddRoleData {
                         user_id,
@@ -90,3 +91,37 @@ impl RunAction for AddRoleData {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::discord;
+
+    #[test]
+    fn only_allowed_roles_are_found() {
+        let context = DiscordContext::empty();
+        context.insert_guild(discord::Guild {
+            id: String::from("1"),
+            roles: Some(vec![
+                discord::Role { id: String::from("10"), name: String::from("artist"), ..Default::default() },
+                discord::Role { id: String::from("11"), name: String::from("admin"), ..Default::default() },
+            ]),
+            ..Default::default()
+        });
+        let allowed = vec![String::from("artist")];
+        let guild_id = String::from("1");
+        assert_eq!(find_role_id(&context, &guild_id, None, Some("artist"), Some(&allowed)), Some(String::from("10")));
+        assert_eq!(find_role_id(&context, &guild_id, None, Some("admin"), Some(&allowed)), None);
+        assert_eq!(find_role_id(&context, &guild_id, None, Some("admin"), None), Some(String::from("11")));
+        assert_eq!(find_role_id(&context, &guild_id, Some("12"), None, None), Some(String::from("12")));
+    }
+
+    #[test]
+    fn templated_roles_need_allowed_roles() {
+        use crate::controller::actions::ActionType;
+        let action = |options: &str| serde_json::from_str::<ActionType>(&format!(r#"{{"type":"AddRole","options":{}}}"#, options)).unwrap();
+        assert!(action(r#"{"role_name":"artist","role_id":null}"#).validate().is_ok());
+        assert!(action(r#"{"role_name":"{{content}}","role_id":null,"allowed_roles":["artist"]}"#).validate().is_ok());
+        assert_eq!(action(r#"{"role_name":"{{content}}","role_id":null}"#).validate().unwrap_err().0, "role_name");
+        assert_eq!(action(r#"{"role_name":null,"role_id":"{{content}}"}"#).validate().unwrap_err().0, "role_id");
+    }
+}
@@ -1,44 +1,91 @@
 use log::*;
 use base64;
 use async_trait::async_trait;
-use std::error::Error;
-use std::future::Future;
-use std::fmt;
-use serde::{Deserialize, Serialize, Serializer, Deserializer};
-use serde::de::{Visitor, MapAccess};
-use std::marker::PhantomData;
-use serde::ser::SerializeMap;
-
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
-use tokio::time::delay_for;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::DiscordContext;
+use crate::discord;
 use crate::controller::actions::{
     RunAction,
     GatewayMessageHandler,
     GatewayMessageType,
-    GatewayMessage
+    GatewayMessage,
+    MatchContext,
+    Render
 };
+use crate::controller::template::TemplateString;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Base64File {
     /// base 64 encoded content
     pub contents: String,
-    /// filename with extension
-    pub filename: String 
+    /// filename with extension. Supports `{{placeholders}}`.
+    pub filename: TemplateString
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EchoOptions {
-    pub content: Option<String>,
-    pub file: Option<Base64File>
+    pub content: Option<TemplateString>,
+    pub file: Option<Base64File>,
+    /// Post in this channel instead of the one the event came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<TemplateString>,
+    /// Post in the channel with this name instead of the one the event came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_name: Option<TemplateString>,
+    /// Who the message may ping. Defaults to the mentioned users only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_mentions: Option<discord::AllowedMentions>
+}
+impl EchoOptions {
+    fn target_channel(&self, context: &DiscordContext, payload: &GatewayMessageType) -> Option<String> {
+        if let Some(channel_id) = self.channel_id.as_ref() {
+            return Some(String::from(channel_id.as_str()))
+        }
+        if let Some(channel_name) = self.channel_name.as_ref() {
+            let guild = context.get_guild(&payload.get_guild_id()?)?;
+            return guild.channels.as_ref()?.iter()
+                .find(|channel| channel.name.as_deref() == Some(channel_name.as_str()))
+                .map(|channel| channel.id.clone())
+        }
+        payload.get_channel_id()
+    }
+}
+impl Render for EchoOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        let options = [
+            ("content", self.content.as_ref()),
+            ("channel_id", self.channel_id.as_ref()),
+            ("channel_name", self.channel_name.as_ref()),
+            ("file.filename", self.file.as_ref().map(|file| &file.filename))
+        ];
+        options.iter()
+            .filter_map(|(field, template)| template.map(|template| (String::from(*field), template)))
+            .collect()
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        let file = match self.file.as_ref() {
+            Some(file) => Some(Base64File {
+                filename: file.filename.render(scope)?,
+                ..file.clone()
+            }),
+            None => None
+        };
+        Ok(EchoOptions {
+            content: self.content.as_ref().map(|content| content.render(scope)).transpose()?,
+            channel_id: self.channel_id.as_ref().map(|channel_id| channel_id.render(scope)).transpose()?,
+            channel_name: self.channel_name.as_ref().map(|channel_name| channel_name.render(scope)).transpose()?,
+            file,
+            ..self.clone()
+        })
+    }
 }
 #[async_trait]
 impl GatewayMessageHandler for EchoOptions {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String> {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
         if let Some(payload) = message.d.clone() {
-            if let Some(channel_id) = payload.get_channel_id() {
+            if let Some(channel_id) = self.target_channel(context, &payload) {
                 let data: EchoData = EchoData {
                     meta: self.to_owned(),
                     channel_id
@@ -61,13 +108,20 @@ pub struct EchoData {
 impl RunAction for EchoData {
     async fn execute(&self, context: &DiscordContext) -> Result<(), String> {
         info!("Executing echo data action...");
+        // The file is still sent if the text fails; the text's error is
+        // returned after it
+        let mut result = Ok(());
         if let Some(content) = &self.meta.content {
-            context.http_client.create_message(self.channel_id.to_owned(), content.to_owned()).await;
+            let allowed_mentions = self.meta.allowed_mentions.clone().unwrap_or_default();
+            if let Err(e) = context.http_client.create_message(self.channel_id.to_owned(), content.as_str().to_owned(), allowed_mentions).await {
+                error!("Unable to send message: {}", e);
+                result = Err(e.to_string());
+            }
         }
         if let Some(file) = &self.meta.file {
             match base64::decode(file.contents.as_bytes()) {
                 Ok(result) => {
-                    match context.http_client.send_file(self.channel_id.to_owned(), file.filename.to_owned(), result).await {
+                    match context.http_client.send_file(self.channel_id.to_owned(), String::from(file.filename.as_str()), result).await {
                         Ok(_) => {
                             info!("Sent!")
                         },
@@ -82,8 +136,37 @@ impl RunAction for EchoData {
                 }
             }
         }
-        Ok(())
+        result
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::template;
+
+    #[test]
+    fn deleted_content_only_pings_users() {
+        let payload = GatewayMessageType::MessageDelete(discord::MessageDelete {
+            id: String::from("1"),
+            channel_id: String::from("2"),
+            guild_id: Some(String::from("3")),
+            message: Some(discord::Message {
+                content: String::from("@everyone <@&4> free nitro"),
+                author: discord::User {
+                    username: String::from("lomz"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        });
+        let content = template::render("{{message.author.username}} deleted: {{message.content}}", &template::event_value(&payload)).unwrap();
+        assert_eq!(content, "lomz deleted: @everyone <@&4> free nitro");
+        let body = serde_json::to_value(discord::CreateMessagePayload::new(content.clone(), Default::default())).unwrap();
+        assert_eq!(body["allowed_mentions"], serde_json::json!({"parse": ["users"]}));
 
+        let options: EchoOptions = serde_json::from_str(r#"{"content":"hi","file":null,"allowed_mentions":{"parse":[]}}"#).unwrap();
+        let body = serde_json::to_value(discord::CreateMessagePayload::new(content, options.allowed_mentions.unwrap())).unwrap();
+        assert_eq!(body["allowed_mentions"], serde_json::json!({"parse": []}));
+    }
+}
@@ -1,6 +1,7 @@
 use log::*;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::DiscordContext;
 
@@ -19,9 +20,68 @@ pub use addrole::{AddRoleOptions, AddRoleData};
 mod removerole;
 pub use removerole::{RemoveRoleOptions, RemoveRoleData};
 
+mod setstatus;
+pub use setstatus::{SetStatusOptions, SetStatusData};
+
+mod senddm;
+pub use senddm::{SendDMOptions, SendDMData};
+
 use crate::gateway::{GatewayMessage, GatewayMessageType};
+use crate::controller::rules::MatchContext;
+use crate::controller::template::TemplateString;
+
+// Each action has Options, Data
+
+/// The role for AddRole and RemoveRole: `role_id`, or else the guild role
+/// called `role_name`. With `allowed_roles`, other roles are refused.
+fn find_role_id(
+    context: &DiscordContext,
+    guild_id: &String,
+    role_id: Option<&str>,
+    role_name: Option<&str>,
+    allowed_roles: Option<&Vec<String>>
+) -> Option<String> {
+    let guild = context.get_guild(guild_id);
+    let roles = guild.as_ref().and_then(|guild| guild.roles.as_ref());
+    let role = match (role_id, role_name) {
+        (Some(role_id), _) => roles.and_then(|roles| roles.iter().find(|role| role.id == role_id)),
+        (None, Some(role_name)) => roles.and_then(|roles| roles.iter().find(|role| role.name == role_name)),
+        (None, None) => None
+    };
+    match (role, allowed_roles) {
+        (Some(role), Some(allowed_roles)) if !allowed_roles.contains(&role.name) => {
+            warn!("[guild_id: {}] Role '{}' is not in allowed_roles", guild_id, role.name);
+            None
+        },
+        (Some(role), _) => Some(role.id.clone()),
+        // Roles we don't have cached can still be used by ID
+        (None, None) => role_id.map(String::from),
+        (None, Some(_)) => None
+    }
+}
 
-/// Each action has Options, Data
+/// A templated role comes from the event, so like role_capture it needs
+/// allowed_roles. role_id would win over role_capture, so they can't be used
+/// together.
+fn validate_role(
+    role_id: Option<&TemplateString>,
+    role_name: Option<&TemplateString>,
+    role_capture: Option<&String>,
+    allowed_roles: Option<&Vec<String>>
+) -> Result<(), (String, String)> {
+    if role_id.is_some() && role_capture.is_some() {
+        return Err((String::from("role_capture"), String::from("can not be used with role_id")))
+    }
+    if allowed_roles.is_some() {
+        return Ok(())
+    }
+    for (field, template) in [("role_id", role_id), ("role_name", role_name)].iter() {
+        if template.is_some_and(|template| !template.is_literal()) {
+            return Err((String::from(*field), String::from("needs allowed_roles to use placeholders")))
+        }
+    }
+    Ok(())
+}
 
 
 /// For executing an action
@@ -37,35 +97,94 @@ pub enum ActionType {
     Echo(EchoOptions),
     React(ReactOptions),
     AddRole(AddRoleOptions),
-    RemoveRole(RemoveRoleOptions)
+    RemoveRole(RemoveRoleOptions),
+    SetStatus(SetStatusOptions),
+    SendDM(SendDMOptions)
+}
+
+impl ActionType {
+    /// Checks the options that the config format can't, e.g. ones that need
+    /// each other. The error is the field and what is wrong with it.
+    pub fn validate(&self) -> Result<(), (String, String)> {
+        match self {
+            ActionType::Webhook(options) => options.validate(),
+            ActionType::AddRole(options) => validate_role(options.role_id.as_ref(), options.role_name.as_ref(), options.role_capture.as_ref(), options.allowed_roles.as_ref()),
+            ActionType::RemoveRole(options) => validate_role(options.role_id.as_ref(), options.role_name.as_ref(), options.role_capture.as_ref(), options.allowed_roles.as_ref()),
+            _ => Ok(())
+        }
+    }
 }
 
 #[async_trait]
 pub trait GatewayMessageHandler {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String>;
+    /// `matched` is what the rule's filter matched, e.g. regex captures
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, matched: &MatchContext) -> Result<(), String>;
+}
+
+/// Options with `{{placeholder}}` fields. Anything not listed by `templates`
+/// is used as written.
+pub trait Render: Sized + Clone {
+    /// The rendered options, by field name
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        Vec::new()
+    }
+    /// A copy with every template rendered against `scope`
+    fn render(&self, _scope: &Value) -> Result<Self, String> {
+        Ok(self.clone())
+    }
+}
+
+impl Render for ActionType {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        match self {
+            ActionType::Webhook(options) => options.templates(),
+            ActionType::Echo(options) => options.templates(),
+            ActionType::React(options) => options.templates(),
+            ActionType::AddRole(options) => options.templates(),
+            ActionType::RemoveRole(options) => options.templates(),
+            ActionType::SetStatus(options) => options.templates(),
+            ActionType::SendDM(options) => options.templates()
+        }
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        Ok(match self {
+            ActionType::Webhook(options) => ActionType::Webhook(options.render(scope)?),
+            ActionType::Echo(options) => ActionType::Echo(options.render(scope)?),
+            ActionType::React(options) => ActionType::React(options.render(scope)?),
+            ActionType::AddRole(options) => ActionType::AddRole(options.render(scope)?),
+            ActionType::RemoveRole(options) => ActionType::RemoveRole(options.render(scope)?),
+            ActionType::SetStatus(options) => ActionType::SetStatus(options.render(scope)?),
+            ActionType::SendDM(options) => ActionType::SendDM(options.render(scope)?)
+        })
+    }
 }
 
 #[async_trait]
 impl GatewayMessageHandler for ActionType {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String> {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, matched: &MatchContext) -> Result<(), String> {
         (match self {
-            ActionType::Webhook(options) => options.handle(context, message),
-            ActionType::Echo(options) => options.handle(context, message),
-            ActionType::React(options) => options.handle(context, message),
-            ActionType::AddRole(options) => options.handle(context, message),
-            ActionType::RemoveRole(options) => options.handle(context, message)
+            ActionType::Webhook(options) => options.handle(context, message, matched),
+            ActionType::Echo(options) => options.handle(context, message, matched),
+            ActionType::React(options) => options.handle(context, message, matched),
+            ActionType::AddRole(options) => options.handle(context, message, matched),
+            ActionType::RemoveRole(options) => options.handle(context, message, matched),
+            ActionType::SetStatus(options) => options.handle(context, message, matched),
+            ActionType::SendDM(options) => options.handle(context, message, matched)
         }).await
     }
 }
 
 
 #[derive(Clone, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum ActionData {
     Webhook(WebhookData),
     Echo(EchoData),
     React(ReactData),
     AddRole(AddRoleData),
     RemoveRole(RemoveRoleData),
+    SetStatus(SetStatusData),
+    SendDM(SendDMData),
 }
 
 #[async_trait]
@@ -77,6 +196,8 @@ impl RunAction for ActionData {
             ActionData::React(data) => data.execute(context),
             ActionData::RemoveRole(data) => data.execute(context),
             ActionData::AddRole(data) => data.execute(context),
+            ActionData::SetStatus(data) => data.execute(context),
+            ActionData::SendDM(data) => data.execute(context),
         }).await
     }
 }
@@ -1,24 +1,48 @@
 
 use log::*;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize, Serializer, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
 use tokio::time::delay_for;
 use std::time::Duration;
+use std::future::Future;
 
 use crate::DiscordContext;
 use crate::gateway::{GatewayMessage, GatewayMessageType};
-use crate::controller::actions::{RunAction, GatewayMessageHandler};
+use crate::controller::actions::{RunAction, GatewayMessageHandler, MatchContext, Render};
+use crate::controller::template::TemplateString;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReactOptions {
-    pub emojis: Option<Vec<String>>,
-    pub custom_emojis: Option<Vec<String>>
+    pub emojis: Option<Vec<TemplateString>>,
+    /// Names of the guild's own emojis
+    pub custom_emojis: Option<Vec<TemplateString>>
+}
+
+impl Render for ReactOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        let emojis = self.emojis.iter().flatten().enumerate()
+            .map(|(i, emoji)| (format!("emojis[{}]", i), emoji));
+        let custom_emojis = self.custom_emojis.iter().flatten().enumerate()
+            .map(|(i, emoji)| (format!("custom_emojis[{}]", i), emoji));
+        emojis.chain(custom_emojis).collect()
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        let render_all = |emojis: &Option<Vec<TemplateString>>| match emojis.as_ref() {
+            Some(emojis) => emojis.iter().map(|emoji| emoji.render(scope)).collect::<Result<_, _>>().map(Some),
+            None => Ok(None)
+        };
+        Ok(ReactOptions {
+            emojis: render_all(&self.emojis)?,
+            custom_emojis: render_all(&self.custom_emojis)?
+        })
+    }
 }
 
 #[async_trait]
 impl GatewayMessageHandler for ReactOptions {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String> {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
         if let Some(GatewayMessageType::MessageCreate(msg)) = message.d.clone() {
             let data = ReactData {
                 meta: self.to_owned(),
@@ -35,7 +59,9 @@ impl GatewayMessageHandler for ReactOptions {
 
 #[derive(Clone, Deserialize)]
 pub struct ReactData {
-    pub guild_id: String,
+    /// Custom emojis can't be used without a guild
+    #[serde(default)]
+    pub guild_id: Option<String>,
     pub channel_id: String,
     pub message_id: String,
     #[serde(flatten)]
@@ -45,45 +71,56 @@ pub struct ReactData {
 #[async_trait]
 impl RunAction for ReactData {
     async fn execute(&self, context: &DiscordContext) -> Result<(), String> {
+        let mut reactions = vec![];
         if let Some(emojis) = &self.meta.emojis {
             for emoji in emojis.iter() {
-                context.http_client.create_reaction(
-                    self.channel_id.to_owned(),
-                    self.message_id.to_owned(),
-                    percent_encode(emoji.as_bytes(), NON_ALPHANUMERIC).collect::<String>()
-                ).await;
-                delay_for(Duration::from_millis(500)).await;
+                reactions.push(percent_encode(emoji.as_str().as_bytes(), NON_ALPHANUMERIC).collect::<String>());
             }
         }
-        if let Some(custom_emojis) = &self.meta.custom_emojis {
+        if let (Some(custom_emojis), Some(guild)) = (&self.meta.custom_emojis, self.guild_id.as_ref().and_then(|id| context.get_guild(id))) {
             for emoji in custom_emojis.iter() {
-                let guild = context.guild_map.get(&self.guild_id).unwrap();
                 // Search guild emojis
                 if let Some(emojis) = guild.emojis.as_ref() {
-                    debug!("Getting guild emojis...{}", emoji);
+                    debug!("Getting guild emojis...{}", emoji.as_str());
                     for searching_emoji in emojis {
-                        debug!("Searching {}", searching_emoji.name);
-                        if searching_emoji.name == *emoji {
-                            context.http_client.create_reaction(
-                                self.channel_id.to_owned(),
-                                self.message_id.to_owned(),
-                                format!("{}:{}", searching_emoji.name, searching_emoji.id)
-                            ).await;
-                            delay_for(Duration::from_millis(500)).await;
+                        debug!("Searching {:?}", searching_emoji.name);
+                        if searching_emoji.name.as_deref() == Some(emoji.as_str()) {
+                            reactions.push(format!("{}:{}", emoji.as_str(), searching_emoji.id.clone().unwrap_or_default()));
                         }
                     }
                 }
             }
         }
-        Ok(())
+        react_in_order(reactions, |emoji| {
+            let reaction = context.http_client.create_reaction(self.channel_id.to_owned(), self.message_id.to_owned(), emoji);
+            async move { reaction.await.map_err(|e| e.to_string()) }
+        }).await
+    }
+}
+
+/// Adds the reactions one at a time, in order. Stops at the first one that
+/// fails; the rest would most likely fail the same way.
+async fn react_in_order<F, R>(reactions: Vec<String>, mut react: F) -> Result<(), String>
+where F: FnMut(String) -> R,
+      R: Future<Output = Result<(), String>>
+{
+    for (index, emoji) in reactions.into_iter().enumerate() {
+        if index > 0 {
+            delay_for(Duration::from_millis(500)).await;
+        }
+        if let Err(e) = react(emoji.clone()).await {
+            error!("Unable to react with {}: {}", emoji, e);
+            return Err(format!("Could not react with {}: {}", emoji, e))
+        }
     }
+    Ok(())
 }
 
 
 #[cfg(test)]
 mod test {
-    use crate::controller::actions::ActionData;
     use super::*;
+    use crate::controller::actions::ActionData;
 
     #[test]
     fn parse_react() {
@@ -92,9 +129,19 @@ mod test {
         if let Some(ActionData::React(react)) = action {
             assert!(react.meta.custom_emojis.unwrap().len() == 1);
         } else {
-            assert!(false);
+            panic!("Parsed incorrectly");
         }
 
     }
-}
 
+    #[tokio::test]
+    async fn reactions_stop_at_the_first_error() {
+        let mut tried = vec![];
+        let result = react_in_order(vec![String::from("a"), String::from("b")], |emoji| {
+            tried.push(emoji.clone());
+            async move { if emoji == "a" { Err(String::from("Unknown Emoji")) } else { Ok(()) } }
+        }).await;
+        assert_eq!(result, Err(String::from("Could not react with a: Unknown Emoji")));
+        assert_eq!(tried, vec!["a"]);
+    }
+}
@@ -0,0 +1,87 @@
+use log::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::DiscordContext;
+use crate::discord;
+use crate::gateway::GatewayMessage;
+use crate::controller::actions::{RunAction, GatewayMessageHandler, MatchContext, Render};
+use crate::controller::template::TemplateString;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendDMOptions {
+    /// Sent to the user that triggered the rule. Supports `{{placeholders}}`.
+    pub content: TemplateString,
+    /// Who the message may ping. Defaults to the mentioned users only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_mentions: Option<discord::AllowedMentions>
+}
+
+impl Render for SendDMOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        vec![(String::from("content"), &self.content)]
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        Ok(SendDMOptions {
+            content: self.content.render(scope)?,
+            ..self.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl GatewayMessageHandler for SendDMOptions {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
+        if let Some(payload) = message.d.as_ref() {
+            if let Some(user_id) = payload.get_user_id() {
+                let data = SendDMData {
+                    meta: self.to_owned(),
+                    user_id
+                };
+                return data.execute(context).await
+            }
+        }
+        Err(String::from("Could not find a user to DM"))
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SendDMData {
+    #[serde(flatten)]
+    pub meta: SendDMOptions,
+    pub user_id: String
+}
+
+#[async_trait]
+impl RunAction for SendDMData {
+    async fn execute(&self, context: &DiscordContext) -> Result<(), String> {
+        info!("Sending DM to {}", self.user_id);
+        let channel = context.http_client.create_dm(self.user_id.to_owned()).await
+            .map_err(|e| e.to_string())?;
+        let allowed_mentions = self.meta.allowed_mentions.clone().unwrap_or_default();
+        context.http_client.create_message(channel.id, self.meta.content.as_str().to_owned(), allowed_mentions).await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::controller::actions::{ActionType, ActionData};
+
+    #[test]
+    fn deserialize_send_dm_action() {
+        let action = r#"{"type":"SendDM","options":{"content":"Welcome, {{user.username}}!"}}"#;
+        match serde_json::from_str::<ActionType>(action).unwrap() {
+            ActionType::SendDM(options) => assert_eq!(options.content.as_str(), "Welcome, {{user.username}}!"),
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        let data = r#"{"SendDM":{"content":"hi","user_id":"228347641120030731"}}"#;
+        match serde_json::from_str::<ActionData>(data).unwrap() {
+            ActionData::SendDM(data) => assert_eq!(data.user_id, "228347641120030731"),
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+}
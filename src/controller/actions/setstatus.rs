@@ -0,0 +1,130 @@
+use log::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::DiscordContext;
+use crate::gateway::{GatewayMessage, PresenceUpdatePayload, Activity, ActivityType, Status};
+use crate::controller::actions::{RunAction, GatewayMessageHandler, MatchContext, Render};
+use crate::controller::template::TemplateString;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetStatusOptions {
+    /// online, dnd, idle or invisible. Defaults to online.
+    #[serde(default)]
+    pub status: Status,
+    /// e.g. {"type": "watching", "name": "#deploys"}. Clears the activity if
+    /// left out.
+    pub activity: Option<ActivityOptions>
+}
+
+/// An `Activity` whose text supports `{{placeholders}}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActivityOptions {
+    pub name: TemplateString,
+    #[serde(rename = "type", default)]
+    pub _type: ActivityType,
+    /// Stream url, for Streaming activities
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<TemplateString>,
+    /// Status text, for Custom activities
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<TemplateString>
+}
+impl From<&ActivityOptions> for Activity {
+    fn from(activity: &ActivityOptions) -> Self {
+        Activity {
+            name: String::from(activity.name.as_str()),
+            _type: activity._type,
+            url: activity.url.as_ref().map(|url| String::from(url.as_str())),
+            state: activity.state.as_ref().map(|state| String::from(state.as_str()))
+        }
+    }
+}
+
+impl Render for SetStatusOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        let activity = match self.activity.as_ref() {
+            Some(activity) => activity,
+            None => return Vec::new()
+        };
+        let options = [
+            ("activity.name", Some(&activity.name)),
+            ("activity.url", activity.url.as_ref()),
+            ("activity.state", activity.state.as_ref())
+        ];
+        options.iter()
+            .filter_map(|(field, template)| template.map(|template| (String::from(*field), template)))
+            .collect()
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        let activity = match self.activity.as_ref() {
+            Some(activity) => Some(ActivityOptions {
+                name: activity.name.render(scope)?,
+                url: activity.url.as_ref().map(|url| url.render(scope)).transpose()?,
+                state: activity.state.as_ref().map(|state| state.render(scope)).transpose()?,
+                ..activity.clone()
+            }),
+            None => None
+        };
+        Ok(SetStatusOptions {
+            activity,
+            ..self.clone()
+        })
+    }
+}
+
+#[async_trait]
+impl GatewayMessageHandler for SetStatusOptions {
+    async fn handle(&self, context: &DiscordContext, _message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
+        let data = SetStatusData {
+            meta: self.to_owned()
+        };
+        data.execute(context).await
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SetStatusData {
+    #[serde(flatten)]
+    pub meta: SetStatusOptions
+}
+
+#[async_trait]
+impl RunAction for SetStatusData {
+    async fn execute(&self, context: &DiscordContext) -> Result<(), String> {
+        info!("Setting status to {:?}", self.meta.status);
+        context.gateway.set_presence(PresenceUpdatePayload {
+            since: None,
+            activities: self.meta.activity.iter().map(Activity::from).collect(),
+            status: self.meta.status,
+            afk: false
+        }).await.map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::actions::{ActionType, ActionData};
+
+    #[test]
+    fn deserialize_set_status_action() {
+        let action = r#"{"type":"SetStatus","options":{"status":"idle","activity":{"type":"watching","name":"deploys"}}}"#;
+        match serde_json::from_str::<ActionType>(action).unwrap() {
+            ActionType::SetStatus(options) => {
+                assert_eq!(options.status, Status::Idle);
+                let activity = options.activity.unwrap();
+                assert_eq!(activity._type, ActivityType::Watching);
+                assert_eq!(activity.name.as_str(), "deploys");
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        let data = r#"{"SetStatus":{"status":"dnd"}}"#;
+        match serde_json::from_str::<ActionData>(data).unwrap() {
+            ActionData::SetStatus(data) => assert!(data.meta.activity.is_none()),
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+}
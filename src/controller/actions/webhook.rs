@@ -1,20 +1,21 @@
 use log::*;
 use async_trait::async_trait;
-use std::error::Error;
-use std::future::Future;
 use std::fmt;
 use serde::{Deserialize, Serialize, Serializer, Deserializer, ser::SerializeMap};
-use serde::de::{Visitor, MapAccess};
+use serde::de::{Visitor, MapAccess, Error};
+use serde_json::Value;
 use std::marker::PhantomData;
 
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 
 use crate::DiscordContext;
-use crate::controller::actions::{ActionData, RunAction, GatewayMessageHandler, GatewayMessage};
+use crate::controller::actions::{ActionData, RunAction, GatewayMessageHandler, GatewayMessage, MatchContext, Render};
+use crate::controller::template::TemplateString;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct WebhookOptions {
-    pub url: String,
+    /// Supports `{{placeholders}}` after the host. They are inserted as is.
+    pub url: TemplateString,
     #[serde(serialize_with="serialize_header_map")]
     #[serde(deserialize_with="deserialize_header_map")]
     pub headers: HeaderMap,
@@ -54,18 +55,46 @@ impl<'de> Visitor<'de> for HeaderMapVisitor {
         let mut header_map = HeaderMap::new();
         while let Some(entry) = map.next_entry::<String, String>()? {
             let (key, value) = entry;
-            header_map.insert(
-                HeaderName::from_bytes(key.as_bytes()).expect("Could not use header name in header map"),
-                HeaderValue::from_str(value.as_str()).expect("Could not insert value into headermap")
-            );
+            let name = HeaderName::from_bytes(key.as_bytes())
+                .map_err(|_| A::Error::custom(format!("Invalid header name '{}'", key)))?;
+            let value = HeaderValue::from_str(value.as_str())
+                .map_err(|_| A::Error::custom(format!("Invalid value for header '{}'", key)))?;
+            header_map.insert(name, value);
         }
         Ok(header_map)
     }
 }
 
+impl WebhookOptions {
+    /// Events must not pick where they are posted, so the scheme and host
+    /// can't have placeholders
+    pub fn validate(&self) -> Result<(), (String, String)> {
+        let url = self.url.as_str();
+        let host_end = url.find("://")
+            .and_then(|scheme_end| url[scheme_end + 3..].find('/').map(|index| scheme_end + 3 + index))
+            .unwrap_or(url.len());
+        if url[..host_end].contains("{{") {
+            return Err((String::from("url"), String::from("can only use placeholders after the host")))
+        }
+        Ok(())
+    }
+}
+
+impl Render for WebhookOptions {
+    fn templates(&self) -> Vec<(String, &TemplateString)> {
+        vec![(String::from("url"), &self.url)]
+    }
+    fn render(&self, scope: &Value) -> Result<Self, String> {
+        Ok(WebhookOptions {
+            url: self.url.render(scope)?,
+            ..self.clone()
+        })
+    }
+}
+
 #[async_trait]
 impl GatewayMessageHandler for WebhookOptions {
-    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage) -> Result<(), String> {
+    async fn handle(&self, context: &DiscordContext, message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
         let data = WebhookData {
             meta: self.to_owned(),
             payload: message.to_owned()
@@ -125,18 +154,25 @@ mod test {
         let mut headers = HeaderMap::new();
         headers.insert(HeaderName::from_static("authorization"), HeaderValue::from_static("jwt"));
         let config = ConfigSchema {
-            guild_id: String::from("1"),
+            guild_id: Some(String::from("1")),
+            first_match: false,
             rules: vec![RuleVariant::MESSAGE_CREATE(Rule {
                 filters: MessageCreateFilter {
-                    content: Some(String::from("test")),
+                    content: Some(Pattern::from("test")),
                     channel_name: None,
                     username: None,
-                    attachments: None
-                },
+                    attachments: None,
+                    dm: None,
+                    roles: RoleFilter::default()
+                }.into(),
                 action: ActionType::Webhook(WebhookOptions {
-                    url: String::from("http://localhost"),
+                    url: TemplateString::from("http://localhost"),
                     headers
-                })
+                }).into(),
+                priority: 0,
+                stop: false,
+                sequence: Default::default(),
+                limits: Default::default()
             })]
         };
 
@@ -146,13 +182,28 @@ mod test {
         )
     }
 
-    /// Invalid header name
     #[test]
-    #[should_panic]
+    fn placeholders_only_after_the_host() {
+        let webhook = |url: &str| WebhookOptions {
+            url: TemplateString::from(url),
+            headers: HeaderMap::new()
+        };
+        assert!(webhook("https://example.com/{{author.id}}?q={{content}}").validate().is_ok());
+        assert_eq!(webhook("https://{{content}}/hook").validate().unwrap_err().0, "url");
+        assert_eq!(webhook("https://example.com{{content}}").validate().unwrap_err().0, "url");
+        assert_eq!(webhook("{{content}}://example.com/").validate().unwrap_err().0, "url");
+        assert_eq!(webhook("http:/{{content}}").validate().unwrap_err().0, "url");
+    }
+
+    #[test]
     fn deserialize_invalid_http_header() {
         let invalid_config = r#"{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Webhook","options":{"url":"http://localhost","headers":{"/":"jwt"}}},"filters":{"content":"test","channel_name":null,"username":null,"attachments":null}}],"guild_id":"1"}"#;
+        assert!(serde_json::de::from_str::<ConfigSchema>(invalid_config).is_err());
 
-        let config = serde_json::de::from_str::<ConfigSchema>(invalid_config);
+        // e.g. a newline from an environment variable
+        let invalid_value = r#"{"url":"http://localhost","headers":{"authorization":"Bearer a\nb"}}"#;
+        let error = serde_json::de::from_str::<WebhookOptions>(invalid_value).unwrap_err();
+        assert!(error.to_string().contains("Invalid value for header 'authorization'"));
     }
 
 
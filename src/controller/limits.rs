@@ -0,0 +1,293 @@
+/// Cooldowns and rate limits for rules.
+///
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::gateway::GatewayMessageType;
+
+/// Most cooldown keys kept per rule. The oldest are dropped past this.
+const MAX_COOLDOWN_KEYS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownScope {
+    #[default]
+    User,
+    Channel,
+    Guild,
+    /// One cooldown for everyone
+    Rule
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "A: Deserialize<'de>"))]
+pub struct Cooldown<A> {
+    /// Seconds before the rule runs again for the same user, channel...
+    pub seconds: u64,
+    /// What the cooldown is kept for. Defaults to user.
+    #[serde(default)]
+    pub per: CooldownScope,
+    /// Runs instead of the action, once per cooldown, when a trigger is
+    /// suppressed. e.g. an Echo saying "slow down!"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply: Option<A>
+}
+
+/// At most `max` runs of the rule in any `window_seconds`, across everyone
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub max: usize,
+    pub window_seconds: u64
+}
+
+#[derive(Default)]
+struct LimitState {
+    /// Last run per cooldown key, and whether we already replied since
+    last_run: HashMap<String, (Instant, bool)>,
+    /// Keys of `last_run`, oldest first
+    expiry: VecDeque<(Instant, String)>,
+    /// Recent runs, oldest first
+    runs: VecDeque<Instant>
+}
+
+/// The `cooldown` and `rate_limit` of a rule
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "A: Deserialize<'de>"))]
+pub struct Limits<A> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<Cooldown<A>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(skip)]
+    state: Arc<Mutex<LimitState>>
+}
+impl<A> Default for Limits<A> {
+    fn default() -> Self {
+        Limits {
+            cooldown: None,
+            rate_limit: None,
+            state: Arc::new(Mutex::new(LimitState::default()))
+        }
+    }
+}
+
+pub enum LimitCheck<'a, A> {
+    Run,
+    /// Suppressed, run this instead
+    Reply(&'a A),
+    /// Suppressed by the cooldown or the rate limit
+    Suppressed(&'static str)
+}
+
+impl<A> Limits<A> {
+    /// A rate limit of 0 runs, or over 0 seconds, would never let the rule
+    /// run. The cooldown reply is checked with the rule's actions.
+    pub fn validate(&self) -> Result<(), (String, String)> {
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.max == 0 {
+                return Err((String::from("rate_limit.max"), String::from("must be at least 1")))
+            }
+            if rate_limit.window_seconds == 0 {
+                return Err((String::from("rate_limit.window_seconds"), String::from("must be at least 1")))
+            }
+        }
+        Ok(())
+    }
+
+    /// Shares `other`'s cooldowns and recent runs, e.g. when the rule is
+    /// reloaded unchanged
+    pub fn keep_state(&mut self, other: &Limits<A>) {
+        self.state = other.state.clone();
+    }
+
+    /// Whether the rule may run for this event. Counts the run if so.
+    pub fn check(&self, payload: &GatewayMessageType) -> LimitCheck<'_, A> {
+        self.check_at(payload, Instant::now())
+    }
+
+    fn check_at(&self, payload: &GatewayMessageType, now: Instant) -> LimitCheck<'_, A> {
+        if self.cooldown.is_none() && self.rate_limit.is_none() {
+            return LimitCheck::Run
+        }
+        let mut state = self.state.lock().unwrap();
+
+        let mut cooldown_key = None;
+        if let Some(cooldown) = &self.cooldown {
+            let period = Duration::from_secs(cooldown.seconds);
+            let state = &mut *state;
+            // Forget cooldowns that are over, and the oldest past the cap
+            while let Some((last_run, key)) = state.expiry.front() {
+                if now.duration_since(*last_run) < period && state.last_run.len() < MAX_COOLDOWN_KEYS {
+                    break
+                }
+                if state.last_run.get(key).map(|(run, _)| run) == Some(last_run) {
+                    state.last_run.remove(key);
+                }
+                state.expiry.pop_front();
+            }
+            let key = match cooldown.per {
+                CooldownScope::User => payload.get_user_id(),
+                CooldownScope::Channel => payload.get_channel_id(),
+                CooldownScope::Guild => payload.get_guild_id(),
+                CooldownScope::Rule => Some(String::new())
+            };
+            match key {
+                Some(key) => {
+                    if let Some((last_run, replied)) = state.last_run.get_mut(&key) {
+                        if now.duration_since(*last_run) < period {
+                            return match &cooldown.reply {
+                                Some(reply) if !*replied => {
+                                    *replied = true;
+                                    LimitCheck::Reply(reply)
+                                },
+                                _ => LimitCheck::Suppressed("cooldown")
+                            }
+                        }
+                    }
+                    cooldown_key = Some(key);
+                },
+                None => debug!("Event has no {:?} id, skipping the cooldown", cooldown.per)
+            }
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            let window = Duration::from_secs(rate_limit.window_seconds);
+            while state.runs.front().is_some_and(|run| now.duration_since(*run) >= window) {
+                state.runs.pop_front();
+            }
+            if state.runs.len() >= rate_limit.max {
+                return LimitCheck::Suppressed("rate limit")
+            }
+            state.runs.push_back(now);
+        }
+
+        if let Some(key) = cooldown_key {
+            state.last_run.insert(key.clone(), (now, false));
+            state.expiry.push_back((now, key));
+        }
+        LimitCheck::Run
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::discord;
+
+    fn message(user_id: &str) -> GatewayMessageType {
+        GatewayMessageType::MessageCreate(discord::Message {
+            channel_id: String::from("2"),
+            author: discord::User {
+                id: String::from(user_id),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn runs(check: LimitCheck<&str>) -> &'static str {
+        match check {
+            LimitCheck::Run => "run",
+            LimitCheck::Reply(_) => "reply",
+            LimitCheck::Suppressed(_) => "suppressed"
+        }
+    }
+
+    #[test]
+    fn cooldown_per_user_with_reply() {
+        let limits: Limits<&str> = Limits {
+            cooldown: Some(Cooldown { seconds: 10, per: CooldownScope::User, reply: Some("slow down") }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        assert_eq!(runs(limits.check_at(&message("1"), start)), "run");
+        assert_eq!(runs(limits.check_at(&message("2"), start)), "run");
+        assert_eq!(runs(limits.check_at(&message("1"), start + Duration::from_secs(1))), "reply");
+        assert_eq!(runs(limits.check_at(&message("1"), start + Duration::from_secs(2))), "suppressed");
+        assert_eq!(runs(limits.check_at(&message("1"), start + Duration::from_secs(10))), "run");
+    }
+
+    #[test]
+    fn deserialize_rule_limits() {
+        use crate::controller::rules::RuleVariant;
+        use crate::controller::actions::ActionType;
+        let rule = r#"{
+            "event": "MESSAGE_CREATE",
+            "filters": {"content": "^!ping$"},
+            "action": {"type": "Echo", "options": {"content": "pong"}},
+            "cooldown": {"seconds": 30, "per": "channel", "reply": {"type": "Echo", "options": {"content": "slow down"}}},
+            "rate_limit": {"max": 5, "window_seconds": 60}
+        }"#;
+        match serde_json::from_str::<RuleVariant>(rule).unwrap() {
+            RuleVariant::MESSAGE_CREATE(rule) => {
+                let cooldown = rule.limits.cooldown.unwrap();
+                assert_eq!(cooldown.per, CooldownScope::Channel);
+                assert!(matches!(cooldown.reply, Some(ActionType::Echo(_))));
+                assert_eq!(rule.limits.rate_limit.unwrap().max, 5);
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn empty_rate_limit_is_invalid() {
+        let mut limits: Limits<&str> = Limits {
+            rate_limit: Some(RateLimit { max: 0, window_seconds: 60 }),
+            ..Default::default()
+        };
+        assert_eq!(limits.validate().unwrap_err().0, "rate_limit.max");
+        limits.rate_limit = Some(RateLimit { max: 5, window_seconds: 0 });
+        assert_eq!(limits.validate().unwrap_err().0, "rate_limit.window_seconds");
+        limits.rate_limit = Some(RateLimit { max: 5, window_seconds: 60 });
+        assert!(limits.validate().is_ok());
+    }
+
+    #[test]
+    fn rate_limit_window() {
+        let limits: Limits<&str> = Limits {
+            rate_limit: Some(RateLimit { max: 2, window_seconds: 60 }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        assert_eq!(runs(limits.check_at(&message("1"), start)), "run");
+        assert_eq!(runs(limits.check_at(&message("2"), start + Duration::from_secs(1))), "run");
+        assert_eq!(runs(limits.check_at(&message("3"), start + Duration::from_secs(2))), "suppressed");
+        assert_eq!(runs(limits.check_at(&message("3"), start + Duration::from_secs(60))), "run");
+    }
+
+    #[test]
+    fn cooldown_keys_are_capped() {
+        let limits: Limits<&str> = Limits {
+            cooldown: Some(Cooldown { seconds: 60, per: CooldownScope::User, reply: None }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        for user in 0..MAX_COOLDOWN_KEYS + 10 {
+            assert_eq!(runs(limits.check_at(&message(&user.to_string()), start + Duration::from_millis(user as u64))), "run");
+        }
+        let state = limits.state.lock().unwrap();
+        assert_eq!(state.last_run.len(), MAX_COOLDOWN_KEYS);
+        assert!(!state.last_run.contains_key("0"));
+        assert!(state.last_run.contains_key(&(MAX_COOLDOWN_KEYS + 9).to_string()));
+        drop(state);
+
+        let later = start + Duration::from_secs(120);
+        assert_eq!(runs(limits.check_at(&message("1"), later)), "run");
+        assert_eq!(limits.state.lock().unwrap().last_run.len(), 1);
+    }
+
+    #[test]
+    fn cooldown_without_id_is_skipped() {
+        let limits: Limits<&str> = Limits {
+            cooldown: Some(Cooldown { seconds: 60, per: CooldownScope::Guild, reply: None }),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        assert_eq!(runs(limits.check_at(&message("1"), start)), "run");
+        assert_eq!(runs(limits.check_at(&message("2"), start)), "run");
+        assert!(limits.state.lock().unwrap().last_run.is_empty());
+    }
+}
@@ -3,6 +3,7 @@
 ///
 use log::*;
 use std::collections::HashMap;
+use std::fmt;
 use serde::{Deserialize, Serialize};
 
 use crate::gateway;
@@ -10,17 +11,25 @@ use crate::DiscordContext;
 
 mod rules;
 mod actions;
+pub mod template;
+mod limits;
+mod sequence;
 
 use rules::RuleVariant;
-use actions::GatewayMessageHandler;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigSchema {
     pub rules: Vec<RuleVariant>,
-    pub guild_id: String
+    /// Rules without a guild_id apply to every event, DMs included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild_id: Option<String>,
+    /// Only the first rule that matches an event runs, even if its action
+    /// fails
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub first_match: bool
 }
 
-#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
 #[allow(non_camel_case_types)]
 pub enum SupportedGatewayMessages {
     GUILD_CREATE,
@@ -31,6 +40,33 @@ pub enum SupportedGatewayMessages {
     MESSAGE_REACTION_ADD,
     MESSAGE_REACTION_REMOVE,
     HELLO,
+    GUILD_UPDATE,
+    GUILD_DELETE,
+    GUILD_ROLE_CREATE,
+    GUILD_ROLE_UPDATE,
+    GUILD_ROLE_DELETE,
+    CHANNEL_CREATE,
+    CHANNEL_UPDATE,
+    CHANNEL_DELETE,
+    CHANNEL_PINS_UPDATE,
+    GUILD_MEMBER_ADD,
+    GUILD_MEMBER_UPDATE,
+    GUILD_MEMBER_REMOVE,
+    GUILD_BAN_ADD,
+    GUILD_BAN_REMOVE,
+    GUILD_EMOJIS_UPDATE,
+    GUILD_INTEGRATIONS_UPDATE,
+    WEBHOOKS_UPDATE,
+    INVITE_CREATE,
+    INVITE_DELETE,
+    VOICE_STATE_UPDATE,
+    PRESENCE_UPDATE,
+    MESSAGE_UPDATE,
+    MESSAGE_DELETE,
+    MESSAGE_DELETE_BULK,
+    MESSAGE_REACTION_REMOVE_ALL,
+    MESSAGE_REACTION_REMOVE_EMOJI,
+    TYPING_START,
 
     OTHER
 }
@@ -38,9 +74,37 @@ pub enum SupportedGatewayMessages {
 
 // Allowed so we can panic during tests
 #[allow(unreachable_patterns)]
-fn event_convert<'a>(msg: gateway::GatewayMessageType) -> SupportedGatewayMessages  {
+fn event_convert(msg: gateway::GatewayMessageType) -> SupportedGatewayMessages  {
     match msg {
         gateway::GatewayMessageType::GuildCreate(_) => SupportedGatewayMessages::GUILD_CREATE,
+        gateway::GatewayMessageType::GuildMembersChunk(_) => SupportedGatewayMessages::OTHER,
+        gateway::GatewayMessageType::GuildUpdate(_) => SupportedGatewayMessages::GUILD_UPDATE,
+        gateway::GatewayMessageType::GuildDelete(_) => SupportedGatewayMessages::GUILD_DELETE,
+        gateway::GatewayMessageType::GuildRoleCreate(_) => SupportedGatewayMessages::GUILD_ROLE_CREATE,
+        gateway::GatewayMessageType::GuildRoleUpdate(_) => SupportedGatewayMessages::GUILD_ROLE_UPDATE,
+        gateway::GatewayMessageType::GuildRoleDelete(_) => SupportedGatewayMessages::GUILD_ROLE_DELETE,
+        gateway::GatewayMessageType::ChannelCreate(_) => SupportedGatewayMessages::CHANNEL_CREATE,
+        gateway::GatewayMessageType::ChannelUpdate(_) => SupportedGatewayMessages::CHANNEL_UPDATE,
+        gateway::GatewayMessageType::ChannelDelete(_) => SupportedGatewayMessages::CHANNEL_DELETE,
+        gateway::GatewayMessageType::ChannelPinsUpdate(_) => SupportedGatewayMessages::CHANNEL_PINS_UPDATE,
+        gateway::GatewayMessageType::GuildMemberAdd(_) => SupportedGatewayMessages::GUILD_MEMBER_ADD,
+        gateway::GatewayMessageType::GuildMemberUpdate(_) => SupportedGatewayMessages::GUILD_MEMBER_UPDATE,
+        gateway::GatewayMessageType::GuildMemberRemove(_) => SupportedGatewayMessages::GUILD_MEMBER_REMOVE,
+        gateway::GatewayMessageType::GuildBanAdd(_) => SupportedGatewayMessages::GUILD_BAN_ADD,
+        gateway::GatewayMessageType::GuildBanRemove(_) => SupportedGatewayMessages::GUILD_BAN_REMOVE,
+        gateway::GatewayMessageType::GuildEmojisUpdate(_) => SupportedGatewayMessages::GUILD_EMOJIS_UPDATE,
+        gateway::GatewayMessageType::GuildIntegrationsUpdate(_) => SupportedGatewayMessages::GUILD_INTEGRATIONS_UPDATE,
+        gateway::GatewayMessageType::WebhooksUpdate(_) => SupportedGatewayMessages::WEBHOOKS_UPDATE,
+        gateway::GatewayMessageType::InviteCreate(_) => SupportedGatewayMessages::INVITE_CREATE,
+        gateway::GatewayMessageType::InviteDelete(_) => SupportedGatewayMessages::INVITE_DELETE,
+        gateway::GatewayMessageType::VoiceStateUpdate(_) => SupportedGatewayMessages::VOICE_STATE_UPDATE,
+        gateway::GatewayMessageType::PresenceUpdate(_) => SupportedGatewayMessages::PRESENCE_UPDATE,
+        gateway::GatewayMessageType::MessageUpdate(_) => SupportedGatewayMessages::MESSAGE_UPDATE,
+        gateway::GatewayMessageType::MessageDelete(_) => SupportedGatewayMessages::MESSAGE_DELETE,
+        gateway::GatewayMessageType::MessageDeleteBulk(_) => SupportedGatewayMessages::MESSAGE_DELETE_BULK,
+        gateway::GatewayMessageType::MessageReactionRemoveAll(_) => SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_ALL,
+        gateway::GatewayMessageType::MessageReactionRemoveEmoji(_) => SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_EMOJI,
+        gateway::GatewayMessageType::TypingStart(_) => SupportedGatewayMessages::TYPING_START,
         gateway::GatewayMessageType::Ready(_) => SupportedGatewayMessages::READY,
         gateway::GatewayMessageType::MessageCreate(_) => SupportedGatewayMessages::MESSAGE_CREATE,
         gateway::GatewayMessageType::MessageReactionAdd(_) => SupportedGatewayMessages::MESSAGE_REACTION_ADD,
@@ -54,15 +118,138 @@ fn event_convert<'a>(msg: gateway::GatewayMessageType) -> SupportedGatewayMessag
     }
 }
 
+/// Intents the gateway needs for us to receive an event
+fn event_intents(event: &SupportedGatewayMessages) -> Vec<gateway::Intent> {
+    match event {
+        SupportedGatewayMessages::GUILD_CREATE
+        | SupportedGatewayMessages::GUILD_UPDATE
+        | SupportedGatewayMessages::GUILD_DELETE
+        | SupportedGatewayMessages::GUILD_ROLE_CREATE
+        | SupportedGatewayMessages::GUILD_ROLE_UPDATE
+        | SupportedGatewayMessages::GUILD_ROLE_DELETE
+        | SupportedGatewayMessages::CHANNEL_CREATE
+        | SupportedGatewayMessages::CHANNEL_UPDATE
+        | SupportedGatewayMessages::CHANNEL_DELETE
+        | SupportedGatewayMessages::CHANNEL_PINS_UPDATE => vec![gateway::Intent::GUILDS],
+        SupportedGatewayMessages::GUILD_MEMBER_ADD
+        | SupportedGatewayMessages::GUILD_MEMBER_UPDATE
+        | SupportedGatewayMessages::GUILD_MEMBER_REMOVE => vec![gateway::Intent::GUILD_MEMBERS],
+        SupportedGatewayMessages::GUILD_BAN_ADD
+        | SupportedGatewayMessages::GUILD_BAN_REMOVE => vec![gateway::Intent::GUILD_BANS],
+        SupportedGatewayMessages::GUILD_EMOJIS_UPDATE => vec![gateway::Intent::GUILD_EMOJIS],
+        SupportedGatewayMessages::GUILD_INTEGRATIONS_UPDATE => vec![gateway::Intent::GUILD_INTEGRATIONS],
+        SupportedGatewayMessages::WEBHOOKS_UPDATE => vec![gateway::Intent::GUILD_WEBHOOKS],
+        SupportedGatewayMessages::INVITE_CREATE
+        | SupportedGatewayMessages::INVITE_DELETE => vec![gateway::Intent::GUILD_INVITES],
+        SupportedGatewayMessages::VOICE_STATE_UPDATE => vec![gateway::Intent::GUILD_VOICE_STATES],
+        SupportedGatewayMessages::PRESENCE_UPDATE => vec![gateway::Intent::GUILD_PRESENCES],
+        // Deletes need content too, for the cached copy of the message.
+        // MESSAGE_CONTENT is privileged, so it is only sent when listed in
+        // `gateway.intents.enable`. Without it content filters never match
+        // in guilds, and the only sign is the warning from `intents`.
+        SupportedGatewayMessages::MESSAGE_CREATE
+        | SupportedGatewayMessages::MESSAGE_UPDATE
+        | SupportedGatewayMessages::MESSAGE_DELETE
+        | SupportedGatewayMessages::MESSAGE_DELETE_BULK => vec![gateway::Intent::GUILD_MESSAGES, gateway::Intent::MESSAGE_CONTENT],
+        SupportedGatewayMessages::MESSAGE_REACTION_ADD
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_ALL
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_EMOJI => vec![gateway::Intent::GUILD_MESSAGE_REACTIONS],
+        SupportedGatewayMessages::TYPING_START => vec![gateway::Intent::GUILD_MESSAGE_TYPING],
+        _ => vec![]
+    }
+}
+
+/// Extra intents for global rules, which also see the event in DMs
+fn dm_event_intents(event: &SupportedGatewayMessages) -> Vec<gateway::Intent> {
+    match event {
+        SupportedGatewayMessages::MESSAGE_CREATE
+        | SupportedGatewayMessages::MESSAGE_UPDATE
+        | SupportedGatewayMessages::MESSAGE_DELETE
+        | SupportedGatewayMessages::CHANNEL_PINS_UPDATE => vec![gateway::Intent::DIRECT_MESSAGES],
+        SupportedGatewayMessages::MESSAGE_REACTION_ADD
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_ALL
+        | SupportedGatewayMessages::MESSAGE_REACTION_REMOVE_EMOJI => vec![gateway::Intent::DIRECT_MESSAGE_REACTIONS],
+        SupportedGatewayMessages::TYPING_START => vec![gateway::Intent::DIRECT_MESSAGE_TYPING],
+        _ => vec![]
+    }
+}
+
+/// A rule in the config that can't be used
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// None for global rules
+    pub guild_id: Option<String>,
+    /// Index of the rule in its guild's `rules`
+    pub rule: usize,
+    /// e.g. `filters.content`
+    pub field: String,
+    pub message: String
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "[guild_id: {}] rules[{}].{}: {}",
+            self.guild_id.as_ref().map_or("global", |id| id.as_str()), self.rule, self.field, self.message
+        )
+    }
+}
+impl std::error::Error for ConfigError {}
+
+/// Rules by event, with their index in the config
+type EventMap = HashMap<SupportedGatewayMessages, Vec<(usize, RuleVariant)>>;
+
+/// The rules of a guild, by event, in the order they run. Each rule keeps
+/// its index in the config for logging.
+#[derive(Default)]
+struct RuleSet {
+    /// Only the first rule that matches an event runs
+    first_match: bool,
+    events: EventMap
+}
+impl RuleSet {
+    /// Rules that are defined exactly like one in `old` keep its cooldowns
+    /// and rate limits
+    fn keep_limits(&mut self, old: &RuleSet) {
+        // Compared as values, so the order of e.g. webhook headers doesn't
+        // make a rule look edited
+        let mut old_rules: Vec<(serde_json::Value, &RuleVariant)> = old.events.values().flatten()
+            .filter_map(|(_, rule)| serde_json::to_value(rule).ok().map(|definition| (definition, rule)))
+            .collect();
+        for (_, rule) in self.events.values_mut().flatten() {
+            let definition = match serde_json::to_value(&*rule) {
+                Ok(definition) => definition,
+                Err(_) => continue
+            };
+            if let Some(index) = old_rules.iter().position(|(old, _)| *old == definition) {
+                let (_, old) = old_rules.swap_remove(index);
+                rule.keep_limits(old);
+            }
+        }
+    }
+}
+
 pub struct Controller {
-    event_map: HashMap<String, HashMap<SupportedGatewayMessages, Vec<RuleVariant>>>
+    event_map: HashMap<String, RuleSet>,
+    /// Rules from schemas without a guild_id
+    global_events: RuleSet
 }
 impl Controller {
-    pub fn new(schemas: Vec<ConfigSchema>) -> Self {
-        let mut event_map = HashMap::<String, HashMap<SupportedGatewayMessages, Vec<RuleVariant>>>::new();
+    pub fn new(schemas: Vec<ConfigSchema>) -> Result<Self, ConfigError> {
+        let mut event_map = HashMap::<String, RuleSet>::new();
+        let mut global_events = RuleSet::default();
         for schema in schemas {
-            let mut guild_map = HashMap::<SupportedGatewayMessages, Vec<RuleVariant>>::new();
-            for rule in schema.rules {
+            let mut guild_map = EventMap::new();
+            for (index, rule) in schema.rules.into_iter().enumerate() {
+                if let Err((field, message)) = rule.validate() {
+                    return Err(ConfigError {
+                        guild_id: schema.guild_id,
+                        rule: index,
+                        field,
+                        message
+                    })
+                }
                 let event_type = match rule.clone() {
                     RuleVariant::MESSAGE_CREATE(_) => {
                         info!("Found MESSAGE_CREATE rule");
@@ -75,35 +262,170 @@ impl Controller {
                     RuleVariant::MESSAGE_REACTION_REMOVE(_) => {
                         info!("Found MESSAGE_REACTION_REMOVE");
                         SupportedGatewayMessages::MESSAGE_REACTION_REMOVE
+                    },
+                    RuleVariant::GUILD_MEMBER_ADD(_) => {
+                        info!("Found GUILD_MEMBER_ADD rule");
+                        SupportedGatewayMessages::GUILD_MEMBER_ADD
+                    },
+                    RuleVariant::GUILD_MEMBER_REMOVE(_) => {
+                        info!("Found GUILD_MEMBER_REMOVE rule");
+                        SupportedGatewayMessages::GUILD_MEMBER_REMOVE
+                    },
+                    RuleVariant::GUILD_MEMBER_UPDATE(_) => {
+                        info!("Found GUILD_MEMBER_UPDATE rule");
+                        SupportedGatewayMessages::GUILD_MEMBER_UPDATE
+                    },
+                    RuleVariant::MESSAGE_UPDATE(_) => {
+                        info!("Found MESSAGE_UPDATE rule");
+                        SupportedGatewayMessages::MESSAGE_UPDATE
+                    },
+                    RuleVariant::MESSAGE_DELETE(_) => {
+                        info!("Found MESSAGE_DELETE rule");
+                        SupportedGatewayMessages::MESSAGE_DELETE
                     }
                 };
 
                 if let Some(rules) = guild_map.get_mut(&event_type) {
-                    rules.push(rule);
+                    rules.push((index, rule));
                 } else {
-                    guild_map.insert(event_type, vec![(rule)]);
+                    guild_map.insert(event_type, vec![(index, rule)]);
+                }
+            }
+            match schema.guild_id {
+                Some(guild_id) => {
+                    event_map.insert(guild_id, RuleSet {
+                        first_match: schema.first_match,
+                        events: guild_map
+                    });
+                },
+                None => {
+                    global_events.first_match |= schema.first_match;
+                    for (event_type, rules) in guild_map {
+                        global_events.events.entry(event_type).or_insert_with(Vec::new).extend(rules);
+                    }
                 }
             }
-            event_map.insert(schema.guild_id, guild_map);
         };
-        Controller {
-            event_map
+        // Stable, so rules with the same priority keep their config order
+        for rule_set in event_map.values_mut().chain(std::iter::once(&mut global_events)) {
+            for rules in rule_set.events.values_mut() {
+                rules.sort_by_key(|(_, rule)| std::cmp::Reverse(rule.priority()));
+            }
         }
+        Ok(Controller {
+            event_map,
+            global_events
+        })
     }
 
+    /// Carries cooldowns and rate limits over from the controller this one
+    /// replaces, so reloading doesn't reset them for rules that didn't change
+    pub fn keep_limits(&mut self, old: &Controller) {
+        self.global_events.keep_limits(&old.global_events);
+        for (guild_id, rule_set) in self.event_map.iter_mut() {
+            if let Some(old) = old.event_map.get(guild_id) {
+                rule_set.keep_limits(old);
+            }
+        }
+    }
+
+    /// Every set of rules, by guild ID. The global rules have none.
+    fn scopes(&self) -> Vec<(Option<&String>, &EventMap)> {
+        let mut scopes: Vec<_> = self.event_map.iter()
+            .map(|(guild_id, rule_set)| (Some(guild_id), &rule_set.events))
+            .collect();
+        if !self.global_events.events.is_empty() {
+            scopes.push((None, &self.global_events.events));
+        }
+        scopes
+    }
+
+    fn scope_intents(guild_id: Option<&String>, event: &SupportedGatewayMessages) -> Vec<gateway::Intent> {
+        let mut intents = event_intents(event);
+        if guild_id.is_none() {
+            intents.extend(dm_event_intents(event));
+        }
+        intents
+    }
+
+    /// Intents the rules subscribe to
+    fn required_intents(&self) -> Vec<gateway::Intent> {
+        let mut required = Vec::<gateway::Intent>::new();
+        for (guild_id, events) in self.scopes() {
+            for event in events.keys() {
+                for intent in Self::scope_intents(guild_id, event) {
+                    if !required.contains(&intent) {
+                        required.push(intent);
+                    }
+                }
+            }
+        }
+        required
+    }
+
+    /// Intents the rules need that are not in `identified`. Intents only
+    /// change when identifying, so reloaded rules may need a restart.
+    pub fn missing_intents(&self, identified: u32) -> Vec<gateway::Intent> {
+        self.required_intents().into_iter()
+            .filter(|intent| identified & intent.bit() == 0)
+            .collect()
+    }
+
+    /// Intents to identify with: whatever the loaded rules subscribe to plus
+    /// the overrides in `config`. Warns about rules that would never fire.
+    pub fn intents(&self, config: &gateway::IntentsConfig) -> u32 {
+        let (enabled, missing) = config.resolve(&self.required_intents());
+        for intent in missing {
+            for (guild_id, events) in self.scopes() {
+                for event in events.keys() {
+                    if Self::scope_intents(guild_id, event).contains(&intent) {
+                        warn!(
+                            "[guild_id: {}] {:?} rules need the {:?} intent, which is not enabled",
+                            guild_id.map_or("global", |id| id.as_str()), event, intent
+                        );
+                    }
+                }
+            }
+        }
+        info!("Identifying with intents {:?}", enabled);
+        gateway::intents::to_bits(&enabled)
+    }
+
+    /// Runs the guild's rules and then the global ones, highest priority
+    /// first. A rule with `stop`, or any rule in a `first_match` set, skips
+    /// the rules after it in its own set once it matches; the global rules
+    /// still run after a guild's. The filters matched even if the action
+    /// failed, so a failed command does not fall through to a catch-all rule.
     pub async fn handle_event(&self, context: &DiscordContext, gateway_message: gateway::GatewayMessage) -> () {
-        if let Some(payload) = gateway_message.d.clone() {
-            let event_type = event_convert(payload.clone());
-            // If we cannot find a guild ID, we cannot route the message
-            if let Some(payload) = &gateway_message.d {
-                if let Some(guild_id) = payload.get_guild_id() {
-                    if let Some(events) = self.event_map.get(&guild_id) {
-                        if let Some(rules) = events.get(&event_type) {
-                            for rule in rules {
-                                rule.handle(context, &gateway_message).await;
-                            };
-                        }
+        let payload = match gateway_message.d.as_ref() {
+            Some(payload) => payload,
+            None => return
+        };
+        let event_type = event_convert(payload.clone());
+        // Without a guild ID (e.g. DMs) only the global rules apply
+        let guild_rules = payload.get_guild_id().and_then(|guild_id| {
+            self.event_map.get(&guild_id).map(|rule_set| (format!("guild_id: {}", guild_id), rule_set))
+        });
+        let global_rules = (String::from("global"), &self.global_events);
+        for (label, rule_set) in guild_rules.into_iter().chain(std::iter::once(global_rules)) {
+            let rules = match rule_set.events.get(&event_type) {
+                Some(rules) => rules,
+                None => continue
+            };
+            for (index, rule) in rules {
+                let matched = match rule.handle(context, &gateway_message).await {
+                    Ok(outcome) => {
+                        debug!("[{}] {:?} rules[{}]: {:?}", label, event_type, index, outcome);
+                        outcome.matched()
+                    },
+                    Err(e) => {
+                        warn!("[{}] {:?} rules[{}] failed: {}", label, event_type, index, e);
+                        true
                     }
+                };
+                if matched && (rule.stop() || rule_set.first_match) {
+                    debug!("[{}] {:?} rules[{}] matched, skipping the rules after it", label, event_type, index);
+                    break
                 }
             }
         }
@@ -115,23 +437,44 @@ mod test {
     use super::*;
     use super::rules::*;
     use super::actions::*;
+    use super::limits::LimitCheck;
     use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+    use serde_json::json;
+
+    /// `!ping` from lomz, in a guild or as a DM
+    fn ping(guild_id: Option<&str>) -> gateway::GatewayMessage {
+        let mut ping: serde_json::Value = serde_json::from_str(gateway::message::fixtures::MESSAGE_CREATE).unwrap();
+        let message = ping["d"].as_object_mut().unwrap();
+        message.insert(String::from("content"), json!("!ping"));
+        match guild_id {
+            Some(guild_id) => message.insert(String::from("guild_id"), json!(guild_id)),
+            None => message.remove("guild_id")
+        };
+        serde_json::from_value(ping).unwrap()
+    }
 
     #[test]
     fn serialize_config() {
         let config = ConfigSchema {
-            guild_id: String::from("1"),
+            guild_id: Some(String::from("1")),
+            first_match: false,
             rules: vec![RuleVariant::MESSAGE_CREATE(Rule {
                 filters: MessageCreateFilter {
-                    content: Some(String::from("test")),
+                    content: Some(Pattern::from("test")),
                     channel_name: None,
                     username: None,
-                    attachments: None
-                },
+                    attachments: None,
+                    dm: None,
+                    roles: RoleFilter::default()
+                }.into(),
                 action: ActionType::Webhook(WebhookOptions {
-                    url: String::from("http://localhost"),
+                    url: "http://localhost".into(),
                     headers: HeaderMap::new()
-                })
+                }).into(),
+                priority: 0,
+                stop: false,
+                sequence: Default::default(),
+                limits: Default::default()
             })]
         };
 
@@ -147,18 +490,25 @@ mod test {
         let mut headers = HeaderMap::new();
         headers.insert(HeaderName::from_static("authorization"), HeaderValue::from_static("jwt"));
         let config = ConfigSchema {
-            guild_id: String::from("1"),
+            guild_id: Some(String::from("1")),
+            first_match: false,
             rules: vec![RuleVariant::MESSAGE_CREATE(Rule {
                 filters: MessageCreateFilter {
-                    content: Some(String::from("test")),
+                    content: Some(Pattern::from("test")),
                     channel_name: None,
                     username: None,
-                    attachments: None
-                },
+                    attachments: None,
+                    dm: None,
+                    roles: RoleFilter::default()
+                }.into(),
                 action: ActionType::Webhook(WebhookOptions {
-                    url: String::from("http://localhost"),
+                    url: "http://localhost".into(),
                     headers
-                })
+                }).into(),
+                priority: 0,
+                stop: false,
+                sequence: Default::default(),
+                limits: Default::default()
             })]
         };
 
@@ -170,13 +520,196 @@ mod test {
 
     /// Invalid header name
     #[test]
-    #[should_panic]
     fn deserialize_invalid_http_header() {
         let invalid_config = r#"{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Webhook","options":{"url":"http://localhost","headers":{"/":"jwt"}}},"filters":{"content":"test","channel_name":null,"username":null,"attachments":null}}],"guild_id":"1"}"#;
 
-        let config = serde_json::de::from_str::<ConfigSchema>(invalid_config);
+        assert!(serde_json::de::from_str::<ConfigSchema>(invalid_config).is_err());
+    }
+
+
+    #[test]
+    fn intents_follow_rules() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_REACTION_ADD","action":{"type":"React","options":{"emojis":["👍"],"custom_emojis":null}},"filters":{"channel_name":null,"username":null,"react":null}}],"guild_id":"1"}]"#;
+        let controller = Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()).unwrap();
+        let intents = controller.intents(&gateway::IntentsConfig::default());
+        assert_eq!(intents, gateway::Intent::GUILDS.bit() | gateway::Intent::GUILD_MESSAGE_REACTIONS.bit());
     }
 
+    #[test]
+    fn global_rules_see_dms() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"SendDM","options":{"content":"pong"}},"filters":{"content":"^!ping","channel_name":null,"username":null,"attachments":null,"dm":true}}]}]"#;
+        let controller = Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()).unwrap();
+        let intents = controller.intents(&gateway::IntentsConfig::default());
+        assert_eq!(intents, gateway::Intent::GUILDS.bit() | gateway::Intent::GUILD_MESSAGES.bit() | gateway::Intent::DIRECT_MESSAGES.bit());
+
+        let msg = ping(None);
+        assert!(msg.d.as_ref().unwrap().get_guild_id().is_none());
+        match &controller.global_events.events[&SupportedGatewayMessages::MESSAGE_CREATE][0].1 {
+            RuleVariant::MESSAGE_CREATE(rule) => assert!(rule.filters.filter(&DiscordContext::empty(), &msg).is_some()),
+            _ => panic!("Mapped incorrectly")
+        }
+    }
+
+    #[test]
+    fn invalid_regex_is_a_config_error() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi","file":null}},"filters":{"content":"hi","channel_name":null,"username":null,"attachments":null}},{"event":"MESSAGE_REACTION_ADD","action":{"type":"Echo","options":{"content":"hi","file":null}},"filters":{"channel_name":"general(","username":null,"react":"*️⃣"}}],"guild_id":"1"}]"#;
+        let error = match Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()) {
+            Err(error) => error,
+            Ok(_) => panic!("Accepted an invalid regex")
+        };
+        assert_eq!(error.guild_id.as_deref(), Some("1"));
+        assert_eq!(error.rule, 1);
+        assert_eq!(error.field, "filters.channel_name");
+        assert!(error.to_string().starts_with("[guild_id: 1] rules[1].filters.channel_name: "));
+    }
+
+    #[test]
+    fn invalid_template_is_a_config_error() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":[{"type":"React","options":{"emojis":["👍"]}},{"type":"Echo","options":{"content":"{{#if content}}hi"}}],"filters":{}}],"guild_id":"1"}]"#;
+        let error = match Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()) {
+            Err(error) => error,
+            Ok(_) => panic!("Accepted an invalid template")
+        };
+        assert_eq!(error.field, "action[1].options.content");
+
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi"}},"filters":{},"cooldown":{"seconds":5,"reply":{"type":"Echo","options":{"content":"{{author.username | shout}}"}}}}],"guild_id":"1"}]"#;
+        let error = match Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()) {
+            Err(error) => error,
+            Ok(_) => panic!("Accepted an invalid template")
+        };
+        assert_eq!(error.field, "cooldown.reply.options.content");
+    }
+
+    #[test]
+    fn empty_rate_limit_is_a_config_error() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi"}},"filters":{},"rate_limit":{"max":0,"window_seconds":60}}],"guild_id":"1"}]"#;
+        let error = match Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()) {
+            Err(error) => error,
+            Ok(_) => panic!("Accepted a rate limit of 0")
+        };
+        assert_eq!(error.field, "rate_limit.max");
+    }
+
+    #[test]
+    fn role_capture_with_role_id_is_a_config_error() {
+        let config = r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"RemoveRole","options":{"role_name":null,"role_id":"10","role_capture":"role","allowed_roles":["artist"]}},"filters":{"content":"^!leave (?P<role>\\w+)"}}],"guild_id":"1"}]"#;
+        let error = match Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()) {
+            Err(error) => error,
+            Ok(_) => panic!("Accepted role_capture with role_id")
+        };
+        assert_eq!(error.field, "action.options.role_capture");
+    }
+
+    #[test]
+    fn rules_run_by_priority() {
+        let config = r#"[{"rules":[
+            {"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"fallback","file":null}},"filters":{}},
+            {"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"help","file":null}},"filters":{"content":"^!help"},"priority":10,"stop":true},
+            {"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"also fallback","file":null}},"filters":{}}
+        ],"guild_id":"1","first_match":true}]"#;
+        let controller = Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()).unwrap();
+        let rule_set = &controller.event_map["1"];
+        assert!(rule_set.first_match);
+        let rules = &rule_set.events[&SupportedGatewayMessages::MESSAGE_CREATE];
+        assert_eq!(rules.iter().map(|(index, _)| *index).collect::<Vec<_>>(), vec![1, 0, 2]);
+        assert!(rules[0].1.stop());
+        assert_eq!(rules[0].1.priority(), 10);
+        assert!(!rules[1].1.stop());
+    }
+
+    #[tokio::test]
+    async fn failed_action_still_stops() {
+        // Both actions fail without a cached role. The second rule's cooldown
+        // only starts if it ran.
+        let config = r#"[{"rules":[
+            {"event":"MESSAGE_CREATE","action":{"type":"AddRole","options":{"role_name":"missing","role_id":null}},"filters":{"content":"^!ping"},"stop":true},
+            {"event":"MESSAGE_CREATE","action":{"type":"AddRole","options":{"role_name":"missing","role_id":null}},"filters":{},"cooldown":{"seconds":60}}
+        ],"guild_id":"1"}]"#;
+        let controller = Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()).unwrap();
+        let msg = ping(Some("1"));
+        let context = DiscordContext::empty();
+
+        let first = &controller.event_map["1"].events[&SupportedGatewayMessages::MESSAGE_CREATE][0].1;
+        assert!(first.handle(&context, &msg).await.is_err());
+
+        controller.handle_event(&context, msg.clone()).await;
+        match &controller.event_map["1"].events[&SupportedGatewayMessages::MESSAGE_CREATE][1].1 {
+            RuleVariant::MESSAGE_CREATE(rule) => assert!(matches!(rule.limits.check(msg.d.as_ref().unwrap()), LimitCheck::Run)),
+            _ => panic!("Wrong rule")
+        }
+    }
+
+    #[tokio::test]
+    async fn guild_stop_keeps_global_rules() {
+        let config = r#"[
+            {"rules":[{"event":"MESSAGE_CREATE","action":{"type":"AddRole","options":{"role_name":"missing","role_id":null}},"filters":{"content":"^!ping"},"stop":true}],"guild_id":"1","first_match":true},
+            {"rules":[{"event":"MESSAGE_CREATE","action":{"type":"AddRole","options":{"role_name":"missing","role_id":null}},"filters":{},"cooldown":{"seconds":60}}]}
+        ]"#;
+        let controller = Controller::new(serde_json::de::from_str::<Vec<ConfigSchema>>(config).unwrap()).unwrap();
+        let msg = ping(Some("1"));
+
+        controller.handle_event(&DiscordContext::empty(), msg.clone()).await;
+        // The global rule's cooldown started, so it ran
+        match &controller.global_events.events[&SupportedGatewayMessages::MESSAGE_CREATE][0].1 {
+            RuleVariant::MESSAGE_CREATE(rule) => assert!(matches!(rule.limits.check(msg.d.as_ref().unwrap()), LimitCheck::Suppressed(_))),
+            _ => panic!("Wrong rule")
+        }
+    }
+
+    #[test]
+    fn cooldowns_survive_reload() {
+        let config = |seconds: u64| serde_json::from_str::<Vec<ConfigSchema>>(&format!(
+            r#"[{{"rules":[{{"event":"MESSAGE_CREATE","action":{{"type":"Echo","options":{{"content":"pong"}}}},"filters":{{"content":"^!ping"}},"cooldown":{{"seconds":{}}}}}],"guild_id":"1"}}]"#,
+            seconds
+        )).unwrap();
+        let msg = ping(Some("1"));
+        let payload = msg.d.as_ref().unwrap();
+        // Only the limits are checked, so no action runs
+        let runs = |controller: &Controller| {
+            match &controller.event_map["1"].events[&SupportedGatewayMessages::MESSAGE_CREATE][0].1 {
+                RuleVariant::MESSAGE_CREATE(rule) => matches!(rule.limits.check(payload), LimitCheck::Run),
+                _ => panic!("Wrong rule")
+            }
+        };
+
+        let old = Controller::new(config(60)).unwrap();
+        assert!(runs(&old));
+        assert!(!runs(&old));
+
+        let mut unchanged = Controller::new(config(60)).unwrap();
+        unchanged.keep_limits(&old);
+        assert!(!runs(&unchanged));
+
+        // An edited rule starts over
+        let mut edited = Controller::new(config(30)).unwrap();
+        edited.keep_limits(&old);
+        assert!(runs(&edited));
+    }
+
+    #[test]
+    fn webhook_cooldowns_survive_reload() {
+        let config = |headers: &str| serde_json::from_str::<Vec<ConfigSchema>>(&format!(
+            r#"[{{"rules":[{{"event":"MESSAGE_CREATE","action":{{"type":"Webhook","options":{{"url":"http://localhost","headers":{}}}}},"filters":{{}},"cooldown":{{"seconds":60}}}}],"guild_id":"1"}}]"#,
+            headers
+        )).unwrap();
+        let msg = ping(Some("1"));
+        let runs = |controller: &Controller| {
+            match &controller.event_map["1"].events[&SupportedGatewayMessages::MESSAGE_CREATE][0].1 {
+                RuleVariant::MESSAGE_CREATE(rule) => matches!(rule.limits.check(msg.d.as_ref().unwrap()), LimitCheck::Run),
+                _ => panic!("Wrong rule")
+            }
+        };
+
+        let old = Controller::new(config(r#"{"authorization":"jwt","x-source":"glenn","accept":"application/json"}"#)).unwrap();
+        assert!(runs(&old));
+        let mut reordered = Controller::new(config(r#"{"accept":"application/json","x-source":"glenn","authorization":"jwt"}"#)).unwrap();
+        reordered.keep_limits(&old);
+        assert!(!runs(&reordered));
+
+        let mut edited = Controller::new(config(r#"{"authorization":"jwt","x-source":"glenn"}"#)).unwrap();
+        edited.keep_limits(&old);
+        assert!(runs(&edited));
+    }
 
     use strum::IntoEnumIterator;
     #[test]
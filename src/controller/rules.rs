@@ -1,25 +1,120 @@
 use log::*;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
 use regex::Regex;
-use async_trait::async_trait;
 
 
-use crate::controller::actions::{ActionType, GatewayMessageHandler};
+use crate::controller::actions::{ActionType, GatewayMessageHandler, Render};
+use crate::controller::template;
+use crate::controller::limits::{Limits, LimitCheck};
+use crate::controller::sequence::{Actions, Sequence};
 use crate::DiscordContext;
+use crate::discord;
 use crate::gateway;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 #[serde(tag = "event")]
 pub enum RuleVariant {
-    MESSAGE_CREATE(Rule<MessageCreateFilter, ActionType>),
-    MESSAGE_REACTION_ADD(Rule<MessageReactionFilter, ActionType>),
-    MESSAGE_REACTION_REMOVE(Rule<MessageReactionFilter, ActionType>)
+    MESSAGE_CREATE(Rule<FilterExpr<MessageCreateFilter>, ActionType>),
+    MESSAGE_REACTION_ADD(Rule<FilterExpr<MessageReactionFilter>, ActionType>),
+    MESSAGE_REACTION_REMOVE(Rule<FilterExpr<MessageReactionFilter>, ActionType>),
+    GUILD_MEMBER_ADD(Rule<FilterExpr<MemberFilter>, ActionType>),
+    GUILD_MEMBER_REMOVE(Rule<FilterExpr<MemberFilter>, ActionType>),
+    GUILD_MEMBER_UPDATE(Rule<FilterExpr<MemberFilter>, ActionType>),
+    MESSAGE_UPDATE(Rule<FilterExpr<MessageChangeFilter>, ActionType>),
+    MESSAGE_DELETE(Rule<FilterExpr<MessageChangeFilter>, ActionType>)
 }
 
-#[async_trait]
-impl GatewayMessageHandler for RuleVariant {
-    async fn handle(&self, context: &DiscordContext, message: &gateway::GatewayMessage) -> Result<(), String> {
+impl RuleVariant {
+    /// Checks the rule's regexes and templates. On error, returns the field
+    /// and why.
+    pub fn validate(&self) -> Result<(), (String, String)> {
+        let (patterns, action, sequence, limits) = match self {
+            RuleVariant::MESSAGE_CREATE(rule) => (rule.filters.patterns(), &rule.action, &rule.sequence, &rule.limits),
+            RuleVariant::MESSAGE_REACTION_ADD(rule)
+            | RuleVariant::MESSAGE_REACTION_REMOVE(rule) => (rule.filters.patterns(), &rule.action, &rule.sequence, &rule.limits),
+            RuleVariant::GUILD_MEMBER_ADD(rule)
+            | RuleVariant::GUILD_MEMBER_REMOVE(rule)
+            | RuleVariant::GUILD_MEMBER_UPDATE(rule) => (rule.filters.patterns(), &rule.action, &rule.sequence, &rule.limits),
+            RuleVariant::MESSAGE_UPDATE(rule)
+            | RuleVariant::MESSAGE_DELETE(rule) => (rule.filters.patterns(), &rule.action, &rule.sequence, &rule.limits)
+        };
+        sequence.validate(action.steps())?;
+        limits.validate()?;
+        for (field, pattern) in patterns {
+            if let Some(error) = pattern.error() {
+                return Err((format!("filters.{}", field), error.clone()))
+            }
+        }
+        let mut steps: Vec<(String, &ActionType)> = match action {
+            Actions::One(step) => vec![(String::from("action"), step)],
+            Actions::Many(steps) => steps.iter().enumerate()
+                .map(|(index, step)| (format!("action[{}]", index), step))
+                .collect()
+        };
+        if let Some(reply) = limits.cooldown.as_ref().and_then(|cooldown| cooldown.reply.as_ref()) {
+            steps.push((String::from("cooldown.reply"), reply));
+        }
+        for (path, step) in steps {
+            for (field, template) in step.templates() {
+                if let Some(error) = template.error() {
+                    return Err((format!("{}.options.{}", path, field), error.clone()))
+                }
+            }
+            step.validate().map_err(|(field, error)| (format!("{}.options.{}", path, field), error))?;
+        }
+        Ok(())
+    }
+
+    /// Rules with a higher priority run first
+    pub fn priority(&self) -> i32 {
+        match self {
+            RuleVariant::MESSAGE_CREATE(rule) => rule.priority,
+            RuleVariant::MESSAGE_REACTION_ADD(rule)
+            | RuleVariant::MESSAGE_REACTION_REMOVE(rule) => rule.priority,
+            RuleVariant::GUILD_MEMBER_ADD(rule)
+            | RuleVariant::GUILD_MEMBER_REMOVE(rule)
+            | RuleVariant::GUILD_MEMBER_UPDATE(rule) => rule.priority,
+            RuleVariant::MESSAGE_UPDATE(rule)
+            | RuleVariant::MESSAGE_DELETE(rule) => rule.priority
+        }
+    }
+
+    /// Whether no other rules run once this one matches
+    pub fn stop(&self) -> bool {
+        match self {
+            RuleVariant::MESSAGE_CREATE(rule) => rule.stop,
+            RuleVariant::MESSAGE_REACTION_ADD(rule)
+            | RuleVariant::MESSAGE_REACTION_REMOVE(rule) => rule.stop,
+            RuleVariant::GUILD_MEMBER_ADD(rule)
+            | RuleVariant::GUILD_MEMBER_REMOVE(rule)
+            | RuleVariant::GUILD_MEMBER_UPDATE(rule) => rule.stop,
+            RuleVariant::MESSAGE_UPDATE(rule)
+            | RuleVariant::MESSAGE_DELETE(rule) => rule.stop
+        }
+    }
+
+    /// Keeps the cooldown and rate limit state of `old`, if it is the same
+    /// kind of rule
+    pub fn keep_limits(&mut self, old: &RuleVariant) {
+        match (self, old) {
+            (RuleVariant::MESSAGE_CREATE(rule), RuleVariant::MESSAGE_CREATE(old)) => rule.limits.keep_state(&old.limits),
+            (RuleVariant::MESSAGE_REACTION_ADD(rule), RuleVariant::MESSAGE_REACTION_ADD(old))
+            | (RuleVariant::MESSAGE_REACTION_REMOVE(rule), RuleVariant::MESSAGE_REACTION_REMOVE(old)) => rule.limits.keep_state(&old.limits),
+            (RuleVariant::GUILD_MEMBER_ADD(rule), RuleVariant::GUILD_MEMBER_ADD(old))
+            | (RuleVariant::GUILD_MEMBER_REMOVE(rule), RuleVariant::GUILD_MEMBER_REMOVE(old))
+            | (RuleVariant::GUILD_MEMBER_UPDATE(rule), RuleVariant::GUILD_MEMBER_UPDATE(old)) => rule.limits.keep_state(&old.limits),
+            (RuleVariant::MESSAGE_UPDATE(rule), RuleVariant::MESSAGE_UPDATE(old))
+            | (RuleVariant::MESSAGE_DELETE(rule), RuleVariant::MESSAGE_DELETE(old)) => rule.limits.keep_state(&old.limits),
+            _ => {}
+        }
+    }
+
+    pub async fn handle(&self, context: &DiscordContext, message: &gateway::GatewayMessage) -> Result<RuleOutcome, String> {
         match self {
             RuleVariant::MESSAGE_CREATE(rule) => {
                 rule.handle(context, message).await
@@ -30,75 +125,457 @@ impl GatewayMessageHandler for RuleVariant {
             RuleVariant::MESSAGE_REACTION_REMOVE(rule) => {
                 rule.handle(context, message).await
             }
+            RuleVariant::GUILD_MEMBER_ADD(rule)
+            | RuleVariant::GUILD_MEMBER_REMOVE(rule)
+            | RuleVariant::GUILD_MEMBER_UPDATE(rule) => {
+                rule.handle(context, message).await
+            }
+            RuleVariant::MESSAGE_UPDATE(rule)
+            | RuleVariant::MESSAGE_DELETE(rule) => {
+                rule.handle(context, message).await
+            }
         }
     }
 }
 
+/// What a rule did with an event. An `Err` from `handle` means it matched
+/// and its action failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RuleOutcome {
+    /// The filters did not match
+    Skipped,
+    /// Matched, but held back by the cooldown or rate limit
+    Suppressed,
+    Ran
+}
+impl RuleOutcome {
+    pub fn matched(&self) -> bool {
+        *self != RuleOutcome::Skipped
+    }
+}
+
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Rule<F, A> {
-    pub action: A,
-    pub filters: F
+    /// One action, or a list of them
+    pub action: Actions<A>,
+    pub filters: F,
+    /// Higher runs first. Rules with the same priority run in config order.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// Once this rule matches, the rules after it do not run. A rule whose
+    /// action fails has still matched.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stop: bool,
+    #[serde(flatten)]
+    pub sequence: Sequence,
+    #[serde(flatten)]
+    pub limits: Limits<A>
 }
 impl<F, A> Rule<F, A>
-where F: Filter,
-      A: GatewayMessageHandler
+where F: Filter + std::marker::Send + std::marker::Sync,
+      A: GatewayMessageHandler + Render + std::marker::Send + std::marker::Sync
 {
-    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> bool {
-        self.filters.filter(context, msg)
+    pub async fn handle(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Result<RuleOutcome, String> {
+        // Never act on our own events, whatever the filters say. A `not`
+        // filter would match them otherwise.
+        if msg.d.as_ref().and_then(|payload| payload.get_user_id()).as_ref() == Some(&context.me.id) {
+            return Ok(RuleOutcome::Skipped)
+        }
+        let matched = match self.filters.filter(context, msg) {
+            Some(matched) => matched,
+            None => return Ok(RuleOutcome::Skipped)
+        };
+        let event = msg.t.as_deref().unwrap_or_default();
+        let payload = match msg.d.as_ref() {
+            Some(payload) => payload,
+            None => {
+                return self.sequence.run(event, self.action.steps(), context, msg, &matched, None).await
+                    .map(|_| RuleOutcome::Ran)
+            }
+        };
+        let steps = match self.limits.check(payload) {
+            LimitCheck::Run => self.action.steps(),
+            LimitCheck::Reply(reply) => {
+                debug!("[{}] Rule on cooldown, replying instead", event);
+                std::slice::from_ref(reply)
+            },
+            LimitCheck::Suppressed(reason) => {
+                debug!("[{}] Rule suppressed by its {}", event, reason);
+                return Ok(RuleOutcome::Suppressed)
+            }
+        };
+        let scope = template::scope(context, payload, matched.template_values());
+        self.sequence.run(event, steps, context, msg, &matched, Some(&scope)).await
+            .map(|_| RuleOutcome::Ran)
     }
 }
 
-#[async_trait]
-impl<F, A> GatewayMessageHandler for Rule<F, A>
-where F: Filter + std::marker::Send + std::marker::Sync,
-      A: GatewayMessageHandler + std::marker::Send+ std::marker::Sync 
-{
-    async fn handle(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Result<(), String> {
-        if !self.filters.filter(context, msg) {
-            return Ok(())
+pub trait Filter {
+    /// The match, if the message passes the filter
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext>;
+    /// The regexes to validate at load, by field path
+    fn patterns(&self) -> Vec<(String, &Pattern)>;
+    /// Keys of the filter, besides the `RoleFilter` ones. Any other key is
+    /// refused so a typo doesn't become a filter that matches everything.
+    const FIELDS: &'static [&'static str];
+}
+
+/// Filters combined with `all`, `any` and `not`, e.g.
+/// `{"any": [{"content": "^!help"}, {"not": {"channel_name": "general"}}]}`.
+/// A plain filter object is a leaf, so older configs still parse.
+///
+/// `all` keeps the captures of every filter in it. `any` stops at the first
+/// filter that matches and only has its captures, even if later ones would
+/// match too. `not` has none.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum FilterExpr<F> {
+    All { all: Vec<FilterExpr<F>> },
+    Any { any: Vec<FilterExpr<F>> },
+    Not { not: Box<FilterExpr<F>> },
+    Leaf(F)
+}
+impl<F> From<F> for FilterExpr<F> {
+    fn from(filter: F) -> Self {
+        FilterExpr::Leaf(filter)
+    }
+}
+impl<F: Filter> Filter for FilterExpr<F> {
+    const FIELDS: &'static [&'static str] = F::FIELDS;
+    fn patterns(&self) -> Vec<(String, &Pattern)> {
+        match self {
+            FilterExpr::All { all } => nested_patterns("all", all),
+            FilterExpr::Any { any } => nested_patterns("any", any),
+            FilterExpr::Not { not } => not.patterns().into_iter()
+                .map(|(field, pattern)| (format!("not.{}", field), pattern))
+                .collect(),
+            FilterExpr::Leaf(filter) => filter.patterns()
+        }
+    }
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext> {
+        match self {
+            FilterExpr::All { all } => all.iter().try_fold(MatchContext::default(), |mut matched, expr| {
+                matched.captures.extend(expr.filter(context, msg)?.captures);
+                Some(matched)
+            }),
+            FilterExpr::Any { any } => any.iter().find_map(|expr| expr.filter(context, msg)),
+            FilterExpr::Not { not } => match not.filter(context, msg) {
+                Some(_) => None,
+                None => Some(MatchContext::default())
+            },
+            FilterExpr::Leaf(filter) => filter.filter(context, msg)
         }
-        self.action.handle(context, msg).await
     }
 }
 
-pub trait Filter {
-    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> bool;
+/// `all`, `any` and `not` must be the only key of their object. Errors keep
+/// the path to the filter, e.g. `all[1].not: unknown filter key ...`.
+impl<'de, F: Filter + DeserializeOwned> Deserialize<'de> for FilterExpr<F> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        FilterExpr::from_map(Map::deserialize(deserializer)?).map_err(|(path, e)| match path.as_str() {
+            "" => serde::de::Error::custom(e),
+            path => serde::de::Error::custom(format!("{}: {}", path, e))
+        })
+    }
+}
+impl<F: Filter + DeserializeOwned> FilterExpr<F> {
+    /// On error, returns the path to the filter and why
+    fn from_map(mut map: Map<String, Value>) -> Result<Self, (String, String)> {
+        let operators: Vec<&str> = ["all", "any", "not"].iter()
+            .filter(|operator| map.contains_key(**operator))
+            .cloned()
+            .collect();
+        match operators.as_slice() {
+            [] => {
+                if let Some(key) = map.keys().find(|key| !F::FIELDS.contains(&key.as_str()) && !RoleFilter::FIELDS.contains(&key.as_str())) {
+                    return Err((String::new(), format!(
+                        "unknown filter key `{}`, expected one of {}, {}, all, any or not",
+                        key, F::FIELDS.join(", "), RoleFilter::FIELDS.join(", ")
+                    )))
+                }
+                serde_json::from_value(Value::Object(map)).map(FilterExpr::Leaf).map_err(|e| (String::new(), e.to_string()))
+            },
+            [operator] if map.len() == 1 => {
+                let value = map.remove(*operator).unwrap_or_default();
+                match *operator {
+                    "not" => FilterExpr::from_value(value, String::from("not"))
+                        .map(|not| FilterExpr::Not { not: Box::new(not) }),
+                    operator => {
+                        let values = match value {
+                            Value::Array(values) => values,
+                            _ => return Err((String::from(operator), String::from("expected a list of filters")))
+                        };
+                        let exprs = values.into_iter().enumerate()
+                            .map(|(index, value)| FilterExpr::from_value(value, format!("{}[{}]", operator, index)))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        Ok(if operator == "all" { FilterExpr::All { all: exprs } } else { FilterExpr::Any { any: exprs } })
+                    }
+                }
+            },
+            _ => Err((String::new(), format!(
+                "`{}` can not be combined with other keys, put them in an `all` list instead",
+                operators.join("`, `")
+            )))
+        }
+    }
+
+    /// A nested filter at `path`
+    fn from_value(value: Value, path: String) -> Result<Self, (String, String)> {
+        let map = match value {
+            Value::Object(map) => map,
+            _ => return Err((path, String::from("expected a filter object")))
+        };
+        FilterExpr::from_map(map).map_err(|(field, e)| match field.as_str() {
+            "" => (path, e),
+            field => (format!("{}.{}", path, field), e)
+        })
+    }
+}
+
+fn nested_patterns<'a, F: Filter>(key: &str, exprs: &'a [FilterExpr<F>]) -> Vec<(String, &'a Pattern)> {
+    exprs.iter().enumerate()
+        .flat_map(|(index, expr)| expr.patterns().into_iter()
+            .map(move |(field, pattern)| (format!("{}[{}].{}", key, index, field), pattern)))
+        .collect()
 }
 
-fn regex_match(reg_str: &String, string: &String) -> bool {
-    Regex::new(reg_str.as_str()).unwrap().is_match(string.as_str()) 
+/// What a filter matched, for the action
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MatchContext {
+    /// Capture groups of each regex field that matched (`content`,
+    /// `username`, `channel_name`...), by index and by name
+    pub captures: HashMap<String, HashMap<String, String>>
+}
+impl MatchContext {
+    /// Like `regex_match`, but keeps the capture groups under `field`
+    fn capture(&mut self, field: &str, pattern: &Pattern, string: &str) -> bool {
+        let re = match pattern.regex() {
+            Some(re) => re,
+            None => return false
+        };
+        let found = match re.captures(string) {
+            Some(found) => found,
+            None => return false
+        };
+        let mut captures = HashMap::new();
+        for (index, name) in re.capture_names().enumerate() {
+            if let Some(text) = found.get(index) {
+                captures.insert(index.to_string(), String::from(text.as_str()));
+                if let Some(name) = name {
+                    captures.insert(String::from(name), String::from(text.as_str()));
+                }
+            }
+        }
+        self.captures.insert(String::from(field), captures);
+        true
+    }
+
+    /// A named capture group from any field
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.captures.values().find_map(|captures| captures.get(name))
+    }
+
+    /// `captures.<field>.<group>` for templates. Named groups are also
+    /// directly under `captures`.
+    pub fn template_values(&self) -> Map<String, Value> {
+        let mut captures = Map::new();
+        for groups in self.captures.values() {
+            for (group, text) in groups.iter() {
+                if group.parse::<usize>().is_err() {
+                    captures.insert(group.clone(), Value::String(text.clone()));
+                }
+            }
+        }
+        for (field, groups) in self.captures.iter() {
+            let groups = groups.iter().map(|(group, text)| (group.clone(), Value::String(text.clone()))).collect();
+            captures.insert(field.clone(), Value::Object(groups));
+        }
+        let mut values = Map::new();
+        values.insert(String::from("captures"), Value::Object(captures));
+        values
+    }
+}
+
+fn regex_match(pattern: &Pattern, string: &str) -> bool {
+    pattern.regex().is_some_and(|re| re.is_match(string))
+}
+
+/// A regex from the config, compiled once when the config is loaded.
+/// Invalid patterns are kept so `Controller::new` can say where they are;
+/// they never match.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    source: String,
+    regex: Result<Regex, String>
+}
+impl Pattern {
+    pub fn as_str(&self) -> &str {
+        self.source.as_str()
+    }
+    pub fn regex(&self) -> Option<&Regex> {
+        self.regex.as_ref().ok()
+    }
+    pub fn error(&self) -> Option<&String> {
+        self.regex.as_ref().err()
+    }
+}
+impl From<&str> for Pattern {
+    fn from(source: &str) -> Self {
+        Pattern {
+            source: String::from(source),
+            regex: Regex::new(source).map_err(|e| e.to_string())
+        }
+    }
+}
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        String::deserialize(deserializer).map(|source| Pattern::from(source.as_str()))
+    }
+}
+
+/// The patterns that are set, by field name
+fn set_patterns<'a>(fields: Vec<(&'static str, &'a Option<Pattern>)>) -> Vec<(String, &'a Pattern)> {
+    fields.into_iter()
+        .filter_map(|(field, pattern)| pattern.as_ref().map(|pattern| (String::from(field), pattern)))
+        .collect()
+}
+
+/// Checks on the roles of the member behind an event. Part of every filter.
+/// They never match when we don't know the member's roles, e.g. in DMs.
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct RoleFilter {
+    /// Role name or ID the member must have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_role: Option<String>,
+    /// Role name or ID the member must not have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lacks_role: Option<String>,
+    /// e.g. "MANAGE_MESSAGES". Guild-wide: channel overwrites are not taken
+    /// into account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_permission: Option<discord::Permission>
+}
+impl RoleFilter {
+    const FIELDS: &'static [&'static str] = &["has_role", "lacks_role", "has_permission"];
+
+    fn matches(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> bool {
+        if self.has_role.is_none() && self.lacks_role.is_none() && self.has_permission.is_none() {
+            return true
+        }
+        let payload = match msg.d.as_ref() {
+            Some(payload) => payload,
+            None => return false
+        };
+        let (guild_id, user_id) = match (payload.get_guild_id(), payload.get_user_id()) {
+            (Some(guild_id), Some(user_id)) => (guild_id, user_id),
+            _ => return false
+        };
+        let guild = match context.get_guild(&guild_id) {
+            Some(guild) => guild,
+            None => return false
+        };
+        let roles = match RoleFilter::event_roles(payload).cloned()
+            .or_else(|| context.get_member(&guild_id, &user_id).map(|member| member.roles)) {
+            Some(roles) => roles,
+            None => return false
+        };
+        let has_role = |name_or_id: &String| roles.iter().any(|role_id| {
+            role_id == name_or_id || guild.roles.iter().flatten()
+                .any(|role| &role.id == role_id && &role.name == name_or_id)
+        });
+
+        if let Some(role) = &self.has_role {
+            if !has_role(role) {
+                return false
+            }
+        }
+        if let Some(role) = &self.lacks_role {
+            if has_role(role) {
+                return false
+            }
+        }
+        if let Some(permission) = &self.has_permission {
+            if guild.member_permissions(&user_id, &roles) & permission.bit() == 0 {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Roles sent with the event, if any
+    fn event_roles(payload: &gateway::GatewayMessageType) -> Option<&Vec<String>> {
+        match payload {
+            gateway::GatewayMessageType::MessageCreate(msg) => msg.member.as_ref().map(|member| &member.roles),
+            gateway::GatewayMessageType::MessageUpdate(event) => event.member.as_ref().map(|member| &member.roles),
+            gateway::GatewayMessageType::MessageReactionAdd(react) => react.member.as_ref().map(|member| &member.roles),
+            gateway::GatewayMessageType::GuildMemberAdd(event) => Some(&event.member.roles),
+            gateway::GatewayMessageType::GuildMemberUpdate(event) => Some(&event.roles),
+            gateway::GatewayMessageType::GuildMemberRemove(event) => event.roles.as_ref(),
+            _ => None
+        }
+    }
+}
+
+/// `Some(true)` only matches DMs, `Some(false)` only matches guilds
+fn dm_match(dm: Option<bool>, guild_id: Option<&String>) -> bool {
+    dm.is_none_or(|dm| dm == guild_id.is_none())
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct MessageCreateFilter {
     /// Message content regex
-    pub content: Option<String>,
+    pub content: Option<Pattern>,
     /// Channel name regex
-    pub channel_name: Option<String>,
+    pub channel_name: Option<Pattern>,
     /// Username regex (include # or not)
-    pub username: Option<String>,
+    pub username: Option<Pattern>,
     /// Are there image attachments?
-    pub attachments: Option<bool>
+    pub attachments: Option<bool>,
+    /// Only DMs (true) or only guild messages (false)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dm: Option<bool>,
+    #[serde(flatten)]
+    pub roles: RoleFilter
 }
 impl Filter for MessageCreateFilter {
-    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> bool {
+    const FIELDS: &'static [&'static str] = &["content", "channel_name", "username", "attachments", "dm"];
+    fn patterns(&self) -> Vec<(String, &Pattern)> {
+        set_patterns(vec![("content", &self.content), ("channel_name", &self.channel_name), ("username", &self.username)])
+    }
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext> {
+        let mut matched = MatchContext::default();
+        if !self.roles.matches(context, msg) {
+            return None
+        }
         match msg.d.clone().unwrap() {
             gateway::GatewayMessageType::MessageCreate(msg) => {
                 if context.me.id == msg.author.id {
-                    return false;
+                    return None;
+                }
+                if !dm_match(self.dm, msg.guild_id.as_ref()) {
+                    return None;
                 }
                 // check username 
                 let author = format!("{}#{}", msg.author.username, msg.author.discriminator);
                 if let Some(searched_user) = &self.username {
-                    if !regex_match(&searched_user, &author) {
-                        return false
+                    if !matched.capture("username", searched_user, &author) {
+                        return None
                     }
                 }
                 // Check message content
                 let content = msg.content;
                 if let Some(re_content) = &self.content {
-                    if !regex_match(&re_content, &content) {
-                        return false;
+                    if !matched.capture("content", re_content, &content) {
+                        return None;
                     }
                 }
                 // Check if there is an attachment
@@ -106,23 +583,25 @@ impl Filter for MessageCreateFilter {
                     let count = msg.attachments.len();
                     if *attachments {
                         if count == 0 {
-                            return false;
+                            return None;
                         }
                     } else {
                         if count > 0 {
-                            return false;
+                            return None;
                         }
                     }
                 }
 
-                // Check channel_name
+                // Check channel_name. DM channels have no name.
                 if let Some(searched_channel_name) = self.channel_name.as_ref() {
-                    if let Some(channels) = context.guild_map.get(&msg.guild_id).unwrap().channels.as_ref() {
+                    let guild_id = msg.guild_id.as_ref()?;
+                    let guild = context.get_guild(guild_id)?;
+                    if let Some(channels) = guild.channels.as_ref() {
                         for channel in channels {
                             if channel.id == msg.channel_id {
                                 if let Some(channel_name) = channel.name.as_ref() {
-                                    if !regex_match(&searched_channel_name, channel_name) {
-                                        return false
+                                    if !matched.capture("channel_name", searched_channel_name, channel_name) {
+                                        return None
                                     }
                                 }
                                 break
@@ -130,9 +609,9 @@ impl Filter for MessageCreateFilter {
                         }
                     }
                 }
-                true
+                Some(matched)
             },
-            _ => false
+            _ => None
         }
     }
 }
@@ -140,91 +619,634 @@ impl Filter for MessageCreateFilter {
 #[derive(Clone, Serialize, Deserialize)]
 pub struct MessageReactionFilter {
     /// Message content regex
-    pub channel_name: Option<String>,
+    pub channel_name: Option<Pattern>,
     /// Username regex (include # or not)
-    pub username: Option<String>,
-    /// React (custom emoji name or unicode)
-    pub react: Option<String>
+    pub username: Option<Pattern>,
+    /// React (custom emoji name or unicode). Emojis that are not a valid
+    /// regex are matched as they are.
+    pub react: Option<Pattern>,
+    /// Only DMs (true) or only guild messages (false)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dm: Option<bool>,
+    #[serde(flatten)]
+    pub roles: RoleFilter
 }
 impl Filter for MessageReactionFilter {
-    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> bool {
+    const FIELDS: &'static [&'static str] = &["channel_name", "username", "react", "dm"];
+    fn patterns(&self) -> Vec<(String, &Pattern)> {
+        set_patterns(vec![("channel_name", &self.channel_name), ("username", &self.username)])
+    }
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext> {
+        let mut matched = MatchContext::default();
+        if !self.roles.matches(context, msg) {
+            return None
+        }
         let payload = msg.d.clone().unwrap();
         match payload {
             gateway::GatewayMessageType::MessageReactionAdd(react) => {
                 if react.user_id == context.me.id {
-                    return false;
+                    return None;
+                }
+                if !dm_match(self.dm, react.guild_id.as_ref()) {
+                    return None;
                 }
 
-                // Check channel_name
+                // Check channel_name. DM channels have no name.
                 if let Some(searched_channel_name) = self.channel_name.as_ref() {
-                    if let Some(channel) = context.get_channel(&react.guild_id, &react.channel_id) {
+                    let guild_id = react.guild_id.as_ref()?;
+                    if let Some(channel) = context.get_channel(guild_id, &react.channel_id) {
                         if let Some(channel_name) = channel.name.as_ref() {
-                            if !regex_match(&searched_channel_name, channel_name) {
-                                return false
+                            if !matched.capture("channel_name", searched_channel_name, channel_name) {
+                                return None
                             }
                         }
                     }
                 }
 
                 // Check username
-                let author = format!("{}#{}", react.member.user.username, react.member.user.discriminator);
                 if let Some(searched_user) = &self.username {
-                    if !regex_match(&searched_user, &author) {
-                        return false
+                    let author = match react.member.as_ref().and_then(|member| member.user.as_ref()) {
+                        Some(user) => format!("{}#{}", user.username, user.discriminator),
+                        None => return None
+                    };
+                    if !matched.capture("username", searched_user, &author) {
+                        return None
                     }
                 }
 
                 if let Some(react_str) = &self.react {
-                    if !regex_match(&react_str, &react.emoji.name) {
-                        if *react_str != react.emoji.name {
-                            return false
-                        }
+                    if !regex_match(react_str, &react.emoji.name) && react_str.as_str() != react.emoji.name {
+                        return None
                     }
                 }
 
-                true
+                Some(matched)
             },
             gateway::GatewayMessageType::MessageReactionRemove(react) => {
                 if react.user_id == context.me.id {
-                    return false;
+                    return None;
+                }
+                if !dm_match(self.dm, react.guild_id.as_ref()) {
+                    return None;
                 }
 
-                // Check channel_name
+                // Check channel_name. DM channels have no name.
                 if let Some(searched_channel_name) = self.channel_name.as_ref() {
-                    if let Some(channel) = context.get_channel(&react.guild_id, &react.channel_id) {
+                    let guild_id = react.guild_id.as_ref()?;
+                    if let Some(channel) = context.get_channel(guild_id, &react.channel_id) {
                         if let Some(channel_name) = channel.name.as_ref() {
-                            if !regex_match(&searched_channel_name, channel_name) {
-                                return false
+                            if !matched.capture("channel_name", searched_channel_name, channel_name) {
+                                return None
                             }
                         }
                     }
                 }
 
-                // Check username
-                if let Some(_) = &self.username {
-                    // TODO check actual username
-                    return false
+                // Check username. The event has no member, so this only works
+                // for members we have cached, and never in DMs.
+                if let Some(searched_user) = &self.username {
+                    let member = react.guild_id.as_ref()
+                        .and_then(|guild_id| context.get_member(guild_id, &react.user_id));
+                    let author = match member.and_then(|member| member.user) {
+                        Some(user) => format!("{}#{}", user.username, user.discriminator),
+                        None => return None
+                    };
+                    if !matched.capture("username", searched_user, &author) {
+                        return None
+                    }
                 }
 
                 if let Some(react_str) = &self.react {
-                    if !regex_match(&react_str, &react.emoji.name) {
-                        return false
+                    if !regex_match(react_str, &react.emoji.name) && react_str.as_str() != react.emoji.name {
+                        return None
                     }
                 }
 
-                true
+                Some(matched)
+            }
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct MemberFilter {
+    /// Username regex (include # or not)
+    pub username: Option<Pattern>,
+    /// Only match accounts created at least this many days ago
+    pub min_account_age_days: Option<u64>,
+    /// Only match accounts created at most this many days ago
+    pub max_account_age_days: Option<u64>,
+    /// Role name regex. GUILD_MEMBER_UPDATE only: matches if a role with a
+    /// matching name was just given to the member.
+    pub roles_added: Option<Pattern>,
+    /// Role name regex. GUILD_MEMBER_UPDATE only: matches if a role with a
+    /// matching name was just taken from the member.
+    pub roles_removed: Option<Pattern>,
+    #[serde(flatten)]
+    pub roles: RoleFilter
+}
+impl MemberFilter {
+    fn role_names_match(context: &DiscordContext, guild_id: &String, role_ids: &[&String], pattern: &Pattern) -> bool {
+        let guild = context.get_guild(guild_id);
+        let roles = match guild.as_ref().and_then(|guild| guild.roles.as_ref()) {
+            Some(roles) => roles,
+            None => return false
+        };
+        roles.iter()
+            .filter(|role| role_ids.contains(&&role.id))
+            .any(|role| regex_match(pattern, &role.name))
+    }
+}
+impl Filter for MemberFilter {
+    const FIELDS: &'static [&'static str] = &["username", "min_account_age_days", "max_account_age_days", "roles_added", "roles_removed"];
+    fn patterns(&self) -> Vec<(String, &Pattern)> {
+        set_patterns(vec![("username", &self.username), ("roles_added", &self.roles_added), ("roles_removed", &self.roles_removed)])
+    }
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext> {
+        let mut matched = MatchContext::default();
+        if !self.roles.matches(context, msg) {
+            return None
+        }
+        let (guild_id, user, roles, previous_roles) = match msg.d.as_ref() {
+            Some(gateway::GatewayMessageType::GuildMemberAdd(event)) => match event.member.user.as_ref() {
+                Some(user) => (&event.guild_id, user, None, None),
+                None => return None
+            },
+            Some(gateway::GatewayMessageType::GuildMemberRemove(event)) => (&event.guild_id, &event.user, None, None),
+            Some(gateway::GatewayMessageType::GuildMemberUpdate(event)) => (
+                &event.guild_id, &event.user, Some(&event.roles), event.previous_roles.as_ref()
+            ),
+            _ => return None
+        };
+        if user.id == context.me.id {
+            return None
+        }
+
+        // Check username
+        let username = format!("{}#{}", user.username, user.discriminator);
+        if let Some(searched_user) = &self.username {
+            if !matched.capture("username", searched_user, &username) {
+                return None
+            }
+        }
+
+        // Check account age
+        if self.min_account_age_days.is_some() || self.max_account_age_days.is_some() {
+            let created_at = discord::snowflake_timestamp(&user.id)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_millis() as u64).unwrap_or(0);
+            let age_days = now.saturating_sub(created_at) / (24 * 60 * 60 * 1000);
+            if self.min_account_age_days.is_some_and(|min| age_days < min) {
+                return None
+            }
+            if self.max_account_age_days.is_some_and(|max| age_days > max) {
+                return None
+            }
+        }
+
+        // Check role changes. Without the roles from before the update we
+        // can't tell what changed.
+        if self.roles_added.is_some() || self.roles_removed.is_some() {
+            let (roles, previous_roles) = match (roles, previous_roles) {
+                (Some(roles), Some(previous_roles)) => (roles, previous_roles),
+                _ => return None
+            };
+            if let Some(searched_role) = &self.roles_added {
+                let added: Vec<&String> = roles.iter().filter(|role| !previous_roles.contains(role)).collect();
+                if !MemberFilter::role_names_match(context, guild_id, &added, searched_role) {
+                    return None
+                }
+            }
+            if let Some(searched_role) = &self.roles_removed {
+                let removed: Vec<&String> = previous_roles.iter().filter(|role| !roles.contains(role)).collect();
+                if !MemberFilter::role_names_match(context, guild_id, &removed, searched_role) {
+                    return None
+                }
+            }
+        }
+
+        Some(matched)
+    }
+}
+
+/// For MESSAGE_UPDATE and MESSAGE_DELETE. The content before an edit or
+/// delete is only known for messages still in the message cache; filters on
+/// it never match otherwise.
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct MessageChangeFilter {
+    /// Regex on the content before the edit or delete
+    pub content_before: Option<Pattern>,
+    /// Regex on the content after the edit. MESSAGE_UPDATE only.
+    pub content_after: Option<Pattern>,
+    /// Channel name regex
+    pub channel_name: Option<Pattern>,
+    /// Author username regex (include # or not)
+    pub username: Option<Pattern>,
+    #[serde(flatten)]
+    pub roles: RoleFilter
+}
+impl Filter for MessageChangeFilter {
+    const FIELDS: &'static [&'static str] = &["content_before", "content_after", "channel_name", "username"];
+    fn patterns(&self) -> Vec<(String, &Pattern)> {
+        set_patterns(vec![("content_before", &self.content_before), ("content_after", &self.content_after), ("channel_name", &self.channel_name), ("username", &self.username)])
+    }
+    fn filter(&self, context: &DiscordContext, msg: &gateway::GatewayMessage) -> Option<MatchContext> {
+        let mut matched = MatchContext::default();
+        if !self.roles.matches(context, msg) {
+            return None
+        }
+        let (guild_id, channel_id, author, before, after) = match msg.d.as_ref() {
+            Some(gateway::GatewayMessageType::MessageUpdate(event)) => (
+                event.guild_id.as_ref(),
+                &event.channel_id,
+                event.author.as_ref().or(event.previous.as_ref().map(|previous| &previous.author)),
+                event.previous.as_ref().map(|previous| &previous.content),
+                event.content.as_ref()
+            ),
+            Some(gateway::GatewayMessageType::MessageDelete(event)) => (
+                event.guild_id.as_ref(),
+                &event.channel_id,
+                event.message.as_ref().map(|message| &message.author),
+                event.message.as_ref().map(|message| &message.content),
+                None
+            ),
+            _ => return None
+        };
+        if author.is_some_and(|author| author.id == context.me.id) {
+            return None
+        }
+
+        // Check username
+        if let Some(searched_user) = &self.username {
+            let author = match author {
+                Some(author) => format!("{}#{}", author.username, author.discriminator),
+                None => return None
+            };
+            if !matched.capture("username", searched_user, &author) {
+                return None
+            }
+        }
+
+        // Check content
+        for (field, searched_content, content) in [
+            ("content_before", &self.content_before, before),
+            ("content_after", &self.content_after, after)
+        ].iter() {
+            if let Some(searched_content) = searched_content {
+                match content {
+                    Some(content) if matched.capture(field, searched_content, content) => {},
+                    _ => return None
+                }
+            }
+        }
+
+        // Check channel_name
+        if let Some(searched_channel_name) = self.channel_name.as_ref() {
+            let channel_name = guild_id
+                .and_then(|guild_id| context.get_channel(guild_id, channel_id))
+                .and_then(|channel| channel.name);
+            match channel_name {
+                Some(channel_name) if matched.capture("channel_name", searched_channel_name, &channel_name) => {},
+                _ => return None
             }
-            _ => false
         }
+
+        Some(matched)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::regex_match;
+    use super::*;
 
     #[test]
     fn regex_match_emoji() {
-        assert!(regex_match(&"2️⃣".into(), &"2️⃣".into()));
+        assert!(regex_match(&"2️⃣".into(), "2️⃣"));
+    }
+
+    fn member_update(roles: Vec<&str>, previous_roles: Vec<&str>) -> gateway::GatewayMessage {
+        gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(gateway::GatewayMessageType::GuildMemberUpdate(discord::GuildMemberUpdate {
+                guild_id: String::from("1"),
+                roles: roles.into_iter().map(String::from).collect(),
+                previous_roles: Some(previous_roles.into_iter().map(String::from).collect()),
+                // Created 2017-10-15
+                user: discord::User {
+                    id: String::from("368952148962181124"),
+                    username: String::from("lomz"),
+                    discriminator: String::from("2555"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            s: None,
+            t: Some(String::from("GUILD_MEMBER_UPDATE"))
+        }
+    }
+
+    #[test]
+    fn member_filter_roles_added() {
+        let context = DiscordContext::empty();
+        context.insert_guild(discord::Guild {
+            id: String::from("1"),
+            roles: Some(vec![
+                discord::Role { id: String::from("10"), name: String::from("newcomer"), ..Default::default() },
+                discord::Role { id: String::from("11"), name: String::from("member"), ..Default::default() },
+            ]),
+            ..Default::default()
+        });
+        let filter = MemberFilter {
+            roles_added: Some(Pattern::from("^member$")),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &member_update(vec!["10", "11"], vec!["10"])).is_some());
+        assert!(filter.filter(&context, &member_update(vec!["10"], vec!["10", "11"])).is_none());
+
+        let filter = MemberFilter {
+            roles_removed: Some(Pattern::from("newcomer")),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &member_update(vec!["11"], vec!["10"])).is_some());
+        assert!(filter.filter(&context, &member_update(vec!["10", "11"], vec!["10"])).is_none());
+    }
+
+    #[test]
+    fn member_filter_account_age() {
+        let context = DiscordContext::empty();
+        let filter = MemberFilter {
+            min_account_age_days: Some(7),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &member_update(vec![], vec![])).is_some());
+        let filter = MemberFilter {
+            max_account_age_days: Some(7),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &member_update(vec![], vec![])).is_none());
+    }
+
+    #[test]
+    fn actions_are_rendered_with_captures() {
+        let context = DiscordContext::empty();
+        let filter = MessageCreateFilter {
+            content: Some(Pattern::from(r"^!echo (?P<text>.+)")),
+            channel_name: None,
+            username: None,
+            attachments: None,
+            dm: None,
+            roles: RoleFilter::default()
+        };
+        let msg = gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(gateway::GatewayMessageType::MessageCreate(discord::Message {
+                content: String::from("!echo hi there"),
+                author: discord::User {
+                    id: String::from("3"),
+                    username: String::from("lomz"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            s: None,
+            t: Some(String::from("MESSAGE_CREATE"))
+        };
+        let action: ActionType = serde_json::from_str(
+            r#"{"type":"Echo","options":{"content":"{{author.mention}} said {{captures.text | upper}}","file":null}}"#
+        ).unwrap();
+        let scope = template::scope(&context, msg.d.as_ref().unwrap(), filter.filter(&context, &msg).unwrap().template_values());
+        match action.render(&scope).unwrap() {
+            ActionType::Echo(options) => assert_eq!(options.content.unwrap().as_str(), "<@3> said HI THERE"),
+            _ => panic!("Rendered incorrectly")
+        }
+    }
+
+    #[test]
+    fn filters_return_captures() {
+        let context = DiscordContext::empty();
+        let filter = MessageCreateFilter {
+            content: Some(Pattern::from(r"^!role (?P<role>\w+)")),
+            channel_name: None,
+            username: Some(Pattern::from(r"^(\w+)#")),
+            attachments: None,
+            dm: None,
+            roles: RoleFilter::default()
+        };
+        let msg = gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(gateway::GatewayMessageType::MessageCreate(discord::Message {
+                content: String::from("!role artist"),
+                author: discord::User {
+                    id: String::from("3"),
+                    username: String::from("lomz"),
+                    discriminator: String::from("2555"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            s: None,
+            t: Some(String::from("MESSAGE_CREATE"))
+        };
+        let matched = filter.filter(&context, &msg).unwrap();
+        assert_eq!(matched.get("role").unwrap(), "artist");
+        assert_eq!(matched.captures["content"]["1"], "artist");
+        assert_eq!(matched.captures["username"]["1"], "lomz");
+        assert_eq!(template::render("{{captures.role}} {{captures.username.1}}", &Value::Object(matched.template_values())).unwrap(), "artist lomz");
+    }
+
+    fn message_create(content: &str) -> gateway::GatewayMessage {
+        gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(gateway::GatewayMessageType::MessageCreate(discord::Message {
+                content: String::from(content),
+                author: discord::User {
+                    id: String::from("3"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })),
+            s: None,
+            t: Some(String::from("MESSAGE_CREATE"))
+        }
+    }
+
+    #[test]
+    fn filter_expressions() {
+        let context = DiscordContext::empty();
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(
+            r#"{"all":[{"content":"^!(?P<command>\\w+)"},{"not":{"any":[{"content":"^!admin"},{"content":"^!ban"}]}}]}"#
+        ).unwrap();
+        assert_eq!(filter.filter(&context, &message_create("!help")).unwrap().get("command").unwrap(), "help");
+        assert!(filter.filter(&context, &message_create("!ban lomz")).is_none());
+        assert!(filter.filter(&context, &message_create("help")).is_none());
+
+        // Flat filters still work
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(
+            r#"{"content":"^!help","channel_name":null,"username":null,"attachments":null}"#
+        ).unwrap();
+        assert!(filter.filter(&context, &message_create("!help")).is_some());
+
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(r#"{"any":[{"content":"a"},{"not":{"username":"("}}]}"#).unwrap();
+        let patterns: Vec<String> = filter.patterns().into_iter()
+            .filter(|(_, pattern)| pattern.error().is_some())
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(patterns, vec!["any[1].not.username"]);
+    }
+
+    #[test]
+    fn any_has_the_first_match_captures() {
+        let context = DiscordContext::empty();
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(
+            r#"{"any":[{"content":"^!(?P<first>\\w+)"},{"content":"(?P<second>\\w+)$"}]}"#
+        ).unwrap();
+        let matched = filter.filter(&context, &message_create("!help me")).unwrap();
+        assert_eq!(matched.get("first").unwrap(), "help");
+        assert!(matched.get("second").is_none());
+        let matched = filter.filter(&context, &message_create("help me")).unwrap();
+        assert_eq!(matched.get("second").unwrap(), "me");
+    }
+
+    #[test]
+    fn filter_keys_are_checked() {
+        let error = |filter: &str| serde_json::from_str::<FilterExpr<MessageCreateFilter>>(filter).err().unwrap().to_string();
+        assert!(error(r#"{"contnet":"^!help"}"#).starts_with("unknown filter key `contnet`"));
+        assert!(error(r#"{"all":[{"content":"a"},{"not":{"contnet":"b"}}]}"#).starts_with("all[1].not: unknown filter key `contnet`"));
+        assert!(error(r#"{"all":[{"content":"a"}],"content":"b"}"#).starts_with("`all` can not be combined"));
+        assert!(error(r#"{"any":[],"not":{}}"#).starts_with("`any`, `not` can not be combined"));
+        assert!(error(r#"{"any":{"content":"a"}}"#).starts_with("any: expected a list"));
+
+        let rule = r#"{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi"}},"filters":{"contnet":"^!hi"}}"#;
+        assert!(serde_json::from_str::<RuleVariant>(rule).err().unwrap().to_string().contains("unknown filter key `contnet`"));
+    }
+
+    #[test]
+    fn role_filters() {
+        let context = DiscordContext::empty();
+        context.insert_guild(discord::Guild {
+            id: String::from("1"),
+            roles: Some(vec![
+                discord::Role { id: String::from("1"), name: String::from("@everyone"), permissions: String::from("1024"), ..Default::default() },
+                discord::Role { id: String::from("10"), name: String::from("mod"), permissions: String::from("8192"), ..Default::default() },
+                discord::Role { id: String::from("11"), name: String::from("muted"), permissions: String::from("0"), ..Default::default() },
+            ]),
+            ..Default::default()
+        });
+        let message = |roles: Vec<&str>| {
+            let mut msg = message_create("!purge");
+            if let Some(gateway::GatewayMessageType::MessageCreate(msg)) = msg.d.as_mut() {
+                msg.guild_id = Some(String::from("1"));
+                msg.member = Some(discord::Member {
+                    roles: roles.into_iter().map(String::from).collect(),
+                    ..Default::default()
+                });
+            }
+            msg
+        };
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(
+            r#"{"content":"^!purge","has_permission":"MANAGE_MESSAGES","lacks_role":"muted"}"#
+        ).unwrap();
+        assert!(filter.filter(&context, &message(vec!["10"])).is_some());
+        assert!(filter.filter(&context, &message(vec![])).is_none());
+        assert!(filter.filter(&context, &message(vec!["10", "11"])).is_none());
+
+        let filter: FilterExpr<MessageCreateFilter> = serde_json::from_str(r#"{"has_role":"mod"}"#).unwrap();
+        assert!(filter.filter(&context, &message(vec!["10"])).is_some());
+        assert!(filter.filter(&context, &message(vec!["11"])).is_none());
+        // Nothing to go on in DMs
+        assert!(filter.filter(&context, &message_create("!purge")).is_none());
+    }
+
+    #[test]
+    fn role_filters_see_members_that_left() {
+        let context = DiscordContext::empty();
+        context.insert_guild(discord::Guild {
+            id: String::from("1"),
+            roles: Some(vec![discord::Role { id: String::from("10"), name: String::from("mod"), ..Default::default() }]),
+            ..Default::default()
+        });
+        let user = discord::User { id: String::from("3"), ..Default::default() };
+        context.cache_member("1", discord::Member {
+            user: Some(user.clone()),
+            roles: vec![String::from("10")],
+            ..Default::default()
+        });
+        let mut payload = gateway::GatewayMessageType::GuildMemberRemove(discord::GuildMemberRemove {
+            guild_id: String::from("1"),
+            user,
+            roles: None
+        });
+        context.annotate(&mut payload);
+        context.update(&payload);
+        assert!(context.get_member(&String::from("1"), &String::from("3")).is_none());
+
+        let msg = gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(payload),
+            s: None,
+            t: Some(String::from("GUILD_MEMBER_REMOVE"))
+        };
+        let filter: FilterExpr<MemberFilter> = serde_json::from_str(r#"{"has_role":"mod"}"#).unwrap();
+        assert!(filter.filter(&context, &msg).is_some());
+        let filter: FilterExpr<MemberFilter> = serde_json::from_str(r#"{"lacks_role":"mod"}"#).unwrap();
+        assert!(filter.filter(&context, &msg).is_none());
+    }
+
+    #[test]
+    fn message_change_filter_uses_cached_content() {
+        let context = DiscordContext::empty();
+        let message = discord::Message {
+            id: String::from("5"),
+            channel_id: String::from("2"),
+            content: String::from("buy cheap nitro"),
+            author: discord::User {
+                id: String::from("3"),
+                username: String::from("lomz"),
+                discriminator: String::from("2555"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        context.update(&gateway::GatewayMessageType::MessageCreate(message));
+
+        let mut payload = gateway::GatewayMessageType::MessageDelete(discord::MessageDelete {
+            id: String::from("5"),
+            channel_id: String::from("2"),
+            ..Default::default()
+        });
+        context.annotate(&mut payload);
+        let deleted = gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(payload),
+            s: None,
+            t: Some(String::from("MESSAGE_DELETE"))
+        };
+
+        let filter = MessageChangeFilter {
+            content_before: Some(Pattern::from("nitro")),
+            username: Some(Pattern::from("^lomz#")),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &deleted).is_some());
+        let filter = MessageChangeFilter {
+            content_after: Some(Pattern::from("nitro")),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &deleted).is_none());
+
+        // Once it is gone from the cache there is nothing to match on
+        context.update(deleted.d.as_ref().unwrap());
+        let mut payload = gateway::GatewayMessageType::MessageDelete(discord::MessageDelete {
+            id: String::from("5"),
+            channel_id: String::from("2"),
+            ..Default::default()
+        });
+        context.annotate(&mut payload);
+        let deleted = gateway::GatewayMessage { d: Some(payload), ..deleted };
+        let filter = MessageChangeFilter {
+            content_before: Some(Pattern::from("nitro")),
+            ..Default::default()
+        };
+        assert!(filter.filter(&context, &deleted).is_none());
     }
 }
@@ -0,0 +1,269 @@
+/// Rules with more than one action, e.g. react and then add a role.
+///
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use futures_util::future::join_all;
+use tokio::time::delay_for;
+use std::time::Duration;
+
+use crate::DiscordContext;
+use crate::gateway::GatewayMessage;
+use crate::controller::actions::{GatewayMessageHandler, Render};
+use crate::controller::rules::MatchContext;
+
+/// One action, or a list of them run as a `Sequence`
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Actions<A> {
+    One(A),
+    Many(Vec<A>)
+}
+impl<A> From<A> for Actions<A> {
+    fn from(action: A) -> Self {
+        Actions::One(action)
+    }
+}
+impl<A> Actions<A> {
+    pub fn steps(&self) -> &[A] {
+        match self {
+            Actions::One(action) => std::slice::from_ref(action),
+            Actions::Many(actions) => actions
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Run the remaining steps anyway
+    Continue,
+    /// Stop at the first step that fails
+    #[default]
+    Abort
+}
+
+/// How the steps of a rule's `action` list are run
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Sequence {
+    /// Milliseconds to wait between steps. This holds up the events behind
+    /// it on the same dispatch worker, i.e. from the same channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+    /// Start every step at once. Every step runs, so there is no `on_error`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub parallel: bool,
+    /// Defaults to abort
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<OnError>
+}
+
+impl Sequence {
+    pub fn validate<A>(&self, steps: &[A]) -> Result<(), (String, String)> {
+        if steps.is_empty() {
+            return Err((String::from("action"), String::from("needs at least one action")))
+        }
+        if self.parallel && self.delay_ms.is_some() {
+            return Err((String::from("delay_ms"), String::from("can not be used with parallel")))
+        }
+        if self.parallel && self.on_error.is_some() {
+            return Err((String::from("on_error"), String::from("can not be used with parallel")))
+        }
+        Ok(())
+    }
+
+    /// Runs `steps`, rendering them against `scope` if there is one. Each
+    /// step's result is logged; the error is a summary of the failures.
+    pub async fn run<A>(
+        &self,
+        event: &str,
+        steps: &[A],
+        context: &DiscordContext,
+        msg: &GatewayMessage,
+        matched: &MatchContext,
+        scope: Option<&Value>
+    ) -> Result<(), String>
+    where A: GatewayMessageHandler + Render + std::marker::Send + std::marker::Sync
+    {
+        let total = steps.len();
+        let report = |index: usize, result: &Result<(), String>| match result {
+            Ok(()) => debug!("[{}] Step {}/{} succeeded", event, index + 1, total),
+            Err(e) => warn!("[{}] Step {}/{} failed: {}", event, index + 1, total, e)
+        };
+
+        let results = if self.parallel {
+            let results = join_all(steps.iter().map(|step| run_step(step, context, msg, matched, scope))).await;
+            for (index, result) in results.iter().enumerate() {
+                report(index, result);
+            }
+            results
+        } else {
+            let mut results = vec![];
+            for (index, step) in steps.iter().enumerate() {
+                if index > 0 {
+                    if let Some(delay_ms) = self.delay_ms {
+                        delay_for(Duration::from_millis(delay_ms)).await;
+                    }
+                }
+                let result = run_step(step, context, msg, matched, scope).await;
+                report(index, &result);
+                let failed = result.is_err();
+                results.push(result);
+                if failed && self.on_error.unwrap_or_default() == OnError::Abort {
+                    break
+                }
+            }
+            results
+        };
+
+        let failed = results.iter().filter(|result| result.is_err()).count();
+        if failed == 0 {
+            Ok(())
+        } else if results.len() < total {
+            Err(format!("Aborted after step {}/{} failed", results.len(), total))
+        } else {
+            Err(format!("{} of {} steps failed", failed, total))
+        }
+    }
+}
+
+async fn run_step<A>(
+    step: &A,
+    context: &DiscordContext,
+    msg: &GatewayMessage,
+    matched: &MatchContext,
+    scope: Option<&Value>
+) -> Result<(), String>
+where A: GatewayMessageHandler + Render + std::marker::Send + std::marker::Sync
+{
+    match scope {
+        Some(scope) => step.render(scope)?.handle(context, msg, matched).await,
+        None => step.handle(context, msg, matched).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use crate::gateway;
+
+    /// Records that it ran, and fails if told to
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Step {
+        id: usize,
+        fail: bool,
+        #[serde(skip)]
+        ran: Arc<Mutex<Vec<usize>>>
+    }
+
+    #[async_trait]
+    impl GatewayMessageHandler for Step {
+        async fn handle(&self, _context: &DiscordContext, _message: &GatewayMessage, _matched: &MatchContext) -> Result<(), String> {
+            self.ran.lock().unwrap().push(self.id);
+            if self.fail { Err(format!("step {} failed", self.id)) } else { Ok(()) }
+        }
+    }
+    impl Render for Step {}
+
+    fn steps(fail: &[bool]) -> (Vec<Step>, Arc<Mutex<Vec<usize>>>) {
+        let ran = Arc::new(Mutex::new(vec![]));
+        let steps = fail.iter().enumerate()
+            .map(|(id, fail)| Step { id, fail: *fail, ran: ran.clone() })
+            .collect();
+        (steps, ran)
+    }
+
+    async fn run(sequence: &Sequence, steps: &[Step]) -> Result<(), String> {
+        let context = DiscordContext::empty();
+        let msg = gateway::GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: None,
+            s: None,
+            t: Some(String::from("MESSAGE_CREATE"))
+        };
+        sequence.run("MESSAGE_CREATE", steps, &context, &msg, &MatchContext::default(), None).await
+    }
+
+    #[tokio::test]
+    async fn sequence_on_error() {
+        let (abort_steps, ran) = steps(&[false, true, false]);
+        let result = run(&Sequence::default(), &abort_steps).await;
+        assert_eq!(result, Err(String::from("Aborted after step 2/3 failed")));
+        assert_eq!(*ran.lock().unwrap(), vec![0, 1]);
+
+        let (continue_steps, ran) = steps(&[false, true, false]);
+        let sequence = Sequence {
+            on_error: Some(OnError::Continue),
+            delay_ms: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(run(&sequence, &continue_steps).await, Err(String::from("1 of 3 steps failed")));
+        assert_eq!(*ran.lock().unwrap(), vec![0, 1, 2]);
+
+        let (parallel_steps, ran) = steps(&[false, false]);
+        let sequence = Sequence {
+            parallel: true,
+            ..Default::default()
+        };
+        assert_eq!(run(&sequence, &parallel_steps).await, Ok(()));
+        assert_eq!(ran.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn deserialize_action_list() {
+        use crate::controller::rules::RuleVariant;
+        let rule = r#"{
+            "event": "MESSAGE_CREATE",
+            "filters": {"content": "^!vote"},
+            "action": [
+                {"type": "React", "options": {"emojis": ["✅"]}},
+                {"type": "AddRole", "options": {"role_name": "voted"}}
+            ],
+            "delay_ms": 500,
+            "on_error": "continue"
+        }"#;
+        match serde_json::from_str::<RuleVariant>(rule).unwrap() {
+            RuleVariant::MESSAGE_CREATE(rule) => {
+                assert_eq!(rule.action.steps().len(), 2);
+                assert_eq!(rule.sequence.delay_ms, Some(500));
+                assert_eq!(rule.sequence.on_error, Some(OnError::Continue));
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        let invalid = r#"{"event": "MESSAGE_CREATE", "filters": {}, "action": [], "parallel": true, "delay_ms": 5}"#;
+        let rule = serde_json::from_str::<RuleVariant>(invalid).unwrap();
+        assert_eq!(rule.validate().unwrap_err().0, "action");
+    }
+
+    #[test]
+    fn validate_sequence() {
+        let steps = [()];
+        assert_eq!(Sequence::default().validate::<()>(&[]).unwrap_err().0, "action");
+        assert!(Sequence::default().validate(&steps).is_ok());
+        let parallel = Sequence {
+            parallel: true,
+            ..Default::default()
+        };
+        assert!(parallel.validate(&steps).is_ok());
+        let sequence = Sequence {
+            delay_ms: Some(5),
+            ..parallel.clone()
+        };
+        assert_eq!(sequence.validate(&steps).unwrap_err().0, "delay_ms");
+        let sequence = Sequence {
+            on_error: Some(OnError::Abort),
+            ..parallel
+        };
+        assert_eq!(sequence.validate(&steps).unwrap_err().0, "on_error");
+    }
+
+    #[tokio::test]
+    async fn single_step_is_reported() {
+        let (steps, ran) = steps(&[true]);
+        assert_eq!(run(&Sequence::default(), &steps).await, Err(String::from("1 of 1 steps failed")));
+        assert_eq!(*ran.lock().unwrap(), vec![0]);
+    }
+}
@@ -0,0 +1,464 @@
+/// Fills `{{path.to.field}}` placeholders in action options from the event
+/// that triggered the rule. Each action lists its templated options in
+/// `Render::templates`. That is every string option except webhook headers,
+/// Echo's base64 file contents, `allowed_mentions` and `allowed_roles`.
+///
+/// Besides plain placeholders, templates support filters and conditionals:
+///
+///   {{ author.username | upper }}
+///   {{ member.nick | default:"no nickname" }}
+///   {{#if attachments}}with attachments{{else}}text only{{/if}}
+///
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Map, Value};
+
+use crate::discord;
+use crate::DiscordContext;
+use crate::gateway::GatewayMessageType;
+
+/// The event payload as JSON, without the variant name around it. This is
+/// what placeholder paths are looked up in.
+pub fn event_value(payload: &GatewayMessageType) -> Value {
+    match serde_json::to_value(payload) {
+        Ok(Value::Object(map)) if map.len() == 1 => map.into_iter().next().unwrap().1,
+        Ok(value) => value,
+        Err(_) => Value::Null
+    }
+}
+
+/// The event payload plus what we know from the caches: `guild`, `channel`
+/// and `user`. `author` and `user` also get a `mention`. `values` come from
+/// the rule's filters and are added last.
+pub fn scope(context: &DiscordContext, payload: &GatewayMessageType, values: Map<String, Value>) -> Value {
+    let mut scope = match event_value(payload) {
+        Value::Object(map) => map,
+        _ => Map::new()
+    };
+    let guild_id = payload.get_guild_id();
+    if let Some(guild) = guild_id.as_ref().and_then(|guild_id| context.get_guild(guild_id)) {
+        scope.entry("guild").or_insert_with(|| json!({ "id": guild.id, "name": guild.name }));
+    }
+    if let (Some(guild_id), Some(channel_id)) = (guild_id.as_ref(), payload.get_channel_id()) {
+        if let Some(channel) = context.get_channel(guild_id, &channel_id) {
+            scope.entry("channel").or_insert_with(|| json!({
+                "id": channel.id,
+                "name": channel.name,
+                "mention": format!("<#{}>", channel.id)
+            }));
+        }
+    }
+    if !scope.contains_key("user") {
+        if let Some(user) = event_user(context, payload) {
+            scope.insert(String::from("user"), serde_json::to_value(user).unwrap_or_default());
+        }
+    }
+    for key in ["author", "user"].iter() {
+        if let Some(Value::Object(user)) = scope.get_mut(*key) {
+            if let Some(id) = user.get("id").and_then(Value::as_str) {
+                let mention = format!("<@{}>", id);
+                user.insert(String::from("mention"), Value::String(mention));
+            }
+        }
+    }
+    scope.extend(values);
+    Value::Object(scope)
+}
+
+fn event_user(context: &DiscordContext, payload: &GatewayMessageType) -> Option<discord::User> {
+    let user_id = payload.get_user_id()?;
+    let user = match payload {
+        GatewayMessageType::MessageCreate(msg) => Some(msg.author.clone()),
+        GatewayMessageType::MessageReactionAdd(react) => react.member.as_ref().and_then(|member| member.user.clone()),
+        _ => None
+    };
+    user.or_else(|| {
+        let guild_id = payload.get_guild_id()?;
+        context.get_member(&guild_id, &user_id)?.user.clone()
+    })
+}
+
+/// Looks up `message.author.username` style paths. Missing fields are None.
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| match value {
+        Value::Object(map) => map.get(key),
+        Value::Array(values) => key.parse::<usize>().ok().and_then(|index| values.get(index)),
+        _ => None
+    })
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        Value::Null => String::new(),
+        value => value.to_string()
+    }
+}
+
+/// null, false, 0, "" and empty lists and objects are false
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(map) => !map.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplateFilter {
+    Upper,
+    Lower,
+    Trim,
+    Length,
+    Default(String),
+    Truncate(usize)
+}
+impl TemplateFilter {
+    fn parse(filter: &str) -> Result<Self, String> {
+        let (name, arg) = match filter.find(':') {
+            Some(index) => (filter[..index].trim(), Some(filter[index + 1..].trim().trim_matches('"'))),
+            None => (filter.trim(), None)
+        };
+        match (name, arg) {
+            ("upper", None) => Ok(TemplateFilter::Upper),
+            ("lower", None) => Ok(TemplateFilter::Lower),
+            ("trim", None) => Ok(TemplateFilter::Trim),
+            ("length", None) => Ok(TemplateFilter::Length),
+            ("default", Some(arg)) => Ok(TemplateFilter::Default(String::from(arg))),
+            ("truncate", Some(arg)) => arg.parse::<usize>()
+                .map(TemplateFilter::Truncate)
+                .map_err(|_| format!("truncate needs a number, got '{}'", arg)),
+            _ => Err(format!("Unknown template filter '{}'", filter.trim()))
+        }
+    }
+    fn apply(&self, value: Value) -> Value {
+        match self {
+            TemplateFilter::Upper => Value::String(to_text(&value).to_uppercase()),
+            TemplateFilter::Lower => Value::String(to_text(&value).to_lowercase()),
+            TemplateFilter::Trim => Value::String(to_text(&value).trim().to_string()),
+            TemplateFilter::Length => match &value {
+                Value::Array(values) => json!(values.len()),
+                Value::Object(map) => json!(map.len()),
+                value => json!(to_text(value).chars().count())
+            },
+            TemplateFilter::Default(default) => {
+                if is_truthy(&value) { value } else { Value::String(default.clone()) }
+            },
+            TemplateFilter::Truncate(length) => Value::String(to_text(&value).chars().take(*length).collect())
+        }
+    }
+}
+
+/// `path | filter | filter:arg`
+#[derive(Debug, Clone, PartialEq)]
+struct Expression {
+    path: String,
+    filters: Vec<TemplateFilter>
+}
+impl Expression {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let mut parts = expression.split('|');
+        let path = parts.next().unwrap_or_default().trim();
+        if path.is_empty() || !path.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.') {
+            return Err(format!("Invalid placeholder '{}'", expression.trim()))
+        }
+        Ok(Expression {
+            path: String::from(path),
+            filters: parts.map(TemplateFilter::parse).collect::<Result<_, _>>()?
+        })
+    }
+    fn evaluate(&self, scope: &Value) -> Value {
+        let value = lookup(scope, &self.path).cloned().unwrap_or(Value::Null);
+        self.filters.iter().fold(value, |value, filter| filter.apply(value))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Placeholder(Expression),
+    If(Expression, Vec<Node>, Vec<Node>)
+}
+
+/// A parsed template
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>
+}
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        // Each open {{#if}}: its condition, the nodes before it, and the if
+        // branch once we are past its {{else}}
+        let mut stack: Vec<(Expression, Vec<Node>, Option<Vec<Node>>)> = vec![];
+        let mut nodes: Vec<Node> = vec![];
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                nodes.push(Node::Text(String::from(&rest[..start])));
+            }
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => return Err(String::from("Unclosed '{{'"))
+            };
+            let tag = rest[start + 2..end].trim();
+            rest = &rest[end + 2..];
+            if let Some(condition) = tag.strip_prefix("#if ") {
+                let condition = Expression::parse(condition)?;
+                stack.push((condition, std::mem::take(&mut nodes), None));
+            } else if tag == "else" {
+                match stack.last_mut() {
+                    Some((_, _, if_branch @ None)) => {
+                        *if_branch = Some(std::mem::take(&mut nodes));
+                    },
+                    _ => return Err(String::from("{{else}} without {{#if}}"))
+                }
+            } else if tag == "/if" {
+                let (condition, parent, if_branch) = match stack.pop() {
+                    Some(open) => open,
+                    None => return Err(String::from("{{/if}} without {{#if}}"))
+                };
+                let branch = std::mem::replace(&mut nodes, parent);
+                nodes.push(match if_branch {
+                    Some(if_branch) => Node::If(condition, if_branch, branch),
+                    None => Node::If(condition, branch, vec![])
+                });
+            } else {
+                nodes.push(Node::Placeholder(Expression::parse(tag)?));
+            }
+        }
+        if !stack.is_empty() {
+            return Err(String::from("{{#if}} without {{/if}}"))
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(String::from(rest)));
+        }
+        Ok(Template { nodes })
+    }
+
+    pub fn render(&self, scope: &Value) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, scope, &mut out);
+        out
+    }
+}
+
+fn render_nodes(nodes: &[Node], scope: &Value, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Placeholder(expression) => out.push_str(&to_text(&expression.evaluate(scope))),
+            Node::If(condition, if_branch, else_branch) => {
+                if is_truthy(&condition.evaluate(scope)) {
+                    render_nodes(if_branch, scope, out);
+                } else {
+                    render_nodes(else_branch, scope, out);
+                }
+            }
+        }
+    }
+}
+
+/// Renders the template against `scope`. Placeholders for missing fields
+/// become empty.
+pub fn render(template: &str, scope: &Value) -> Result<String, String> {
+    Template::parse(template).map(|parsed| parsed.render(scope))
+}
+
+/// A string option that takes `{{placeholders}}`, parsed once when the
+/// config is loaded. Invalid templates are kept so `Controller::new` can say
+/// where they are; rendering them is an error.
+#[derive(Clone, Debug)]
+pub struct TemplateString {
+    source: String,
+    template: Result<Template, String>
+}
+impl TemplateString {
+    /// Text that is used as is, e.g. an already rendered template
+    pub fn literal(text: String) -> Self {
+        TemplateString {
+            template: Ok(Template { nodes: vec![Node::Text(text.clone())] }),
+            source: text
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        self.source.as_str()
+    }
+    pub fn error(&self) -> Option<&String> {
+        self.template.as_ref().err()
+    }
+    /// No placeholders or conditionals, so it renders to itself
+    pub fn is_literal(&self) -> bool {
+        match &self.template {
+            Ok(template) => template.nodes.iter().all(|node| matches!(node, Node::Text(_))),
+            Err(_) => false
+        }
+    }
+    pub fn render(&self, scope: &Value) -> Result<TemplateString, String> {
+        match &self.template {
+            Ok(template) => Ok(TemplateString::literal(template.render(scope))),
+            Err(e) => Err(format!("Could not parse template '{}': {}", self.source, e))
+        }
+    }
+}
+impl From<&str> for TemplateString {
+    fn from(source: &str) -> Self {
+        TemplateString {
+            source: String::from(source),
+            template: Template::parse(source)
+        }
+    }
+}
+impl Serialize for TemplateString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+impl<'de> Deserialize<'de> for TemplateString {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        String::deserialize(deserializer).map(|source| TemplateString::from(source.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::discord;
+
+    #[test]
+    fn render_deleted_message() {
+        let payload = GatewayMessageType::MessageDelete(discord::MessageDelete {
+            id: String::from("1"),
+            channel_id: String::from("2"),
+            guild_id: None,
+            message: Some(discord::Message {
+                content: String::from("oops"),
+                author: discord::User {
+                    username: String::from("lomz"),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        });
+        let value = event_value(&payload);
+        assert_eq!(
+            render("{{message.author.username}} deleted: {{ message.content }} in {{channel_id}}{{missing.field}}", &value).unwrap(),
+            "lomz deleted: oops in 2"
+        );
+    }
+
+    #[test]
+    fn render_filters_and_conditionals() {
+        let scope = json!({
+            "author": { "username": "lomz", "mention": "<@1>" },
+            "content": "  hello there  ",
+            "attachments": [],
+            "member": { "nick": null }
+        });
+        assert_eq!(render("{{ author.username | upper }}: {{content | trim | truncate:5}}", &scope).unwrap(), "LOMZ: hello");
+        assert_eq!(render("{{member.nick | default:\"no nick\"}}", &scope).unwrap(), "no nick");
+        assert_eq!(
+            render("{{#if attachments}}files{{else}}{{#if content}}text from {{author.mention}}{{/if}}{{/if}}", &scope).unwrap(),
+            "text from <@1>"
+        );
+        assert!(Template::parse("{{#if content}}unclosed").is_err());
+        assert!(Template::parse("{{content | shout}}").is_err());
+        assert!(render("{{content | shout}}", &scope).is_err());
+    }
+
+    #[test]
+    fn scope_adds_channel_and_mentions() {
+        let context = DiscordContext::empty();
+        context.insert_guild(discord::Guild {
+            id: String::from("1"),
+            name: String::from("glenn"),
+            channels: Some(vec![discord::Channel {
+                id: String::from("2"),
+                name: Some(String::from("general")),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        let payload = GatewayMessageType::MessageCreate(discord::Message {
+            guild_id: Some(String::from("1")),
+            channel_id: String::from("2"),
+            author: discord::User {
+                id: String::from("3"),
+                username: String::from("lomz"),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let scope = scope(&context, &payload, Map::new());
+        assert_eq!(
+            render("{{author.mention}} in #{{channel.name}} ({{guild.name}})", &scope).unwrap(),
+            "<@3> in #general (glenn)"
+        );
+    }
+
+    #[test]
+    fn string_options_are_rendered() {
+        use crate::controller::actions::{ActionType, Render};
+        let scope = json!({ "captures": { "role": "artist" }, "content": "general" });
+        let action: ActionType = serde_json::from_str(
+            r#"{"type":"AddRole","options":{"role_name":"{{captures.role}}","role_id":null,"allowed_roles":["{{captures.role}}"]}}"#
+        ).unwrap();
+        match action.render(&scope).unwrap() {
+            ActionType::AddRole(options) => {
+                assert_eq!(options.role_name.unwrap().as_str(), "artist");
+                assert_eq!(options.allowed_roles.unwrap(), vec!["{{captures.role}}"]);
+            },
+            _ => panic!("Rendered incorrectly")
+        }
+
+        let action: ActionType = serde_json::from_str(
+            r#"{"type":"Echo","options":{"content":"{{content}}","file":{"contents":"aGk=","filename":"{{content}}.txt"},"channel_name":"{{content}}"}}"#
+        ).unwrap();
+        match action.render(&scope).unwrap() {
+            ActionType::Echo(options) => {
+                assert_eq!(options.content.unwrap().as_str(), "general");
+                assert_eq!(options.channel_name.unwrap().as_str(), "general");
+                assert_eq!(options.file.unwrap().filename.as_str(), "general.txt");
+            },
+            _ => panic!("Rendered incorrectly")
+        }
+
+        let action: ActionType = serde_json::from_str(
+            r#"{"type":"SetStatus","options":{"activity":{"type":"custom","name":"{{content}}","state":"in #{{content}}"}}}"#
+        ).unwrap();
+        match action.render(&scope).unwrap() {
+            ActionType::SetStatus(options) => {
+                let activity = options.activity.unwrap();
+                assert_eq!(activity.name.as_str(), "general");
+                assert_eq!(activity.state.unwrap().as_str(), "in #general");
+            },
+            _ => panic!("Rendered incorrectly")
+        }
+
+        let action: ActionType = serde_json::from_str(
+            r#"{"type":"Webhook","options":{"url":"http://localhost/{{content}}","headers":{"authorization":"Bearer {{content}}"}}}"#
+        ).unwrap();
+        assert_eq!(action.templates().len(), 1);
+        match action.render(&scope).unwrap() {
+            ActionType::Webhook(options) => {
+                assert_eq!(options.url.as_str(), "http://localhost/general");
+                assert_eq!(options.headers["authorization"], "Bearer {{content}}");
+            },
+            _ => panic!("Rendered incorrectly")
+        }
+    }
+
+    #[test]
+    fn invalid_templates_do_not_render() {
+        let template = TemplateString::from("{{#if content}}unclosed");
+        assert!(template.error().is_some());
+        assert!(template.render(&json!({ "content": "hi" })).is_err());
+
+        let template = TemplateString::from("hi {{author.username}}");
+        assert!(!template.is_literal());
+        assert_eq!(template.render(&json!({ "author": { "username": "lomz" } })).unwrap().as_str(), "hi lomz");
+        assert!(TemplateString::from("hi").is_literal());
+        assert_eq!(serde_json::to_string(&template).unwrap(), r#""hi {{author.username}}""#);
+    }
+}
@@ -1,11 +1,86 @@
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
+use log::warn;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Overrides the Discord API version, e.g. to try a newer one early
+pub const API_VERSION_ENV: &str = "DISCORD_API_VERSION";
+/// The models in this module follow this version's field names
+const DEFAULT_API_VERSION: u8 = 10;
+/// Discord has shut down the versions before this one
+const MIN_API_VERSION: u8 = 8;
+/// 0 until the first `api_version` call reads the env
+static API_VERSION: AtomicU8 = AtomicU8::new(0);
+
+/// Discord API version used by both the REST client and the gateway.
+/// `API_VERSION_ENV` if set, `DEFAULT_API_VERSION` otherwise.
+pub fn api_version() -> u8 {
+    match API_VERSION.load(Ordering::Relaxed) {
+        0 => {
+            let version = parse_api_version(std::env::var(API_VERSION_ENV).ok());
+            API_VERSION.store(version, Ordering::Relaxed);
+            version
+        },
+        version => version
+    }
+}
+
+fn parse_api_version(value: Option<String>) -> u8 {
+    match value.as_deref().map(|v| v.trim().trim_start_matches('v').parse::<u8>()) {
+        None => DEFAULT_API_VERSION,
+        Some(Ok(version)) if version >= MIN_API_VERSION => version,
+        Some(_) => {
+            warn!("Ignoring invalid {} {:?}, using v{}", API_VERSION_ENV, value.unwrap(), DEFAULT_API_VERSION);
+            DEFAULT_API_VERSION
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IntegerOrString<T> {
+    Integer(T),
+    String(String)
+}
+
+/// Over ETF, integers that don't fit in 32 bits arrive as strings (see
+/// `gateway::etf`). Accepts either for fields that can get that big.
+pub fn integer<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where D: serde::Deserializer<'de>, T: Deserialize<'de> + std::str::FromStr
+{
+    match IntegerOrString::<T>::deserialize(deserializer)? {
+        IntegerOrString::Integer(integer) => Ok(integer),
+        IntegerOrString::String(string) => string.parse()
+            .map_err(|_| serde::de::Error::custom(format!("expected an integer, got {:?}", string)))
+    }
+}
+
+/// `integer` for optional fields
+pub fn optional_integer<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where D: serde::Deserializer<'de>, T: Deserialize<'de> + std::str::FromStr
+{
+    match Option::<IntegerOrString<T>>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(IntegerOrString::Integer(integer)) => Ok(Some(integer)),
+        Some(IntegerOrString::String(string)) => string.parse().map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("expected an integer, got {:?}", string)))
+    }
+}
+
+/// The first second of 2015, in milliseconds since the unix epoch
+const DISCORD_EPOCH: u64 = 1420070400000;
+
+/// When a snowflake (user, message, ...) was created, in milliseconds since
+/// the unix epoch
+pub fn snowflake_timestamp(id: &str) -> Option<u64> {
+    id.parse::<u64>().ok().map(|id| (id >> 22) + DISCORD_EPOCH)
+}
 
 #[derive(Deserialize, Default)]
 pub struct Me {
   pub id: String,
   pub username: String,
-  pub avatar: String,
+  pub avatar: Option<String>,
   pub discriminator: String,
   pub public_flags: i32,
   pub flags: i32,
@@ -18,13 +93,15 @@ pub struct Me {
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct Emoji {
-  pub roles: Vec<()>,
-  pub require_colors: Option<bool>,
-  pub name: String,
-  pub managed: bool,
-  pub id: String,
-  pub available: bool,
-  pub animated: bool
+  pub id: Option<String>,
+  /// Only null for deleted emojis in reactions
+  pub name: Option<String>,
+  #[serde(default)]
+  pub roles: Vec<String>,
+  pub require_colons: Option<bool>,
+  pub managed: Option<bool>,
+  pub available: Option<bool>,
+  pub animated: Option<bool>
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
@@ -33,36 +110,332 @@ pub struct Guild {
     pub name: String,
     pub icon: Option<String>,
     pub owner: Option<bool>,
-    pub permissions: Option<i32>,
-    pub features: Vec<()>,
-    pub permissions_new: Option<String>,
+    /// Sent with GUILD_CREATE
+    pub owner_id: Option<String>,
+    /// Permissions of the current user, as a stringified bitfield. Only sent
+    /// from `/users/@me/guilds`.
+    pub permissions: Option<String>,
+    pub features: Vec<String>,
     pub channels: Option<Vec<Channel>>,
     pub emojis: Option<Vec<Emoji>>,
-    pub roles: Option<Vec<Role>>
+    pub roles: Option<Vec<Role>>,
+    /// Sent with GUILD_CREATE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member_count: Option<u64>
+}
+
+impl Guild {
+    /// Guild-wide permissions of a member with these roles, as a bitfield.
+    /// Channel permission overwrites are not applied.
+    pub fn member_permissions(&self, user_id: &String, member_roles: &[String]) -> u64 {
+        if self.owner_id.as_ref() == Some(user_id) {
+            return u64::MAX
+        }
+        // The @everyone role has the guild's ID
+        let permissions = self.roles.iter().flatten()
+            .filter(|role| role.id == self.id || member_roles.contains(&role.id))
+            .fold(0, |permissions, role| permissions | role.permissions.parse::<u64>().unwrap_or(0));
+        if permissions & Permission::ADMINISTRATOR.bit() != 0 {
+            u64::MAX
+        } else {
+            permissions
+        }
+    }
+}
+
+/// https://discord.com/developers/docs/topics/permissions#permissions-bitwise-permission-flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum Permission {
+    CREATE_INSTANT_INVITE,
+    KICK_MEMBERS,
+    BAN_MEMBERS,
+    ADMINISTRATOR,
+    MANAGE_CHANNELS,
+    MANAGE_GUILD,
+    ADD_REACTIONS,
+    VIEW_AUDIT_LOG,
+    PRIORITY_SPEAKER,
+    STREAM,
+    VIEW_CHANNEL,
+    SEND_MESSAGES,
+    SEND_TTS_MESSAGES,
+    MANAGE_MESSAGES,
+    EMBED_LINKS,
+    ATTACH_FILES,
+    READ_MESSAGE_HISTORY,
+    MENTION_EVERYONE,
+    USE_EXTERNAL_EMOJIS,
+    VIEW_GUILD_INSIGHTS,
+    CONNECT,
+    SPEAK,
+    MUTE_MEMBERS,
+    DEAFEN_MEMBERS,
+    MOVE_MEMBERS,
+    USE_VAD,
+    CHANGE_NICKNAME,
+    MANAGE_NICKNAMES,
+    MANAGE_ROLES,
+    MANAGE_WEBHOOKS,
+    MANAGE_EMOJIS_AND_STICKERS,
+    USE_APPLICATION_COMMANDS,
+    REQUEST_TO_SPEAK,
+    MANAGE_EVENTS,
+    MANAGE_THREADS,
+    CREATE_PUBLIC_THREADS,
+    CREATE_PRIVATE_THREADS,
+    USE_EXTERNAL_STICKERS,
+    SEND_MESSAGES_IN_THREADS,
+    USE_EMBEDDED_ACTIVITIES,
+    MODERATE_MEMBERS
+}
+
+impl Permission {
+    pub fn bit(&self) -> u64 {
+        match self {
+            Permission::CREATE_INSTANT_INVITE => 1 << 0,
+            Permission::KICK_MEMBERS => 1 << 1,
+            Permission::BAN_MEMBERS => 1 << 2,
+            Permission::ADMINISTRATOR => 1 << 3,
+            Permission::MANAGE_CHANNELS => 1 << 4,
+            Permission::MANAGE_GUILD => 1 << 5,
+            Permission::ADD_REACTIONS => 1 << 6,
+            Permission::VIEW_AUDIT_LOG => 1 << 7,
+            Permission::PRIORITY_SPEAKER => 1 << 8,
+            Permission::STREAM => 1 << 9,
+            Permission::VIEW_CHANNEL => 1 << 10,
+            Permission::SEND_MESSAGES => 1 << 11,
+            Permission::SEND_TTS_MESSAGES => 1 << 12,
+            Permission::MANAGE_MESSAGES => 1 << 13,
+            Permission::EMBED_LINKS => 1 << 14,
+            Permission::ATTACH_FILES => 1 << 15,
+            Permission::READ_MESSAGE_HISTORY => 1 << 16,
+            Permission::MENTION_EVERYONE => 1 << 17,
+            Permission::USE_EXTERNAL_EMOJIS => 1 << 18,
+            Permission::VIEW_GUILD_INSIGHTS => 1 << 19,
+            Permission::CONNECT => 1 << 20,
+            Permission::SPEAK => 1 << 21,
+            Permission::MUTE_MEMBERS => 1 << 22,
+            Permission::DEAFEN_MEMBERS => 1 << 23,
+            Permission::MOVE_MEMBERS => 1 << 24,
+            Permission::USE_VAD => 1 << 25,
+            Permission::CHANGE_NICKNAME => 1 << 26,
+            Permission::MANAGE_NICKNAMES => 1 << 27,
+            Permission::MANAGE_ROLES => 1 << 28,
+            Permission::MANAGE_WEBHOOKS => 1 << 29,
+            Permission::MANAGE_EMOJIS_AND_STICKERS => 1 << 30,
+            Permission::USE_APPLICATION_COMMANDS => 1 << 31,
+            Permission::REQUEST_TO_SPEAK => 1 << 32,
+            Permission::MANAGE_EVENTS => 1 << 33,
+            Permission::MANAGE_THREADS => 1 << 34,
+            Permission::CREATE_PUBLIC_THREADS => 1 << 35,
+            Permission::CREATE_PRIVATE_THREADS => 1 << 36,
+            Permission::USE_EXTERNAL_STICKERS => 1 << 37,
+            Permission::SEND_MESSAGES_IN_THREADS => 1 << 38,
+            Permission::USE_EMBEDDED_ACTIVITIES => 1 << 39,
+            Permission::MODERATE_MEMBERS => 1 << 40,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct Role {
-    pub position: i32,
-    pub permissions_new: Option<String>,
+    pub id: String,
     pub name: String,
-    pub id: String
+    pub color: u32,
+    pub hoist: bool,
+    pub position: i32,
+    /// Stringified permission bitfield
+    pub permissions: String,
+    pub managed: bool,
+    pub mentionable: bool
+}
+
+/// https://discord.com/developers/docs/topics/gateway#get-gateway-bot
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GatewayBot {
+    /// The WSS URL that can be used for connecting to the gateway
+    pub url: String,
+    /// The recommended number of shards to use when connecting
+    pub shards: u32,
+    /// Information on the current session start limit
+    pub session_start_limit: SessionStartLimit
+}
 
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SessionStartLimit {
+    /// The total number of session starts the current user is allowed
+    pub total: u32,
+    /// The remaining number of session starts the current user is allowed
+    pub remaining: u32,
+    /// The number of milliseconds after which the limit resets
+    pub reset_after: u64,
+    /// The number of identify requests allowed per 5 seconds
+    pub max_concurrency: Option<u32>
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct UnavailableGuild {
     pub id: String,
+    /// Not set in GUILD_DELETE when we were removed from the guild
+    #[serde(default)]
     pub unavailable: bool
 }
 
+/// GUILD_ROLE_CREATE and GUILD_ROLE_UPDATE
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildRole {
+    pub guild_id: String,
+    pub role: Role
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildRoleDelete {
+    pub guild_id: String,
+    pub role_id: String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildEmojisUpdate {
+    pub guild_id: String,
+    pub emojis: Vec<Emoji>
+}
+
+/// GUILD_BAN_ADD and GUILD_BAN_REMOVE
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildBan {
+    pub guild_id: String,
+    pub user: User
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildIntegrationsUpdate {
+    pub guild_id: String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct WebhooksUpdate {
+    pub guild_id: String,
+    pub channel_id: String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildMemberAdd {
+    pub guild_id: String,
+    #[serde(flatten)]
+    pub member: Member
+}
+
+/// Only the fields that can change are sent
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildMemberUpdate {
+    pub guild_id: String,
+    pub roles: Vec<String>,
+    /// Roles from the member cache before this update, if we knew the member
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub previous_roles: Option<Vec<String>>,
+    pub user: User,
+    pub nick: Option<String>,
+    pub joined_at: Option<String>,
+    pub premium_since: Option<String>,
+    pub pending: Option<bool>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildMemberRemove {
+    pub guild_id: String,
+    pub user: User,
+    /// Roles from the member cache before the member left, if we knew them
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct ChannelPinsUpdate {
+    pub guild_id: Option<String>,
+    pub channel_id: String,
+    pub last_pin_timestamp: Option<String>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct InviteCreate {
+    pub channel_id: String,
+    /// the unique invite code
+    pub code: String,
+    pub created_at: String,
+    pub guild_id: Option<String>,
+    /// the user that created the invite
+    pub inviter: Option<User>,
+    /// how long the invite is valid for (in seconds)
+    pub max_age: u32,
+    /// the maximum number of times the invite can be used
+    pub max_uses: u32,
+    /// whether or not the invite is temporary (invited users will be kicked on disconnect unless they're assigned a role)
+    pub temporary: bool,
+    /// how many times the invite has been used (always will be 0)
+    pub uses: u32
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct InviteDelete {
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub code: String
+}
+
+/// https://discord.com/developers/docs/resources/voice#voice-state-object
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct VoiceState {
+    pub guild_id: Option<String>,
+    /// None when the user left voice
+    pub channel_id: Option<String>,
+    pub user_id: String,
+    pub member: Option<Member>,
+    pub session_id: String,
+    pub deaf: bool,
+    pub mute: bool,
+    pub self_deaf: bool,
+    pub self_mute: bool,
+    pub self_stream: Option<bool>,
+    pub self_video: bool,
+    pub suppress: bool
+}
+
+/// Only the user's id is guaranteed to be sent
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct PartialUser {
+    pub id: String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct PresenceUpdate {
+    pub user: PartialUser,
+    pub guild_id: String,
+    /// idle, dnd, online or offline
+    pub status: String
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct TypingStart {
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub user_id: String,
+    /// unix time (in seconds) of when the user started typing
+    #[serde(deserialize_with = "integer")]
+    pub timestamp: u64,
+    pub member: Option<Member>
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct User {
     pub id: String,
     pub username: String,
     pub discriminator: String,
     pub avatar: Option<String>,
-    //pub bot: bool,
+    pub bot: Option<bool>,
+    /// Display name, if the user set one
+    pub global_name: Option<String>,
+    pub public_flags: Option<u32>,
     //pub mfa_enabled: bool,
     //pub locale: String,
     //pub verified: bool,
@@ -74,13 +447,36 @@ pub struct User {
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
 pub struct Member {
-    pub user: User,
+    /// Not included in the member attached to MESSAGE_CREATE
+    pub user: Option<User>,
     pub nick: Option<String>,
     pub roles: Vec<String>,
-    pub joined_at: String,
+    /// Discord sends null for some members
+    pub joined_at: Option<String>,
+    pub premium_since: Option<String>,
     pub mute: bool,
-    pub hoisted_role: Option<String>,
-    pub deaf: bool
+    pub deaf: bool,
+    /// Whether the user has not yet passed membership screening
+    pub pending: Option<bool>,
+    /// Permissions of the member in the channel, on interactions only
+    pub permissions: Option<String>
+}
+
+/// Sent in response to Request Guild Members. Large requests are split
+/// across several chunks.
+/// https://discord.com/developers/docs/topics/gateway#guild-members-chunk
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct GuildMembersChunk {
+    pub guild_id: String,
+    pub members: Vec<Member>,
+    /// the chunk index in the expected chunks for this response (0 <= chunk_index < chunk_count)
+    pub chunk_index: u32,
+    /// the total number of expected chunks for this response
+    pub chunk_count: u32,
+    /// ids passed in `user_ids` that were not found
+    pub not_found: Option<Vec<String>>,
+    /// the nonce used in the Guild Members Request
+    pub nonce: Option<String>
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
@@ -95,6 +491,8 @@ pub struct Ready {
     pub guilds: Vec<UnavailableGuild>,
     /// used for resuming connections
     pub session_id: String,
+    /// gateway URL to use when resuming
+    pub resume_gateway_url: Option<String>,
     /// (shard_id, num_shards)
     /// shard information associated with this session.
     pub shard: Option<(u32, u32)>
@@ -107,6 +505,7 @@ pub struct Attachment {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub url: String,
+    #[serde(deserialize_with = "integer")]
     pub size: u32,
     pub proxy_url: String,
     pub id: String,
@@ -117,20 +516,93 @@ pub struct Attachment {
 pub struct Message {
     pub id: String,
     pub channel_id: String,
-    pub guild_id: String,
+    /// Not set for DMs, or on messages fetched over REST
+    pub guild_id: Option<String>,
     pub author: User,
-    //member: GuildMember,
+    /// Member properties of the author, on gateway events in guilds
+    pub member: Option<Member>,
+    /// Empty unless the MESSAGE_CONTENT intent is enabled
     pub content: String,
     pub timestamp: String,
     pub edited_timestamp: Option<String>,
     pub tts: bool,
     pub mention_everyone: bool,
     pub mentions: Vec<User>,
-    //mention_roles: Vec<Role>
+    /// ids of the roles mentioned
+    #[serde(default)]
+    pub mention_roles: Vec<String>,
     //mention_channels: Vec<ChannelMention>
     pub attachments: Vec<Attachment>,
     //embeds: Vec<Embed>
-    pub reactions: Option<Vec<Reaction>>
+    pub reactions: Option<Vec<Reaction>>,
+    pub pinned: Option<bool>,
+    /// Set when the message was sent by a webhook
+    pub webhook_id: Option<String>,
+    /// Set on replies, crossposts and pins
+    pub message_reference: Option<MessageReference>,
+}
+
+/// https://discord.com/developers/docs/resources/channel#message-reference-object-message-reference-structure
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MessageReference {
+    pub message_id: Option<String>,
+    pub channel_id: Option<String>,
+    pub guild_id: Option<String>
+}
+
+/// Edits only carry the fields that changed, so everything but the ids is
+/// optional.
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MessageUpdate {
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub author: Option<User>,
+    pub member: Option<Member>,
+    pub content: Option<String>,
+    pub edited_timestamp: Option<String>,
+    pub mentions: Option<Vec<User>>,
+    pub mention_roles: Option<Vec<String>>,
+    pub attachments: Option<Vec<Attachment>>,
+    pub pinned: Option<bool>,
+    /// The message before the edit, if it was in the message cache
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Message>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MessageDelete {
+    pub id: String,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    /// The deleted message, if it was in the message cache
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct MessageDeleteBulk {
+    pub ids: Vec<String>,
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    /// The deleted messages that were in the message cache
+    #[serde(skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<Message>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct RemoveAllReactions {
+    pub channel_id: String,
+    pub message_id: String,
+    pub guild_id: Option<String>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct RemoveEmojiReactions {
+    pub channel_id: String,
+    pub guild_id: Option<String>,
+    pub message_id: String,
+    pub emoji: ReactionEmoji
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
@@ -140,8 +612,10 @@ pub struct Reaction {
     /// We populate this when we throw this up to the webhook
     pub message: Option<Message>,
     pub channel_id: String,
-    pub guild_id: String,
-    pub member: Member,
+    /// Not set for reactions in DMs
+    pub guild_id: Option<String>,
+    /// Not set for reactions in DMs
+    pub member: Option<Member>,
     pub emoji: ReactionEmoji
 }
 
@@ -150,7 +624,7 @@ pub struct RemoveReaction {
     pub user_id: String,
     pub message_id: String,
     pub channel_id: String,
-    pub guild_id: String,
+    pub guild_id: Option<String>,
     pub emoji: ReactionEmoji
 }
 
@@ -162,11 +636,43 @@ pub struct ReactionEmoji {
 }
 
 
+/// Which mentions in a message notify anyone. Message content often comes
+/// from users, so by default only mentioned users are pinged: never
+/// `@everyone`, `@here` or a role. `{"parse": []}` pings nobody, e.g. for a
+/// mod log that quotes deleted messages.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct AllowedMentions {
+    /// `users`, `roles` and/or `everyone`
+    pub parse: Vec<String>
+}
+impl Default for AllowedMentions {
+    fn default() -> Self {
+        AllowedMentions {
+            parse: vec![String::from("users")]
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct CreateDMPayload {
+    pub recipient_id: String
+}
+
 #[derive(Serialize, Default)]
 pub struct CreateMessagePayload {
     pub content: String,
     pub tts: bool,
     //embed:
+    pub allowed_mentions: AllowedMentions
+}
+impl CreateMessagePayload {
+    pub fn new(content: String, allowed_mentions: AllowedMentions) -> Self {
+        CreateMessagePayload {
+            content,
+            allowed_mentions,
+            ..Default::default()
+        }
+    }
 }
 
 /// https://discord.com/developers/docs/resources/channel#channel-object
@@ -207,11 +713,14 @@ pub struct Channel {
     pub last_pin_timestamp: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize_repr, Deserialize_repr)]
+/// Types this version doesn't know deserialize as `INVALID`, so a new kind
+/// of channel doesn't fail a whole GUILD_CREATE
+#[derive(Clone, Debug, Serialize_repr, Default)]
 #[repr(u8)]
 #[allow(non_camel_case_types)]
 pub enum ChannelType {
-    INVALID = 10,
+    #[default]
+    INVALID = 255,
     /// a text channel within a server
     GUILD_TEXT = 0,
     /// a direct message between users
@@ -226,10 +735,40 @@ pub enum ChannelType {
     GUILD_NEWS = 5,
     /// a channel in which game developers can sell their game on Discord
     GUILD_STORE = 6,
+    /// a temporary sub-channel within a GUILD_NEWS channel
+    GUILD_NEWS_THREAD = 10,
+    /// a temporary sub-channel within a GUILD_TEXT channel
+    GUILD_PUBLIC_THREAD = 11,
+    /// a temporary sub-channel within a GUILD_TEXT channel that is only viewable by those invited
+    GUILD_PRIVATE_THREAD = 12,
+    /// a voice channel for hosting events with an audience
+    GUILD_STAGE_VOICE = 13,
+    /// the channel in a hub containing the listed servers
+    GUILD_DIRECTORY = 14,
+    /// a channel that can only contain threads
+    GUILD_FORUM = 15,
+    /// like GUILD_FORUM, for media posts
+    GUILD_MEDIA = 16,
 }
-impl Default for ChannelType {
-    fn default() -> Self {
-        ChannelType::INVALID
+impl<'de> Deserialize<'de> for ChannelType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        Ok(match u64::deserialize(deserializer)? {
+            0 => ChannelType::GUILD_TEXT,
+            1 => ChannelType::DM,
+            2 => ChannelType::GUILD_VOICE,
+            3 => ChannelType::GROUP_DM,
+            4 => ChannelType::GUILD_CATEGORY,
+            5 => ChannelType::GUILD_NEWS,
+            6 => ChannelType::GUILD_STORE,
+            10 => ChannelType::GUILD_NEWS_THREAD,
+            11 => ChannelType::GUILD_PUBLIC_THREAD,
+            12 => ChannelType::GUILD_PRIVATE_THREAD,
+            13 => ChannelType::GUILD_STAGE_VOICE,
+            14 => ChannelType::GUILD_DIRECTORY,
+            15 => ChannelType::GUILD_FORUM,
+            16 => ChannelType::GUILD_MEDIA,
+            _ => ChannelType::INVALID
+        })
     }
 }
 
@@ -259,7 +798,7 @@ pub struct Embed {
     /// thumbnail information
     thumbnail: Option<()>,
     //thumbnail: Option<EmbedThumbnail>,
-    /// video object	video information
+    /// video information
     video: Option<()>,
     //video: Option<EmbedVideo>,
     /// provider information
@@ -273,3 +812,25 @@ pub struct Embed {
     //fields: Option<Vec<EmbedField>>,
 
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn api_version_from_env() {
+        assert_eq!(parse_api_version(None), 10);
+        assert_eq!(parse_api_version(Some(String::from("9"))), 9);
+        assert_eq!(parse_api_version(Some(String::from("v11"))), 11);
+        assert_eq!(parse_api_version(Some(String::from("0"))), 10);
+        assert_eq!(parse_api_version(Some(String::from("7"))), 10);
+        assert_eq!(parse_api_version(Some(String::from("8"))), 8);
+        assert_eq!(parse_api_version(Some(String::from("latest"))), 10);
+    }
+
+    #[test]
+    fn member_without_joined_at() {
+        let member: Member = serde_json::from_str(r#"{"roles":[],"joined_at":null,"mute":false,"deaf":false}"#).unwrap();
+        assert!(member.joined_at.is_none());
+    }
+}
@@ -0,0 +1,221 @@
+/// Runs rules off the gateway read loop. Events are handed to a fixed set of
+/// workers, each with a bounded queue, so a slow action only holds up the
+/// events behind it on the same worker.
+///
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::DiscordContext;
+use crate::reload::SharedController;
+use crate::gateway::GatewayMessage;
+
+/// Full queues are warned about at most this often, per worker
+const FULL_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+/// How often queue stats are logged, if any events came in since
+const STATS_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DispatchConfig {
+    /// How many events are handled at once
+    pub workers: usize,
+    /// Events each worker holds before the gateway has to wait
+    pub queue_size: usize
+}
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        DispatchConfig {
+            workers: 4,
+            queue_size: 64
+        }
+    }
+}
+
+/// Counters for one worker's queue
+#[derive(Default)]
+pub struct QueueStats {
+    /// Events waiting or being handled
+    pub pending: AtomicUsize,
+    pub handled: AtomicUsize,
+    /// Events that found the queue full and had to wait
+    pub delayed: AtomicUsize
+}
+
+struct Worker {
+    tx: Sender<GatewayMessage>,
+    stats: Arc<QueueStats>,
+    last_warning: Option<Instant>
+}
+
+pub struct Dispatcher {
+    workers: Vec<Worker>,
+    queue_size: usize
+}
+
+impl Dispatcher {
+    /// Spawns the workers. They stop once the dispatcher is dropped and
+    /// their queues are empty.
+    pub fn start(config: &DispatchConfig, context: Arc<DiscordContext>, controller: Arc<SharedController>) -> Self {
+        let queue_size = config.queue_size.max(1);
+        let workers = (0..config.workers.max(1)).map(|worker_id| {
+            let (tx, mut rx) = channel::<GatewayMessage>(queue_size);
+            let stats = Arc::new(QueueStats::default());
+            let worker_stats = stats.clone();
+            let context = context.clone();
+            let controller = controller.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = rx.recv().await {
+                    controller.current().handle_event(&context, msg).await;
+                    worker_stats.pending.fetch_sub(1, Ordering::Relaxed);
+                    worker_stats.handled.fetch_add(1, Ordering::Relaxed);
+                }
+                debug!("[worker {}] Stopped", worker_id);
+            });
+            Worker { tx, stats, last_warning: None }
+        }).collect::<Vec<_>>();
+        info!("Dispatching events to {} worker(s)", workers.len());
+        let dispatcher = Dispatcher { workers, queue_size };
+        log_stats(dispatcher.stats());
+        dispatcher
+    }
+
+    /// Queues the event behind the others from its channel. Waits if that
+    /// worker's queue is full.
+    pub async fn dispatch(&mut self, msg: GatewayMessage) {
+        let worker_id = worker_for(&msg, self.workers.len());
+        let queue_size = self.queue_size;
+        let worker = &mut self.workers[worker_id];
+        worker.stats.pending.fetch_add(1, Ordering::Relaxed);
+        let msg = match worker.tx.try_send(msg) {
+            Ok(()) => return,
+            Err(TrySendError::Full(msg)) => msg,
+            Err(TrySendError::Closed(_)) => {
+                error!("[worker {}] Stopped, dropping event", worker_id);
+                worker.stats.pending.fetch_sub(1, Ordering::Relaxed);
+                return
+            }
+        };
+
+        let delayed = worker.stats.delayed.fetch_add(1, Ordering::Relaxed) + 1;
+        let started = Instant::now();
+        if worker.tx.send(msg).await.is_err() {
+            error!("[worker {}] Stopped, dropping event", worker_id);
+            worker.stats.pending.fetch_sub(1, Ordering::Relaxed);
+            return
+        }
+        let waited = started.elapsed();
+        let handled = worker.stats.handled.load(Ordering::Relaxed);
+        if worker.last_warning.is_none_or(|last| last.elapsed() >= FULL_WARNING_INTERVAL) {
+            warn!(
+                "[worker {}] Queue full ({} events), waited {}ms. {} event(s) delayed, {} handled so far",
+                worker_id, queue_size, waited.as_millis(), delayed, handled
+            );
+            worker.last_warning = Some(Instant::now());
+        } else {
+            debug!("[worker {}] Queue full, waited {}ms", worker_id, waited.as_millis());
+        }
+    }
+
+    /// The counters of each worker, by worker ID
+    pub fn stats(&self) -> Vec<Arc<QueueStats>> {
+        self.workers.iter().map(|worker| worker.stats.clone()).collect()
+    }
+}
+
+/// Logs every worker's counters each `STATS_INTERVAL`. Stops once the
+/// dispatcher and its workers are gone.
+fn log_stats(stats: Vec<Arc<QueueStats>>) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + STATS_INTERVAL, STATS_INTERVAL);
+        let mut last_handled = 0;
+        loop {
+            ticks.tick().await;
+            if stats.iter().all(|stats| Arc::strong_count(stats) == 1) {
+                return
+            }
+            let handled: usize = stats.iter().map(|stats| stats.handled.load(Ordering::Relaxed)).sum();
+            if handled == last_handled {
+                continue
+            }
+            last_handled = handled;
+            for (worker_id, stats) in stats.iter().enumerate() {
+                info!(
+                    "[worker {}] {} pending, {} handled, {} delayed by a full queue",
+                    worker_id,
+                    stats.pending.load(Ordering::Relaxed),
+                    stats.handled.load(Ordering::Relaxed),
+                    stats.delayed.load(Ordering::Relaxed)
+                );
+            }
+        }
+    });
+}
+
+/// Events from the same channel, or the same guild for events without one,
+/// always go to the same worker so they are handled in order.
+fn worker_for(msg: &GatewayMessage, workers: usize) -> usize {
+    let key = msg.d.as_ref()
+        .and_then(|payload| payload.get_channel_id().or_else(|| payload.get_guild_id()))
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % workers.max(1) as u64) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::Controller;
+    use crate::discord;
+    use crate::gateway;
+
+    fn message(channel_id: &str) -> GatewayMessage {
+        GatewayMessage {
+            op: gateway::GatewayOpcode::Dispatch,
+            d: Some(gateway::GatewayMessageType::MessageCreate(discord::Message {
+                channel_id: String::from(channel_id),
+                ..Default::default()
+            })),
+            s: None,
+            t: Some(String::from("MESSAGE_CREATE"))
+        }
+    }
+
+    #[test]
+    fn channels_keep_their_worker() {
+        let workers: Vec<usize> = (0..20).map(|channel| worker_for(&message(&channel.to_string()), 4)).collect();
+        for (channel, worker) in workers.iter().enumerate() {
+            assert_eq!(worker_for(&message(&channel.to_string()), 4), *worker);
+            assert!(*worker < 4);
+        }
+        // Not everything on one worker
+        assert!(workers.iter().any(|worker| *worker != workers[0]));
+    }
+
+    #[tokio::test]
+    async fn dispatch_handles_every_event() {
+        let controller = Arc::new(SharedController::new(Controller::new(vec![]).unwrap()));
+        let config = DispatchConfig { workers: 2, queue_size: 1 };
+        let mut dispatcher = Dispatcher::start(&config, Arc::new(DiscordContext::empty()), controller);
+        for channel in 0..10 {
+            dispatcher.dispatch(message(&channel.to_string())).await;
+        }
+        let stats = dispatcher.stats();
+        drop(dispatcher);
+        for _ in 0..100 {
+            if stats.iter().map(|stats| stats.handled.load(Ordering::Relaxed)).sum::<usize>() == 10 {
+                break
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert_eq!(stats.iter().map(|stats| stats.handled.load(Ordering::Relaxed)).sum::<usize>(), 10);
+        assert!(stats.iter().all(|stats| stats.pending.load(Ordering::Relaxed) == 0));
+    }
+}
@@ -0,0 +1,132 @@
+use flate2::{Decompress, FlushDecompress};
+
+use crate::gateway::GatewayError;
+
+/// Every complete zlib-stream message ends with a Z_SYNC_FLUSH.
+const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Compressed bytes we hold on to while waiting for `ZLIB_SUFFIX`. Real
+/// payloads are far smaller; past this the stream is broken.
+const MAX_BUFFER: usize = 4 * 1024 * 1024;
+
+/// Inflates a `compress=zlib-stream` connection.
+///
+/// The whole connection shares one zlib context, so this has to live as long
+/// as the websocket does. A single payload may be split across several binary
+/// frames; it is only complete once the buffer ends with `ZLIB_SUFFIX`.
+pub struct ZlibStream {
+    inflater: Decompress,
+    buffer: Vec<u8>
+}
+
+impl Default for ZlibStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ZlibStream {
+    pub fn new() -> Self {
+        ZlibStream {
+            inflater: Decompress::new(true),
+            buffer: Vec::new()
+        }
+    }
+
+    /// Feeds one binary frame in. Returns the payload once all of its frames
+    /// have arrived.
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, GatewayError> {
+        if self.buffer.len() + frame.len() > MAX_BUFFER {
+            self.buffer.clear();
+            return Err(GatewayError::Decode(format!("zlib payload is over {} bytes", MAX_BUFFER)))
+        }
+        self.buffer.extend_from_slice(frame);
+        if !self.buffer.ends_with(&ZLIB_SUFFIX) {
+            return Ok(None)
+        }
+
+        let compressed = std::mem::take(&mut self.buffer);
+        let mut input = &compressed[..];
+        let mut output = Vec::with_capacity(compressed.len() * 4);
+        loop {
+            let (total_in, total_out) = (self.inflater.total_in(), self.inflater.total_out());
+            let had_room = output.len() < output.capacity();
+            self.inflater.decompress_vec(input, &mut output, FlushDecompress::Sync)
+                .map_err(|e| GatewayError::Decode(e.to_string()))?;
+            let consumed = (self.inflater.total_in() - total_in) as usize;
+            input = &input[consumed..];
+            // decompress_vec only writes into spare capacity; if it filled it
+            // up there may be more to come.
+            if input.is_empty() && output.len() < output.capacity() {
+                break
+            }
+            // e.g. data after the end of the stream
+            if had_room && consumed == 0 && self.inflater.total_out() == total_out {
+                return Err(GatewayError::Decode(format!("zlib stream stopped with {} byte(s) left", input.len())))
+            }
+            output.reserve(output.capacity().max(1024));
+        }
+
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    fn compress(compressor: &mut Compress, text: &str) -> Vec<u8> {
+        let mut output = Vec::with_capacity(text.len() + 64);
+        compressor.compress_vec(text.as_bytes(), &mut output, FlushCompress::Sync).unwrap();
+        output
+    }
+
+    #[test]
+    fn inflate_split_frames() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut stream = ZlibStream::new();
+
+        let hello = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#;
+        let compressed = compress(&mut compressor, hello);
+        assert!(compressed.ends_with(&ZLIB_SUFFIX));
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        assert_eq!(stream.push(first).unwrap(), None);
+        assert_eq!(stream.push(second).unwrap(), Some(hello.as_bytes().to_vec()));
+
+        // The context carries over to the next payload
+        let ack = r#"{"t":null,"s":null,"op":11,"d":null}"#;
+        let compressed = compress(&mut compressor, ack);
+        assert_eq!(stream.push(&compressed).unwrap(), Some(ack.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn inflate_large_payload() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut stream = ZlibStream::new();
+
+        let payload = format!(r#"{{"content":"{}"}}"#, "a".repeat(1 << 16));
+        let compressed = compress(&mut compressor, &payload);
+        assert_eq!(stream.push(&compressed).unwrap(), Some(payload.into_bytes()));
+    }
+
+    #[test]
+    fn unfinished_payloads_are_capped() {
+        let mut stream = ZlibStream::new();
+        let frame = vec![0; MAX_BUFFER / 2];
+        assert_eq!(stream.push(&frame).unwrap(), None);
+        assert_eq!(stream.push(&frame).unwrap(), None);
+        assert!(matches!(stream.push(&[0]), Err(GatewayError::Decode(_))));
+    }
+
+    #[test]
+    fn data_after_stream_end_is_an_error() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut stream = ZlibStream::new();
+
+        let mut frame = Vec::with_capacity(128);
+        compressor.compress_vec(b"{\"op\":11}", &mut frame, FlushCompress::Finish).unwrap();
+        frame.extend_from_slice(&[1, 2, 3]);
+        frame.extend_from_slice(&ZLIB_SUFFIX);
+        assert!(stream.push(&frame).is_err());
+    }
+}
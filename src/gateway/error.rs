@@ -0,0 +1,51 @@
+use std::fmt;
+use std::error::Error;
+use tokio_tungstenite::tungstenite;
+
+/// Everything that can go wrong between us and the gateway.
+#[derive(Debug)]
+pub enum GatewayError {
+    /// Could not open the websocket connection
+    Connect(tungstenite::Error),
+    /// The websocket failed while reading or writing
+    WebSocket(tungstenite::Error),
+    /// The gateway closed the connection (close code, reason)
+    Closed(u16, String),
+    /// The first payload from the gateway was not a usable Hello
+    BadHello(String),
+    /// A payload from the gateway could not be decoded
+    Decode(String),
+    /// A command could not be encoded for the gateway
+    Encode(String),
+    /// Could not send Identify or Resume
+    Handshake(String),
+    /// The gateway stopped acknowledging our heartbeats
+    Zombie,
+    /// The channels between the client and the websocket threads are gone
+    ChannelClosed,
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewayError::Connect(e) => write!(f, "Could not connect to gateway: {}", e),
+            GatewayError::WebSocket(e) => write!(f, "Websocket error: {}", e),
+            GatewayError::Closed(code, reason) => write!(f, "Gateway closed the connection ({}): {}", code, reason),
+            GatewayError::BadHello(e) => write!(f, "Bad Hello from gateway: {}", e),
+            GatewayError::Decode(e) => write!(f, "Could not decode gateway payload: {}", e),
+            GatewayError::Encode(e) => write!(f, "Could not encode gateway command: {}", e),
+            GatewayError::Handshake(e) => write!(f, "Could not identify or resume: {}", e),
+            GatewayError::Zombie => write!(f, "Gateway stopped acknowledging heartbeats"),
+            GatewayError::ChannelClosed => write!(f, "Gateway channel closed"),
+        }
+    }
+}
+
+impl Error for GatewayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GatewayError::Connect(e) | GatewayError::WebSocket(e) => Some(e),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,362 @@
+/// Erlang External Term Format, for connecting with `encoding=etf`.
+///
+/// Terms are mapped to and from `serde_json::Value` so that the same models
+/// deserialize no matter which encoding the gateway speaks:
+///
+/// * `nil`/`null`, `true` and `false` atoms become null and booleans; other atoms become strings
+/// * binaries become strings
+/// * charlists (`STRING_EXT`) are lists of small integers, e.g. `shard: [0, 1]`,
+///   and become arrays of numbers
+/// * big integers become strings. Snowflakes arrive this way and the models
+///   expect them as strings; numeric fields that can pass 32 bits accept
+///   both (see `discord::integer`).
+/// * tuples and lists become arrays, maps become objects
+///
+/// https://erlang.org/doc/apps/erts/erl_ext_dist.html
+use std::convert::TryInto;
+use serde_json::{Map, Number, Value};
+
+const FORMAT_VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    let mut decoder = Decoder { data, position: 0 };
+    let version = decoder.read_u8()?;
+    if version != FORMAT_VERSION {
+        return Err(format!("unsupported ETF version {}", version))
+    }
+    let value = decoder.read_term()?;
+    if decoder.position != data.len() {
+        return Err(format!("{} trailing bytes after term", data.len() - decoder.position))
+    }
+    Ok(value)
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = vec![FORMAT_VERSION];
+    write_term(&mut output, value);
+    output
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Decoder<'a> {
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let end = self.position.checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| format!("unexpected end of ETF data at byte {}", self.position))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_string(&mut self, length: usize) -> Result<String, String> {
+        let bytes = self.read_bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn read_term(&mut self) -> Result<Value, String> {
+        let tag = self.read_u8()?;
+        match tag {
+            SMALL_INTEGER_EXT => Ok(Value::from(self.read_u8()?)),
+            INTEGER_EXT => Ok(Value::from(self.read_u32()? as i32)),
+            NEW_FLOAT_EXT => {
+                let float = f64::from_be_bytes(self.read_bytes(8)?.try_into().unwrap());
+                Ok(Number::from_f64(float).map(Value::Number).unwrap_or(Value::Null))
+            },
+            FLOAT_EXT => {
+                let text = self.read_string(31)?;
+                let float = text.trim_end_matches('\0').trim().parse::<f64>().map_err(|e| e.to_string())?;
+                Ok(Number::from_f64(float).map(Value::Number).unwrap_or(Value::Null))
+            },
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let length = self.read_u16()? as usize;
+                self.read_atom(length)
+            },
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let length = self.read_u8()? as usize;
+                self.read_atom(length)
+            },
+            SMALL_TUPLE_EXT => {
+                let arity = self.read_u8()? as usize;
+                self.read_elements(arity)
+            },
+            LARGE_TUPLE_EXT => {
+                let arity = self.read_u32()? as usize;
+                self.read_elements(arity)
+            },
+            NIL_EXT => Ok(Value::Array(vec![])),
+            STRING_EXT => {
+                let length = self.read_u16()? as usize;
+                Ok(self.read_bytes(length)?.iter().map(|byte| Value::from(*byte)).collect())
+            },
+            LIST_EXT => {
+                let length = self.read_u32()? as usize;
+                let elements = self.read_elements(length)?;
+                // Proper lists end with NIL_EXT
+                match self.read_term()? {
+                    Value::Array(tail) if tail.is_empty() => Ok(elements),
+                    _ => Err(String::from("improper lists are not supported"))
+                }
+            },
+            BINARY_EXT => {
+                let length = self.read_u32()? as usize;
+                Ok(Value::String(self.read_string(length)?))
+            },
+            SMALL_BIG_EXT => {
+                let length = self.read_u8()? as usize;
+                self.read_big(length)
+            },
+            LARGE_BIG_EXT => {
+                let length = self.read_u32()? as usize;
+                self.read_big(length)
+            },
+            MAP_EXT => {
+                let arity = self.read_u32()? as usize;
+                let mut map = Map::new();
+                for _ in 0..arity {
+                    let key = match self.read_term()? {
+                        Value::String(key) => key,
+                        Value::Null => String::from("nil"),
+                        key => key.to_string()
+                    };
+                    let value = self.read_term()?;
+                    map.insert(key, value);
+                }
+                Ok(Value::Object(map))
+            },
+            _ => Err(format!("unsupported ETF tag {}", tag))
+        }
+    }
+
+    fn read_atom(&mut self, length: usize) -> Result<Value, String> {
+        let atom = self.read_string(length)?;
+        Ok(match &atom[..] {
+            "nil" | "null" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(atom)
+        })
+    }
+
+    fn read_elements(&mut self, count: usize) -> Result<Value, String> {
+        let mut elements = Vec::with_capacity(count.min(1 << 16));
+        for _ in 0..count {
+            elements.push(self.read_term()?);
+        }
+        Ok(Value::Array(elements))
+    }
+
+    fn read_big(&mut self, length: usize) -> Result<Value, String> {
+        let sign = self.read_u8()?;
+        let digits = self.read_bytes(length)?;
+        if length > 8 {
+            return Err(format!("{} byte integers are not supported", length))
+        }
+        let mut magnitude: u64 = 0;
+        for (i, digit) in digits.iter().enumerate() {
+            magnitude |= (*digit as u64) << (8 * i);
+        }
+        if sign == 0 {
+            Ok(Value::String(magnitude.to_string()))
+        } else {
+            Ok(Value::String(format!("-{}", magnitude)))
+        }
+    }
+}
+
+fn write_term(output: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => write_atom(output, "nil"),
+        Value::Bool(true) => write_atom(output, "true"),
+        Value::Bool(false) => write_atom(output, "false"),
+        Value::Number(number) => {
+            if let Some(integer) = number.as_u64() {
+                write_integer(output, integer as i128);
+            } else if let Some(integer) = number.as_i64() {
+                write_integer(output, integer as i128);
+            } else {
+                output.push(NEW_FLOAT_EXT);
+                output.extend_from_slice(&number.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        },
+        Value::String(string) => {
+            output.push(BINARY_EXT);
+            output.extend_from_slice(&(string.len() as u32).to_be_bytes());
+            output.extend_from_slice(string.as_bytes());
+        },
+        Value::Array(elements) => {
+            if !elements.is_empty() {
+                output.push(LIST_EXT);
+                output.extend_from_slice(&(elements.len() as u32).to_be_bytes());
+                for element in elements {
+                    write_term(output, element);
+                }
+            }
+            output.push(NIL_EXT);
+        },
+        Value::Object(map) => {
+            output.push(MAP_EXT);
+            output.extend_from_slice(&(map.len() as u32).to_be_bytes());
+            for (key, value) in map {
+                write_term(output, &Value::String(key.clone()));
+                write_term(output, value);
+            }
+        }
+    }
+}
+
+fn write_atom(output: &mut Vec<u8>, atom: &str) {
+    output.push(SMALL_ATOM_UTF8_EXT);
+    output.push(atom.len() as u8);
+    output.extend_from_slice(atom.as_bytes());
+}
+
+fn write_integer(output: &mut Vec<u8>, integer: i128) {
+    if integer >= 0 && integer <= u8::MAX as i128 {
+        output.push(SMALL_INTEGER_EXT);
+        output.push(integer as u8);
+    } else if integer >= i32::MIN as i128 && integer <= i32::MAX as i128 {
+        output.push(INTEGER_EXT);
+        output.extend_from_slice(&(integer as i32).to_be_bytes());
+    } else {
+        let magnitude = integer.unsigned_abs() as u64;
+        let digits = magnitude.to_le_bytes();
+        let length = 8 - digits.iter().rev().take_while(|digit| **digit == 0).count();
+        output.push(SMALL_BIG_EXT);
+        output.push(length as u8);
+        output.push(if integer < 0 { 1 } else { 0 });
+        output.extend_from_slice(&digits[..length]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gateway::message::fixtures;
+    use crate::gateway::{GatewayCommand, GatewayCommandType, GatewayMessage, GatewayMessageType, GatewayOpcode};
+
+    #[test]
+    fn round_trip_gateway_fixtures() {
+        for fixture in fixtures::ALL.iter() {
+            let value = serde_json::from_str::<Value>(fixture).unwrap();
+            let decoded = decode(&encode(&value)).unwrap();
+            assert_eq!(decoded, value);
+            serde_json::from_value::<GatewayMessage>(decoded).unwrap();
+        }
+    }
+
+    #[test]
+    fn decode_message_create_fixture() {
+        let value = serde_json::from_str::<Value>(fixtures::MESSAGE_CREATE).unwrap();
+        let message = serde_json::from_value::<GatewayMessage>(decode(&encode(&value)).unwrap()).unwrap();
+        match message.d.unwrap() {
+            GatewayMessageType::MessageCreate(msg) => assert_eq!(msg.content, "aaa"),
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn decode_erlang_terms() {
+        // term_to_binary(#{d => nil, op => 11})
+        let heartbeat_ack = [131, 116, 0, 0, 0, 2, 100, 0, 1, 100, 100, 0, 3, 110, 105, 108, 100, 0, 2, 111, 112, 97, 11];
+        assert_eq!(decode(&heartbeat_ack).unwrap(), serde_json::json!({"d": null, "op": 11}));
+
+        // Snowflakes arrive as big integers
+        let mut snowflake = vec![131, SMALL_BIG_EXT, 8, 0];
+        snowflake.extend_from_slice(&368933402751008771u64.to_le_bytes());
+        assert_eq!(decode(&snowflake).unwrap(), Value::from("368933402751008771"));
+
+        assert!(decode(&[131, 109, 0, 0, 0, 5, 97]).is_err());
+    }
+
+    #[test]
+    fn decode_ready_with_shard() {
+        // term_to_binary(#{op => 0, s => 1, t => 'READY', d => #{v => 10,
+        //     session_id => <<"abc">>, shard => [0, 1], guilds => [], private_channels => [],
+        //     user => #{id => <<"1">>, username => <<"bot">>, discriminator => <<"0">>}}})
+        let ready: &[u8] = &[
+            131, 116, 0, 0, 0, 4,
+            100, 0, 2, 111, 112, 97, 0,
+            100, 0, 1, 115, 97, 1,
+            100, 0, 1, 116, 100, 0, 5, 82, 69, 65, 68, 89,
+            100, 0, 1, 100, 116, 0, 0, 0, 6,
+                100, 0, 1, 118, 97, 10,
+                100, 0, 10, 115, 101, 115, 115, 105, 111, 110, 95, 105, 100, 109, 0, 0, 0, 3, 97, 98, 99,
+                100, 0, 5, 115, 104, 97, 114, 100, 107, 0, 2, 0, 1,
+                100, 0, 6, 103, 117, 105, 108, 100, 115, 106,
+                100, 0, 16, 112, 114, 105, 118, 97, 116, 101, 95, 99, 104, 97, 110, 110, 101, 108, 115, 106,
+                100, 0, 4, 117, 115, 101, 114, 116, 0, 0, 0, 3,
+                    100, 0, 2, 105, 100, 109, 0, 0, 0, 1, 49,
+                    100, 0, 8, 117, 115, 101, 114, 110, 97, 109, 101, 109, 0, 0, 0, 3, 98, 111, 116,
+                    100, 0, 13, 100, 105, 115, 99, 114, 105, 109, 105, 110, 97, 116, 111, 114, 109, 0, 0, 0, 1, 48
+        ];
+        let value = decode(ready).unwrap();
+        assert_eq!(value["d"]["shard"], serde_json::json!([0, 1]));
+        match serde_json::from_value::<GatewayMessage>(value).unwrap().d.unwrap() {
+            GatewayMessageType::Ready(ready) => {
+                assert_eq!(ready.shard, Some((0, 1)));
+                assert_eq!(ready.session_id, "abc");
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn big_integers_fit_numeric_fields() {
+        // A millisecond timestamp is past 32 bits
+        let mut since = vec![131, SMALL_BIG_EXT, 6, 0];
+        since.extend_from_slice(&1700000000000u64.to_le_bytes()[..6]);
+        let payload = serde_json::json!({"since": decode(&since).unwrap(), "status": "idle"});
+        let presence = serde_json::from_value::<crate::gateway::PresenceUpdatePayload>(payload).unwrap();
+        assert_eq!(presence.since, Some(1700000000000));
+    }
+
+    #[test]
+    fn encode_gateway_command() {
+        let heartbeat = GatewayCommand {
+            op: GatewayOpcode::Heartbeat,
+            d: GatewayCommandType::Heartbeat(Some(70000))
+        };
+        let value = serde_json::to_value(&heartbeat).unwrap();
+        let encoded = encode(&value);
+        assert_eq!(
+            encoded,
+            vec![131, 116, 0, 0, 0, 2,
+                 109, 0, 0, 0, 1, 100, 98, 0, 1, 17, 112,
+                 109, 0, 0, 0, 2, 111, 112, 97, 1]
+        );
+        assert_eq!(decode(&encoded).unwrap(), value);
+    }
+}
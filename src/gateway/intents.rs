@@ -0,0 +1,131 @@
+use serde::{Serialize, Deserialize};
+use strum_macros::EnumIter;
+
+/// https://discord.com/developers/docs/topics/gateway#gateway-intents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumIter)]
+#[allow(non_camel_case_types)]
+pub enum Intent {
+    GUILDS,
+    /// Privileged
+    GUILD_MEMBERS,
+    GUILD_BANS,
+    GUILD_EMOJIS,
+    GUILD_INTEGRATIONS,
+    GUILD_WEBHOOKS,
+    GUILD_INVITES,
+    GUILD_VOICE_STATES,
+    /// Privileged
+    GUILD_PRESENCES,
+    GUILD_MESSAGES,
+    GUILD_MESSAGE_REACTIONS,
+    GUILD_MESSAGE_TYPING,
+    DIRECT_MESSAGES,
+    DIRECT_MESSAGE_REACTIONS,
+    DIRECT_MESSAGE_TYPING,
+    /// Privileged. Without it, message content is empty outside of DMs and
+    /// mentions.
+    MESSAGE_CONTENT,
+}
+
+impl Intent {
+    pub fn bit(&self) -> u32 {
+        match self {
+            Intent::GUILDS => 1 << 0,
+            Intent::GUILD_MEMBERS => 1 << 1,
+            Intent::GUILD_BANS => 1 << 2,
+            Intent::GUILD_EMOJIS => 1 << 3,
+            Intent::GUILD_INTEGRATIONS => 1 << 4,
+            Intent::GUILD_WEBHOOKS => 1 << 5,
+            Intent::GUILD_INVITES => 1 << 6,
+            Intent::GUILD_VOICE_STATES => 1 << 7,
+            Intent::GUILD_PRESENCES => 1 << 8,
+            Intent::GUILD_MESSAGES => 1 << 9,
+            Intent::GUILD_MESSAGE_REACTIONS => 1 << 10,
+            Intent::GUILD_MESSAGE_TYPING => 1 << 11,
+            Intent::DIRECT_MESSAGES => 1 << 12,
+            Intent::DIRECT_MESSAGE_REACTIONS => 1 << 13,
+            Intent::DIRECT_MESSAGE_TYPING => 1 << 14,
+            Intent::MESSAGE_CONTENT => 1 << 15,
+        }
+    }
+
+    /// Privileged intents have to be switched on in the developer portal, so
+    /// we only ever send them when the config asks for them.
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Intent::GUILD_MEMBERS | Intent::GUILD_PRESENCES | Intent::MESSAGE_CONTENT)
+    }
+}
+
+/// `gateway.intents` in the config
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct IntentsConfig {
+    /// Always sent, on top of what the rules need. Required for privileged intents.
+    #[serde(default)]
+    pub enable: Vec<Intent>,
+    /// Never sent, even if a rule needs it
+    #[serde(default)]
+    pub disable: Vec<Intent>,
+}
+
+impl IntentsConfig {
+    /// Works out which intents to identify with given the intents rules need.
+    /// Returns the intents to send and the required intents that were left out.
+    pub fn resolve<'a, I>(&self, required: I) -> (Vec<Intent>, Vec<Intent>)
+    where I: IntoIterator<Item = &'a Intent>
+    {
+        // We always need GUILD_CREATE to know about channels, roles and emojis
+        let mut enabled = vec![Intent::GUILDS];
+        let mut missing = vec![];
+        for intent in self.enable.iter() {
+            if !enabled.contains(intent) {
+                enabled.push(*intent);
+            }
+        }
+        for intent in required {
+            if enabled.contains(intent) {
+                continue
+            }
+            if intent.is_privileged() || self.disable.contains(intent) {
+                if !missing.contains(intent) {
+                    missing.push(*intent);
+                }
+            } else {
+                enabled.push(*intent);
+            }
+        }
+        enabled.retain(|intent| !self.disable.contains(intent));
+        (enabled, missing)
+    }
+}
+
+pub fn to_bits<'a, I>(intents: I) -> u32
+where I: IntoIterator<Item = &'a Intent>
+{
+    intents.into_iter().fold(0, |bits, intent| bits | intent.bit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn privileged_intents_are_opt_in() {
+        let config = IntentsConfig::default();
+        let (enabled, missing) = config.resolve(&[Intent::GUILD_MESSAGES, Intent::GUILD_MEMBERS]);
+        assert_eq!(to_bits(&enabled), Intent::GUILDS.bit() | Intent::GUILD_MESSAGES.bit());
+        assert_eq!(missing, vec![Intent::GUILD_MEMBERS]);
+
+        let config = serde_json::from_str::<IntentsConfig>(r#"{"enable":["GUILD_MEMBERS"]}"#).unwrap();
+        let (enabled, missing) = config.resolve(&[Intent::GUILD_MEMBERS]);
+        assert_eq!(to_bits(&enabled), Intent::GUILDS.bit() | Intent::GUILD_MEMBERS.bit());
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn disabled_intents_are_never_sent() {
+        let config = serde_json::from_str::<IntentsConfig>(r#"{"disable":["GUILD_MESSAGE_REACTIONS"]}"#).unwrap();
+        let (enabled, missing) = config.resolve(&[Intent::GUILD_MESSAGE_REACTIONS]);
+        assert_eq!(enabled, vec![Intent::GUILDS]);
+        assert_eq!(missing, vec![Intent::GUILD_MESSAGE_REACTIONS]);
+    }
+}
@@ -2,85 +2,10 @@ use log::*;
 use std::fmt;
 use std::default::Default;
 use serde::{Serialize, Deserialize, Deserializer};
-use serde::de::{Visitor, MapAccess};
-use serde_json::{ser, de};
+use serde::de::{self, Visitor, MapAccess, DeserializeOwned};
 use strum_macros::{EnumIter};
 use crate::discord;
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use strum::IntoEnumIterator;
-//#[allow(non_camel_case_types)]
-//enum GatewayEventName {
-//    // GUILDS (1 << 0)
-//    GUILD_CREATE,
-//    GUILD_UPDATE,
-//    //GUILD_DELETE,
-//    //GUILD_ROLE_CREATE,
-//    //GUILD_ROLE_UPDATE,
-//    //GUILD_ROLE_DELETE,
-//    //CHANNEL_CREATE,
-//    //CHANNEL_UPDATE,
-//    //CHANNEL_DELETE,
-//    //CHANNEL_PINS_UPDATE,
-//
-//    // GUILD_MEMBERS (1 << 1)
-//    GUILD_MEMBER_ADD,
-//    //GUILD_MEMBER_UPDATE,
-//    //GUILD_MEMBER_REMOVE,
-//
-//    // GUILD_BANS (1 << 2)
-//    //GUILD_BAN_ADD,
-//    //GUILD_BAN_REMOVE,
-//
-//    // GUILD_EMOJIS (1 << 3)
-//    //GUILD_EMOJIS_UPDATE,
-//
-//    // GUILD_INTEGRATIONS (1 << 4)
-//    //GUILD_INTEGRATIONS_UPDATE,
-//
-//    // GUILD_WEBHOOKS (1 << 5)
-//    //WEBHOOKS_UPDATE,
-//
-//    // GUILD_INVITES (1 << 6)
-//    //INVITE_CREATE,
-//    //INVITE_DELETE,
-//
-//    // GUILD_VOICE_STATES (1 << 7)
-//    //VOICE_STATE_UPDATE,
-//
-//    // GUILD_PRESENCES (1 << 8)
-//    //PRESENCE_UPDATE,
-//
-//    // GUILD_MESSAGES (1 << 9)
-//    MESSAGE_CREATE,
-//    //MESSAGE_UPDATE,
-//    //MESSAGE_DELETE,
-//    //MESSAGE_DELETE_BULK,
-//
-//    // GUILD_MESSAGE_REACTIONS (1 << 10)
-//    MESSAGE_REACTION_ADD,
-//    //MESSAGE_REACTION_REMOVE,
-//    //MESSAGE_REACTION_REMOVE_ALL,
-//    //MESSAGE_REACTION_REMOVE_EMOJI,
-//
-//    // GUILD_MESSAGE_TYPING (1 << 11)
-//    TYPING_START,
-//
-//    // DIRECT_MESSAGES (1 << 12)
-//    //CHANNEL_CREATE,
-//    //MESSAGE_CREATE,
-//    //MESSAGE_UPDATE,
-//    //MESSAGE_DELETE,
-//    //CHANNEL_PINS_UPDATE,
-//
-//    // DIRECT_MESSAGE_REACTIONS (1 << 13)
-//    //MESSAGE_REACTION_ADD,
-//    //MESSAGE_REACTION_REMOVE,
-//    //MESSAGE_REACTION_REMOVE_ALL,
-//    //MESSAGE_REACTION_REMOVE_EMOJI,
-//
-//    // DIRECT_MESSAGE_TYPING (1 << 14)
-//    //TYPING_START,
-//}
 
 #[derive(Debug, PartialEq, Clone, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
@@ -149,61 +74,150 @@ impl<'de> Visitor<'de> for GatewayMessageVisitor {
     fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
     where A: MapAccess<'de> 
     {
+        use serde::de::Error;
+
         let mut op: Option<GatewayOpcode> = None;
-        let mut d_str: Option<String> = None;
+        let mut d_value: Option<serde_json::Value> = None;
         let mut d: Option<GatewayMessageType> = None;
         let mut s: Option<u64> = None;
         let mut t: Option<String> = None;
 
         while let Some((key, value)) = map.next_entry::<String, serde_json::Value>()? {
-            if key == "op" {
-                op = Some(de::from_str::<GatewayOpcode>(value.to_string().as_str()).unwrap());
-            }
-            if key == "d" {
-                if !value.is_null() {
-                    d_str = Some(value.to_string());
-                }
-            }
-            if key == "t" {
-                if !value.is_null() {
-                    t = Some(value.to_string());
-                }
-            }
-            if key == "s" {
-                if !value.is_null() {
-                    s = Some(value.as_u64().unwrap());
-                }
+            match &key[..] {
+                "op" => {
+                    op = Some(serde_json::from_value::<GatewayOpcode>(value).map_err(A::Error::custom)?);
+                },
+                "d" if !value.is_null() => {
+                    d_value = Some(value);
+                },
+                "t" if !value.is_null() => {
+                    t = Some(value.as_str().ok_or_else(|| A::Error::custom("event name is not a string"))?.to_owned());
+                },
+                "s" if !value.is_null() => {
+                    s = Some(
+                        value.as_u64()
+                            .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+                            .ok_or_else(|| A::Error::custom("sequence number is not an integer"))?
+                    );
+                },
+                _ => {}
             }
         }
-        if let None = op {
-            panic!("Could not find opcode");
-        }
+        let op = op.ok_or_else(|| A::Error::missing_field("op"))?;
 
         // Deserialize GatewayMessage Payload (d)
-        match op.clone().unwrap() {
+        match op {
             GatewayOpcode::Dispatch => {
-                let d_str = d_str.unwrap();
-                match &t.clone().expect("Message type is none in dispatch type")[..] {
-                    "\"HELLO\"" => {
-                        d = Some(GatewayMessageType::Hello(de::from_str::<HelloPayload>(d_str.as_str()).unwrap()));
+                let event = t.clone().ok_or_else(|| A::Error::missing_field("t"))?;
+                let d_value = d_value.ok_or_else(|| A::Error::missing_field("d"))?;
+                match &event[..] {
+                    "HELLO" => {
+                        d = Some(GatewayMessageType::Hello(decode(&event, d_value)?));
+                    },
+                    "MESSAGE_REACTION_ADD" => {
+                        d = Some(GatewayMessageType::MessageReactionAdd(decode(&event, d_value)?));
+                    },
+                    "MESSAGE_REACTION_REMOVE" => {
+                        d = Some(GatewayMessageType::MessageReactionRemove(decode(&event, d_value)?));
+                    },
+                    "MESSAGE_CREATE" => {
+                        d = Some(GatewayMessageType::MessageCreate(decode(&event, d_value)?));
+                    },
+                    "GUILD_CREATE" => {
+                        d = Some(GatewayMessageType::GuildCreate(decode(&event, d_value)?));
+                    },
+                    "READY" => {
+                        d = Some(GatewayMessageType::Ready(decode(&event, d_value)?));
+                    },
+                    "GUILD_MEMBERS_CHUNK" => {
+                        d = Some(GatewayMessageType::GuildMembersChunk(decode(&event, d_value)?));
+                    },
+                    "GUILD_UPDATE" => {
+                        d = Some(GatewayMessageType::GuildUpdate(decode(&event, d_value)?));
+                    },
+                    "GUILD_DELETE" => {
+                        d = Some(GatewayMessageType::GuildDelete(decode(&event, d_value)?));
+                    },
+                    "GUILD_ROLE_CREATE" => {
+                        d = Some(GatewayMessageType::GuildRoleCreate(decode(&event, d_value)?));
+                    },
+                    "GUILD_ROLE_UPDATE" => {
+                        d = Some(GatewayMessageType::GuildRoleUpdate(decode(&event, d_value)?));
+                    },
+                    "GUILD_ROLE_DELETE" => {
+                        d = Some(GatewayMessageType::GuildRoleDelete(decode(&event, d_value)?));
+                    },
+                    "CHANNEL_CREATE" => {
+                        d = Some(GatewayMessageType::ChannelCreate(decode(&event, d_value)?));
+                    },
+                    "CHANNEL_UPDATE" => {
+                        d = Some(GatewayMessageType::ChannelUpdate(decode(&event, d_value)?));
+                    },
+                    "CHANNEL_DELETE" => {
+                        d = Some(GatewayMessageType::ChannelDelete(decode(&event, d_value)?));
+                    },
+                    "CHANNEL_PINS_UPDATE" => {
+                        d = Some(GatewayMessageType::ChannelPinsUpdate(decode(&event, d_value)?));
+                    },
+                    "GUILD_MEMBER_ADD" => {
+                        d = Some(GatewayMessageType::GuildMemberAdd(decode(&event, d_value)?));
+                    },
+                    "GUILD_MEMBER_UPDATE" => {
+                        d = Some(GatewayMessageType::GuildMemberUpdate(decode(&event, d_value)?));
+                    },
+                    "GUILD_MEMBER_REMOVE" => {
+                        d = Some(GatewayMessageType::GuildMemberRemove(decode(&event, d_value)?));
+                    },
+                    "GUILD_BAN_ADD" => {
+                        d = Some(GatewayMessageType::GuildBanAdd(decode(&event, d_value)?));
+                    },
+                    "GUILD_BAN_REMOVE" => {
+                        d = Some(GatewayMessageType::GuildBanRemove(decode(&event, d_value)?));
+                    },
+                    "GUILD_EMOJIS_UPDATE" => {
+                        d = Some(GatewayMessageType::GuildEmojisUpdate(decode(&event, d_value)?));
+                    },
+                    "GUILD_INTEGRATIONS_UPDATE" => {
+                        d = Some(GatewayMessageType::GuildIntegrationsUpdate(decode(&event, d_value)?));
+                    },
+                    "WEBHOOKS_UPDATE" => {
+                        d = Some(GatewayMessageType::WebhooksUpdate(decode(&event, d_value)?));
+                    },
+                    "INVITE_CREATE" => {
+                        d = Some(GatewayMessageType::InviteCreate(decode(&event, d_value)?));
+                    },
+                    "INVITE_DELETE" => {
+                        d = Some(GatewayMessageType::InviteDelete(decode(&event, d_value)?));
+                    },
+                    "VOICE_STATE_UPDATE" => {
+                        d = Some(GatewayMessageType::VoiceStateUpdate(decode(&event, d_value)?));
+                    },
+                    "PRESENCE_UPDATE" => {
+                        d = Some(GatewayMessageType::PresenceUpdate(decode(&event, d_value)?));
                     },
-                    "\"MESSAGE_REACTION_ADD\"" => {
-                        d = Some(GatewayMessageType::MessageReactionAdd(de::from_str::<discord::Reaction>(d_str.as_str()).unwrap()));
+                    "MESSAGE_UPDATE" => {
+                        d = Some(GatewayMessageType::MessageUpdate(decode(&event, d_value)?));
                     },
-                    "\"MESSAGE_REACTION_REMOVE\"" => {
-                        d = Some(GatewayMessageType::MessageReactionRemove(de::from_str::<discord::RemoveReaction>(d_str.as_str()).unwrap()));
+                    "MESSAGE_DELETE" => {
+                        d = Some(GatewayMessageType::MessageDelete(decode(&event, d_value)?));
                     },
-                    "\"MESSAGE_CREATE\"" => {
-                        d = Some(GatewayMessageType::MessageCreate(de::from_str::<discord::Message>(d_str.as_str()).unwrap()));
+                    "MESSAGE_DELETE_BULK" => {
+                        d = Some(GatewayMessageType::MessageDeleteBulk(decode(&event, d_value)?));
                     },
-                    "\"GUILD_CREATE\"" => {
-                        d = Some(GatewayMessageType::GuildCreate(de::from_str::<discord::Guild>(d_str.as_str()).unwrap()));
+                    "MESSAGE_REACTION_REMOVE_ALL" => {
+                        d = Some(GatewayMessageType::MessageReactionRemoveAll(decode(&event, d_value)?));
                     },
-                    "\"READY\"" => {
-                        d = Some(GatewayMessageType::Ready(de::from_str::<discord::Ready>(d_str.as_str()).unwrap()));
+                    "MESSAGE_REACTION_REMOVE_EMOJI" => {
+                        d = Some(GatewayMessageType::MessageReactionRemoveEmoji(decode(&event, d_value)?));
+                    },
+                    "TYPING_START" => {
+                        d = Some(GatewayMessageType::TypingStart(decode(&event, d_value)?));
+                    },
+                    "RESUMED" => {
+                        d = Some(GatewayMessageType::Resumed(()));
                     },
                     _ => {
-                        debug!("Unhandled event... {}", t.clone().unwrap());
+                        debug!("Unhandled event... {}", event);
                     }
                 }
             },
@@ -215,18 +229,12 @@ impl<'de> Visitor<'de> for GatewayMessageVisitor {
                 d = Some(GatewayMessageType::Reconnect(()))
             },
             GatewayOpcode::InvalidSession => {
-                d = Some(
-                    GatewayMessageType::InvalidSession(
-                        de::from_str::<bool>(d_str.unwrap().as_str()).unwrap()
-                    )
-                );
+                let resumable = d_value.map(|value| value.as_bool().unwrap_or(false)).unwrap_or(false);
+                d = Some(GatewayMessageType::InvalidSession(resumable));
             },
             GatewayOpcode::Hello => {
-                d = Some(
-                    GatewayMessageType::Hello(
-                        de::from_str::<HelloPayload>(d_str.unwrap().as_str()).unwrap()
-                    )
-                )
+                let d_value = d_value.ok_or_else(|| A::Error::missing_field("d"))?;
+                d = Some(GatewayMessageType::Hello(decode("HELLO", d_value)?));
             },
             GatewayOpcode::HeartbeatAck => {
                 d = Some(GatewayMessageType::HeartbeatAck(()));
@@ -236,7 +244,7 @@ impl<'de> Visitor<'de> for GatewayMessageVisitor {
         };
 
         Ok(GatewayMessage {
-            op: op.unwrap(),
+            op,
             d,
             s,
             t
@@ -244,6 +252,16 @@ impl<'de> Visitor<'de> for GatewayMessageVisitor {
     }
 }
 
+/// Decodes the payload of a single event, naming the event if it does not fit
+/// the model.
+fn decode<T, E>(event: &str, value: serde_json::Value) -> Result<T, E>
+where T: DeserializeOwned,
+      E: de::Error
+{
+    serde_json::from_value::<T>(value)
+        .map_err(|err| E::custom(format!("could not decode {} payload: {}", event, err)))
+}
+
 #[derive(Clone, Serialize, Deserialize, EnumIter, Debug)]
 //#[serde(tag = "t")]
 //#[serde(untagged)]
@@ -252,6 +270,34 @@ pub enum GatewayMessageType {
     MessageReactionAdd(discord::Reaction),
     MessageCreate(discord::Message),
     GuildCreate(discord::Guild),
+    GuildMembersChunk(discord::GuildMembersChunk),
+    GuildUpdate(discord::Guild),
+    GuildDelete(discord::UnavailableGuild),
+    GuildRoleCreate(discord::GuildRole),
+    GuildRoleUpdate(discord::GuildRole),
+    GuildRoleDelete(discord::GuildRoleDelete),
+    ChannelCreate(discord::Channel),
+    ChannelUpdate(discord::Channel),
+    ChannelDelete(discord::Channel),
+    ChannelPinsUpdate(discord::ChannelPinsUpdate),
+    GuildMemberAdd(discord::GuildMemberAdd),
+    GuildMemberUpdate(discord::GuildMemberUpdate),
+    GuildMemberRemove(discord::GuildMemberRemove),
+    GuildBanAdd(discord::GuildBan),
+    GuildBanRemove(discord::GuildBan),
+    GuildEmojisUpdate(discord::GuildEmojisUpdate),
+    GuildIntegrationsUpdate(discord::GuildIntegrationsUpdate),
+    WebhooksUpdate(discord::WebhooksUpdate),
+    InviteCreate(discord::InviteCreate),
+    InviteDelete(discord::InviteDelete),
+    VoiceStateUpdate(discord::VoiceState),
+    PresenceUpdate(discord::PresenceUpdate),
+    MessageUpdate(discord::MessageUpdate),
+    MessageDelete(discord::MessageDelete),
+    MessageDeleteBulk(discord::MessageDeleteBulk),
+    MessageReactionRemoveAll(discord::RemoveAllReactions),
+    MessageReactionRemoveEmoji(discord::RemoveEmojiReactions),
+    TypingStart(discord::TypingStart),
     Ready(discord::Ready),
     Hello(HelloPayload),
     InvalidSession(bool),
@@ -265,23 +311,73 @@ impl GatewayMessageType {
     pub fn get_guild_id(&self) -> Option<String> {
         match self {
             GatewayMessageType::MessageReactionRemove(react) => {
-                Some(react.guild_id.clone())
+                react.guild_id.clone()
             },
             GatewayMessageType::MessageReactionAdd(react) => {
-                Some(react.guild_id.clone())
+                react.guild_id.clone()
             },
             GatewayMessageType::MessageCreate(msg) => {
-                Some(msg.guild_id.clone())
+                msg.guild_id.clone()
             },
             GatewayMessageType::GuildCreate(msg) => {
                 Some(msg.id.clone())
-            }
+            },
+            GatewayMessageType::GuildMembersChunk(chunk) => {
+                Some(chunk.guild_id.clone())
+            },
+            GatewayMessageType::GuildUpdate(guild) => Some(guild.id.clone()),
+            GatewayMessageType::GuildDelete(guild) => Some(guild.id.clone()),
+            GatewayMessageType::GuildRoleCreate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildRoleUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildRoleDelete(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::ChannelCreate(channel) => channel.guild_id.clone(),
+            GatewayMessageType::ChannelUpdate(channel) => channel.guild_id.clone(),
+            GatewayMessageType::ChannelDelete(channel) => channel.guild_id.clone(),
+            GatewayMessageType::ChannelPinsUpdate(event) => event.guild_id.clone(),
+            GatewayMessageType::GuildMemberAdd(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildMemberUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildMemberRemove(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildBanAdd(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildBanRemove(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildEmojisUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::GuildIntegrationsUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::WebhooksUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::InviteCreate(event) => event.guild_id.clone(),
+            GatewayMessageType::InviteDelete(event) => event.guild_id.clone(),
+            GatewayMessageType::VoiceStateUpdate(event) => event.guild_id.clone(),
+            GatewayMessageType::PresenceUpdate(event) => Some(event.guild_id.clone()),
+            GatewayMessageType::MessageUpdate(event) => event.guild_id.clone(),
+            GatewayMessageType::MessageDelete(event) => event.guild_id.clone(),
+            GatewayMessageType::MessageDeleteBulk(event) => event.guild_id.clone(),
+            GatewayMessageType::MessageReactionRemoveAll(event) => event.guild_id.clone(),
+            GatewayMessageType::MessageReactionRemoveEmoji(event) => event.guild_id.clone(),
+            GatewayMessageType::TypingStart(event) => event.guild_id.clone(),
             _ => {
                 debug!("Could not get guild_id");
                 None
             }
         }
     }
+    /// The user the event is about or came from
+    pub fn get_user_id(&self) -> Option<String> {
+        match self {
+            GatewayMessageType::MessageCreate(msg) => Some(msg.author.id.clone()),
+            GatewayMessageType::MessageReactionAdd(react) => Some(react.user_id.clone()),
+            GatewayMessageType::MessageReactionRemove(react) => Some(react.user_id.clone()),
+            GatewayMessageType::GuildMemberAdd(event) => event.member.user.as_ref().map(|user| user.id.clone()),
+            GatewayMessageType::GuildMemberUpdate(event) => Some(event.user.id.clone()),
+            GatewayMessageType::GuildMemberRemove(event) => Some(event.user.id.clone()),
+            GatewayMessageType::MessageUpdate(event) => event.author.as_ref()
+                .or(event.previous.as_ref().map(|previous| &previous.author))
+                .map(|user| user.id.clone()),
+            GatewayMessageType::MessageDelete(event) => event.message.as_ref().map(|message| message.author.id.clone()),
+            GatewayMessageType::TypingStart(event) => Some(event.user_id.clone()),
+            _ => {
+                debug!("Could not get user_id");
+                None
+            }
+        }
+    }
     pub fn get_channel_id(&self) -> Option<String> {
         match self {
             GatewayMessageType::MessageCreate(msg) => {
@@ -293,6 +389,20 @@ impl GatewayMessageType {
             GatewayMessageType::MessageReactionRemove(react) => {
                 Some(react.channel_id.clone())
             },
+            GatewayMessageType::ChannelCreate(channel) => Some(channel.id.clone()),
+            GatewayMessageType::ChannelUpdate(channel) => Some(channel.id.clone()),
+            GatewayMessageType::ChannelDelete(channel) => Some(channel.id.clone()),
+            GatewayMessageType::ChannelPinsUpdate(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::WebhooksUpdate(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::InviteCreate(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::InviteDelete(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::VoiceStateUpdate(event) => event.channel_id.clone(),
+            GatewayMessageType::MessageUpdate(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::MessageDelete(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::MessageDeleteBulk(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::MessageReactionRemoveAll(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::MessageReactionRemoveEmoji(event) => Some(event.channel_id.clone()),
+            GatewayMessageType::TypingStart(event) => Some(event.channel_id.clone()),
             _ => {
                 debug!("Could not get channel_id");
                 None
@@ -311,7 +421,9 @@ impl Default for GatewayMessageType {
 pub enum GatewayCommandType {
     Identify(IdentifyPayload),
     Resume(ResumePayload),
-    Heartbeat(()),
+    PresenceUpdate(PresenceUpdatePayload),
+    /// Carries the last sequence number received, if any
+    Heartbeat(Option<u64>),
     RequestGuildMembers(GuildRequestPayload),
 
     /// Hack. If we send this message, we'll kill the sender thread
@@ -345,35 +457,114 @@ pub struct HelloPayload {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IdentifyConnectionPropertiesPayload {
-    #[serde(rename = "$os")]
     pub os: String,
-    #[serde(rename = "$browser")]
     pub browser: String,
-    #[serde(rename = "$device")]
     pub device: String,
 }
 
+/// https://discord.com/developers/docs/topics/gateway#activity-object-activity-types
+#[derive(Debug, PartialEq, Clone, Copy, Serialize_repr, Default)]
+#[repr(u8)]
+pub enum ActivityType {
+    /// Playing {name}
+    #[default]
+    Game = 0,
+    /// Streaming {name}, needs a twitch or youtube `url`
+    Streaming = 1,
+    /// Listening to {name}
+    Listening = 2,
+    /// Watching {name}
+    Watching = 3,
+    /// {emoji} {state}
+    Custom = 4,
+    /// Competing in {name}
+    Competing = 5,
+}
+/// Discord sends the number. In the config it is nicer to write the name.
+impl<'de> Deserialize<'de> for ActivityType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where D: Deserializer<'de>
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Number(u8),
+            Name(String)
+        }
+        let activity_type = match Repr::deserialize(deserializer)? {
+            Repr::Number(0) => ActivityType::Game,
+            Repr::Number(1) => ActivityType::Streaming,
+            Repr::Number(2) => ActivityType::Listening,
+            Repr::Number(3) => ActivityType::Watching,
+            Repr::Number(4) => ActivityType::Custom,
+            Repr::Number(5) => ActivityType::Competing,
+            Repr::Name(name) => match &name.to_lowercase()[..] {
+                "game" | "playing" => ActivityType::Game,
+                "streaming" => ActivityType::Streaming,
+                "listening" => ActivityType::Listening,
+                "watching" => ActivityType::Watching,
+                "custom" => ActivityType::Custom,
+                "competing" => ActivityType::Competing,
+                _ => return Err(de::Error::custom(format!("unknown activity type {}", name)))
+            },
+            Repr::Number(number) => return Err(de::Error::custom(format!("unknown activity type {}", number)))
+        };
+        Ok(activity_type)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct IdentifyPresenceGamePayload {
+pub struct Activity {
     pub name: String,
-    #[serde(rename = "type")]
-    pub _type: u32
+    #[serde(rename = "type", default)]
+    pub _type: ActivityType,
+    /// Stream url, for Streaming activities
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Status text, for Custom activities
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>
 }
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct IdentifyPresencePayload {
-    pub game: IdentifyPresenceGamePayload,
-    pub status: String,
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Online,
+    /// Do Not Disturb
+    Dnd,
+    Idle,
+    /// Shown as offline
+    Invisible,
+    Offline
+}
+
+/// Sent on its own as op 3, and as the initial presence in Identify.
+/// https://discord.com/developers/docs/topics/gateway#update-presence
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PresenceUpdatePayload {
+    /// unix time (in milliseconds) of when the client went idle
+    #[serde(default, deserialize_with = "discord::optional_integer")]
     pub since: Option<u64>,
+    #[serde(default)]
+    pub activities: Vec<Activity>,
+    #[serde(default)]
+    pub status: Status,
+    #[serde(default)]
     pub afk: bool
 }
+impl<'a> GatewayPayload<'a> for PresenceUpdatePayload {}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IdentifyPayload {
     pub token: String,
     pub properties: IdentifyConnectionPropertiesPayload,
-    pub presence: IdentifyPresencePayload,
+    pub presence: PresenceUpdatePayload,
     /// https://discord.com/developers/docs/topics/gateway#gateway-intents
-    pub intents: u32
+    pub intents: u32,
+    /// [shard_id, num_shards]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<[u32; 2]>
 }
 impl<'a> GatewayPayload<'a> for IdentifyPayload {}
 
@@ -384,35 +575,60 @@ pub struct ResumePayload {
     pub seq: u64
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct GuildRequestPayload {
-    /// id of the guild(s) to get members for
-    pub guild_id: Vec<String>,
+    /// id of the guild to get members for
+    pub guild_id: String,
     /// string that username starts with, or an empty string to return all members
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub query: Option<String>,
-    /// maximum number of members to send matching the query; a limit of 0 
-    /// can be used with an empty string query to return all members	
+    /// maximum number of members to send matching the query; a limit of 0
+    /// can be used with an empty string query to return all members
     pub limit: u32,
-    /// used to specify if we want the presences of the matched members	
+    /// used to specify if we want the presences of the matched members
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub presences: Option<bool>,
     /// used to specify which users you wish to fetch
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub user_ids: Option<Vec<String>>,
-    /// nonce to identify the Guild Members Chunk response	
+    /// nonce to identify the Guild Members Chunk response
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub nonce: Option<String>
 }
+impl<'a> GatewayPayload<'a> for GuildRequestPayload {}
 
 
+#[cfg(test)]
+pub(crate) mod fixtures {
+    //! Payloads captured from the gateway, shared by the tests of each encoding.
+    pub const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250,"_trace":["[\"gateway-prd-main-t2rl\",{\"micros\":0.0}]"]}}"#;
+    pub const READY: &str = r#"{"t":"READY","s":1,"op":0,"d":{"v":6,"user_settings":{},"user":{"verified":true,"username":"GlennLeuteritz","mfa_enabled":false,"id":"368952148962181124","flags":0,"email":null,"discriminator":"8867","bot":true,"avatar":"0d1621c897fb531fa0295ed8ddefbc2d"},"session_id":"f386e7b70a22eec7cd795e37128be79a","relationships":[],"private_channels":[],"presences":[],"guilds":[{"unavailable":true,"id":"368933402751008771"}],"application":{"id":"368952148962181124","flags":0},"_trace":["[\"gateway-prd-main-rws7\",{\"micros\":54307,\"calls\":[\"discord-sessions-prd-1-51\",{\"micros\":52470,\"calls\":[\"start_session\",{\"micros\":49274,\"calls\":[\"api-prd-main-pmtg\",{\"micros\":45988,\"calls\":[\"get_user\",{\"micros\":7725},\"add_authorized_ip\",{\"micros\":1967},\"get_guilds\",{\"micros\":4030},\"coros_wait\",{\"micros\":1}]}]},\"guilds_connect\",{\"micros\":2,\"calls\":[]},\"presence_connect\",{\"micros\":915,\"calls\":[]}]}]}]"]}}"#;
+    pub const RESUMED: &str = r#"{"t":"RESUMED","s":7,"op":0,"d":{"_trace":["[\"gateway-prd-main-rws7\",{\"micros\":1203}]"]}}"#;
+    pub const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
+    pub const MESSAGE_CREATE: &str = r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"type":0,"tts":false,"timestamp":"2020-07-19T20:42:30.904000+00:00","pinned":false,"nonce":"734510507435753472","mentions":[],"mention_roles":[],"mention_everyone":false,"member":{"roles":["437773472324911115"],"premium_since":null,"nick":null,"mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","hoisted_role":null,"deaf":false},"id":"734510504860450826","flags":0,"embeds":[],"edited_timestamp":null,"content":"aaa","channel_id":"705147009761280010","author":{"username":"lomz","public_flags":0,"id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"attachments":[],"guild_id":"368933402751008771"}}"#;
+    pub const MESSAGE_REACTION_ADD: &str = r#"{"t":"MESSAGE_REACTION_ADD","s":5,"op":0,"d":{"user_id":"228347641120030731","message_id":"753807148777209886","member":{"user":{"username":"lomz","id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"roles":["437773472324911115"],"premium_since":null,"nick":"json michaud","mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","hoisted_role":null,"deaf":false},"emoji":{"name":"Doggo","id":"437783545490964482"},"channel_id":"705147009761280010","guild_id":"368933402751008771"}}"#;
+    pub const MESSAGE_REPLY: &str = r#"{"t":"MESSAGE_CREATE","s":4,"op":0,"d":{"type":19,"tts":false,"timestamp":"2023-03-02T18:11:04.153000+00:00","referenced_message":null,"pinned":false,"nonce":"1080907010011430912","message_reference":{"message_id":"1080906943082905650","guild_id":"368933402751008771","channel_id":"705147009761280010"},"mentions":[],"mention_roles":["437773472324911115"],"mention_everyone":false,"member":{"roles":["437773472324911115"],"premium_since":null,"pending":false,"nick":null,"mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","flags":0,"deaf":false,"communication_disabled_until":null,"avatar":null},"id":"1080907011072589864","flags":0,"embeds":[],"edited_timestamp":null,"content":"<@&437773472324911115> yes","components":[],"channel_id":"705147009761280010","author":{"username":"lomz","public_flags":0,"id":"228347641120030731","global_name":null,"discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"attachments":[],"guild_id":"368933402751008771"}}"#;
+
+    pub const GUILD_MEMBERS_CHUNK: &str = r#"{"t":"GUILD_MEMBERS_CHUNK","s":9,"op":0,"d":{"nonce":"0-1","members":[{"user":{"username":"lomz","public_flags":0,"id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"roles":["437773472324911115"],"premium_since":null,"pending":false,"nick":"json michaud","mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","deaf":false}],"guild_id":"368933402751008771","chunk_index":0,"chunk_count":2}}"#;
+    pub const GUILD_ROLE_CREATE: &str = r#"{"t":"GUILD_ROLE_CREATE","s":11,"op":0,"d":{"role":{"position":4,"permissions":"1071698660929","name":"new role","mentionable":false,"managed":false,"id":"1080913406325538896","hoist":false,"flags":0,"color":0},"guild_id":"368933402751008771"}}"#;
+    pub const MESSAGE_DELETE: &str = r#"{"t":"MESSAGE_DELETE","s":12,"op":0,"d":{"id":"1080907011072589864","channel_id":"705147009761280010","guild_id":"368933402751008771"}}"#;
+    pub const TYPING_START: &str = r#"{"t":"TYPING_START","s":13,"op":0,"d":{"user_id":"228347641120030731","timestamp":1677781234,"member":{"user":{"username":"lomz","public_flags":0,"id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"roles":["437773472324911115"],"premium_since":null,"pending":false,"nick":"json michaud","mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","deaf":false},"channel_id":"705147009761280010","guild_id":"368933402751008771"}}"#;
+
+    pub const ALL: [&str; 11] = [
+        HELLO, READY, RESUMED, INVALID_SESSION, MESSAGE_CREATE, MESSAGE_REACTION_ADD, MESSAGE_REPLY,
+        GUILD_MEMBERS_CHUNK, GUILD_ROLE_CREATE, MESSAGE_DELETE, TYPING_START
+    ];
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use serde_json::ser;
+    use serde_json::{ser, de};
 
 
     #[test]
     fn deserialize_hello_from_gateway() {
-        let hello_str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250,"_trace":["[\"gateway-prd-main-t2rl\",{\"micros\":0.0}]"]}}"#;
-        
-        let hello = de::from_str::<HelloMessage>(hello_str).unwrap();
+        let hello = de::from_str::<HelloMessage>(fixtures::HELLO).unwrap();
         assert_eq!(hello.t, None);
         assert_eq!(hello.s, None);
         assert_eq!(hello.op, GatewayOpcode::Hello);
@@ -421,8 +637,7 @@ mod test {
 
     #[test]
     fn deserialize_ready_from_gateway() {
-        let ready_str = r#"{"t":"READY","s":1,"op":0,"d":{"v":6,"user_settings":{},"user":{"verified":true,"username":"GlennLeuteritz","mfa_enabled":false,"id":"368952148962181124","flags":0,"email":null,"discriminator":"8867","bot":true,"avatar":"0d1621c897fb531fa0295ed8ddefbc2d"},"session_id":"f386e7b70a22eec7cd795e37128be79a","relationships":[],"private_channels":[],"presences":[],"guilds":[{"unavailable":true,"id":"368933402751008771"}],"application":{"id":"368952148962181124","flags":0},"_trace":["[\"gateway-prd-main-rws7\",{\"micros\":54307,\"calls\":[\"discord-sessions-prd-1-51\",{\"micros\":52470,\"calls\":[\"start_session\",{\"micros\":49274,\"calls\":[\"api-prd-main-pmtg\",{\"micros\":45988,\"calls\":[\"get_user\",{\"micros\":7725},\"add_authorized_ip\",{\"micros\":1967},\"get_guilds\",{\"micros\":4030},\"coros_wait\",{\"micros\":1}]}]},\"guilds_connect\",{\"micros\":2,\"calls\":[]},\"presence_connect\",{\"micros\":915,\"calls\":[]}]}]}]"]}}"#;
-        let ready = de::from_str::<GatewayMessage>(ready_str).unwrap();
+        let ready = de::from_str::<GatewayMessage>(fixtures::READY).unwrap();
 
         match ready.d.unwrap() {
             GatewayMessageType::Ready(ready) => {
@@ -434,10 +649,160 @@ mod test {
     }
 
     #[test]
-    fn deserialize_message_create_from_gateway() {
-        let message_str = r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"type":0,"tts":false,"timestamp":"2020-07-19T20:42:30.904000+00:00","pinned":false,"nonce":"734510507435753472","mentions":[],"mention_roles":[],"mention_everyone":false,"member":{"roles":["437773472324911115"],"premium_since":null,"nick":null,"mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","hoisted_role":null,"deaf":false},"id":"734510504860450826","flags":0,"embeds":[],"edited_timestamp":null,"content":"aaa","channel_id":"705147009761280010","author":{"username":"lomz","public_flags":0,"id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"attachments":[],"guild_id":"368933402751008771"}}"#;
+    fn serialize_heartbeat_with_sequence() {
+        let heartbeat = GatewayCommand {
+            op: GatewayOpcode::Heartbeat,
+            d: GatewayCommandType::Heartbeat(Some(251))
+        };
+        assert_eq!(ser::to_string(&heartbeat).unwrap(), r#"{"d":251,"op":1}"#);
 
-        let message = de::from_str::<GatewayMessage>(message_str).unwrap();
+        let heartbeat = GatewayCommand {
+            op: GatewayOpcode::Heartbeat,
+            d: GatewayCommandType::Heartbeat(None)
+        };
+        assert_eq!(ser::to_string(&heartbeat).unwrap(), r#"{"d":null,"op":1}"#);
+    }
+
+    #[test]
+    fn serialize_identify_with_shard() {
+        let identify = IdentifyPayload {
+            token: String::from("token"),
+            properties: IdentifyConnectionPropertiesPayload {
+                os: String::from("linux"),
+                browser: String::from("glennbot"),
+                device: String::from("glennbot"),
+            },
+            presence: PresenceUpdatePayload::default(),
+            intents: 1,
+            shard: Some([1, 2])
+        };
+        let value = serde_json::to_value(&identify).unwrap();
+        assert_eq!(value["shard"], serde_json::json!([1, 2]));
+
+        let identify = IdentifyPayload { shard: None, ..identify };
+        let value = serde_json::to_value(&identify).unwrap();
+        assert!(value.get("shard").is_none());
+    }
+
+    #[test]
+    fn serialize_presence_update() {
+        let presence = serde_json::from_str::<PresenceUpdatePayload>(
+            r##"{"status":"dnd","activities":[{"name":"#deploys","type":"watching"}]}"##
+        ).unwrap();
+        let update = GatewayCommand {
+            op: GatewayOpcode::PresenceUpdate,
+            d: GatewayCommandType::PresenceUpdate(presence)
+        };
+        assert_eq!(
+            ser::to_string(&update).unwrap(),
+            r##"{"d":{"since":null,"activities":[{"name":"#deploys","type":3}],"status":"dnd","afk":false},"op":3}"##
+        );
+    }
+
+    #[test]
+    fn deserialize_guild_members_chunk_from_gateway() {
+        let message = de::from_str::<GatewayMessage>(fixtures::GUILD_MEMBERS_CHUNK).unwrap();
+
+        match message.d.unwrap() {
+            GatewayMessageType::GuildMembersChunk(chunk) => {
+                assert_eq!(chunk.nonce.unwrap(), "0-1");
+                assert_eq!(chunk.chunk_count, 2);
+                assert_eq!(chunk.members[0].user.as_ref().unwrap().username, "lomz");
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn deserialize_guild_events_from_gateway() {
+        let message = de::from_str::<GatewayMessage>(fixtures::GUILD_ROLE_CREATE).unwrap();
+        match message.d.unwrap() {
+            GatewayMessageType::GuildRoleCreate(event) => {
+                assert_eq!(event.role.name, "new role");
+                assert_eq!(event.role.permissions, "1071698660929");
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        let message = de::from_str::<GatewayMessage>(fixtures::MESSAGE_DELETE).unwrap();
+        let payload = message.d.unwrap();
+        assert_eq!(payload.get_guild_id().unwrap(), "368933402751008771");
+        assert_eq!(payload.get_channel_id().unwrap(), "705147009761280010");
+        match payload {
+            GatewayMessageType::MessageDelete(event) => assert_eq!(event.id, "1080907011072589864"),
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        let message = de::from_str::<GatewayMessage>(fixtures::TYPING_START).unwrap();
+        match message.d.unwrap() {
+            GatewayMessageType::TypingStart(event) => {
+                assert_eq!(event.user_id, "228347641120030731");
+                assert!(event.member.is_some());
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+
+        // Channel types added after this version still deserialize
+        let channel_create = r#"{"t":"CHANNEL_CREATE","s":4,"op":0,"d":{"id":"1","type":99,"guild_id":"2","name":"new"}}"#;
+        match de::from_str::<GatewayMessage>(channel_create).unwrap().d.unwrap() {
+            GatewayMessageType::ChannelCreate(channel) => assert!(matches!(channel._type, discord::ChannelType::INVALID)),
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn serialize_request_guild_members() {
+        let request = GatewayCommand {
+            op: GatewayOpcode::RequestGuildMembers,
+            d: GatewayCommandType::RequestGuildMembers(GuildRequestPayload {
+                guild_id: String::from("368933402751008771"),
+                query: Some(String::new()),
+                limit: 0,
+                nonce: Some(String::from("0-1")),
+                ..Default::default()
+            })
+        };
+        assert_eq!(
+            ser::to_string(&request).unwrap(),
+            r#"{"d":{"guild_id":"368933402751008771","query":"","limit":0,"nonce":"0-1"},"op":8}"#
+        );
+    }
+
+    #[test]
+    fn deserialize_resumed_from_gateway() {
+        let resumed = de::from_str::<GatewayMessage>(fixtures::RESUMED).unwrap();
+
+        assert_eq!(resumed.s, Some(7));
+        match resumed.d.unwrap() {
+            GatewayMessageType::Resumed(_) => {},
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn deserialize_invalid_session_from_gateway() {
+        let invalid = de::from_str::<GatewayMessage>(fixtures::INVALID_SESSION).unwrap();
+
+        match invalid.d.unwrap() {
+            GatewayMessageType::InvalidSession(resumable) => assert!(!resumable),
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn deserialize_malformed_dispatch_is_an_error() {
+        // author is missing
+        let message_str = r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"id":"734510504860450826","content":"aaa"}}"#;
+        let err = de::from_str::<GatewayMessage>(message_str).err().unwrap();
+        assert!(err.to_string().contains("MESSAGE_CREATE"));
+
+        let missing_op = r#"{"t":null,"s":null,"d":null}"#;
+        assert!(de::from_str::<GatewayMessage>(missing_op).is_err());
+    }
+
+    #[test]
+    fn deserialize_message_create_from_gateway() {
+        let message = de::from_str::<GatewayMessage>(fixtures::MESSAGE_CREATE).unwrap();
 
         match message.d.unwrap() {
             GatewayMessageType::MessageCreate(msg) => {
@@ -448,10 +813,25 @@ mod test {
     }
 
     #[test]
-    fn deserialize_message_reaction_add_from_gateway() {
-        let message_str = r#"{"t":"MESSAGE_REACTION_ADD","s":5,"op":0,"d":{"user_id":"228347641120030731","message_id":"753807148777209886","member":{"user":{"username":"lomz","id":"228347641120030731","discriminator":"2555","avatar":"a4cd28fe90118475114437f18a4f7d56"},"roles":["437773472324911115"],"premium_since":null,"nick":"json michaud","mute":false,"joined_at":"2017-10-15T01:29:37.754000+00:00","hoisted_role":null,"deaf":false},"emoji":{"name":"Doggo","id":"437783545490964482"},"channel_id":"705147009761280010","guild_id":"368933402751008771"}}"#;
+    fn deserialize_message_reply_from_gateway() {
+        let message = de::from_str::<GatewayMessage>(fixtures::MESSAGE_REPLY).unwrap();
 
-        let message = de::from_str::<GatewayMessage>(message_str).unwrap();
+        match message.d.unwrap() {
+            GatewayMessageType::MessageCreate(msg) => {
+                let reference = msg.message_reference.unwrap();
+                assert_eq!(reference.message_id.unwrap(), "1080906943082905650");
+                assert_eq!(msg.mention_roles, vec!["437773472324911115"]);
+                let member = msg.member.unwrap();
+                assert!(member.user.is_none());
+                assert_eq!(member.roles, vec!["437773472324911115"]);
+            },
+            _ => panic!("Deserialized incorrectly")
+        }
+    }
+
+    #[test]
+    fn deserialize_message_reaction_add_from_gateway() {
+        let message = de::from_str::<GatewayMessage>(fixtures::MESSAGE_REACTION_ADD).unwrap();
 
         match message.d.unwrap() {
             GatewayMessageType::MessageReactionAdd(reaction) => {
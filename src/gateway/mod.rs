@@ -1,20 +1,34 @@
 use log::*;
 use std::sync::{Mutex, Arc};
-use std::error::Error;
+use std::collections::{HashMap, VecDeque};
 use serde;
 use serde_json::{ser, de};
 use serde::{Serialize, Deserialize};
 use url::Url;
 use futures_util::{SinkExt, StreamExt};
 use tokio::sync::mpsc::{Sender, Receiver, channel};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 use tokio_tungstenite::{connect_async};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
 use tokio::time::delay_for;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::discord;
 
 
 pub mod message;
+pub mod error;
+pub use error::GatewayError;
+pub mod intents;
+pub use intents::{Intent, IntentsConfig};
+pub mod shard;
+pub use shard::{ShardManager, IdentifyLimiter, GatewayHandle};
+pub mod compression;
+use compression::ZlibStream;
+pub mod etf;
 pub use message::{
     GatewayCommand,
     GatewayCommandType,
@@ -24,106 +38,402 @@ pub use message::{
     IdentifyPayload,
     HelloMessage,
     HelloPayload,
-    IdentifyPresencePayload,
-    IdentifyPresenceGamePayload,
-    IdentifyConnectionPropertiesPayload
+    IdentifyConnectionPropertiesPayload,
+    PresenceUpdatePayload,
+    Activity,
+    ActivityType,
+    Status,
+    GuildRequestPayload
 };
 
-const GATEWAY_URL: &'static str = "wss://gateway.discord.gg";
+const GATEWAY_URL: &str = "wss://gateway.discord.gg";
+/// Closing with a non-1000/1001 code keeps the session resumable.
+const RESUMABLE_CLOSE_CODE: u16 = 4000;
+/// The gateway allows 120 commands per minute on a connection. Member
+/// requests may use this many of them; the rest are left for heartbeats and
+/// presence updates.
+const MEMBER_REQUEST_LIMIT: usize = 100;
+const COMMAND_LIMIT_WINDOW: Duration = Duration::from_secs(60);
 
 #[derive(PartialEq)]
 enum GatewayState {
     New,
     Connected,
+    Resuming,
     Flushing,
     InvalidSession,
 }
 
+/// `gateway` section of the config
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct GatewayConfig {
+    #[serde(default)]
+    pub intents: IntentsConfig,
+    /// Number of shards to run. Defaults to what Discord recommends.
+    pub shards: Option<u32>,
+    /// Use zlib-stream transport compression
+    #[serde(default)]
+    pub compress: bool,
+    /// Payload encoding to ask the gateway for
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Presence to identify with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<PresenceUpdatePayload>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// Erlang External Term Format
+    Etf
+}
+impl Encoding {
+    fn query(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Etf => "etf"
+        }
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<serde_json::Value, GatewayError> {
+        match self {
+            Encoding::Json => de::from_slice::<serde_json::Value>(data)
+                .map_err(|e| GatewayError::Decode(e.to_string())),
+            Encoding::Etf => etf::decode(data)
+                .map_err(GatewayError::Decode)
+        }
+    }
+
+    fn encode(&self, command: &GatewayCommand) -> Result<Message, GatewayError> {
+        match self {
+            Encoding::Json => ser::to_string(command)
+                .map(Message::Text)
+                .map_err(|e| GatewayError::Encode(e.to_string())),
+            Encoding::Etf => serde_json::to_value(command)
+                .map(|value| Message::Binary(etf::encode(&value)))
+                .map_err(|e| GatewayError::Encode(e.to_string()))
+        }
+    }
+}
+
+/// Turns a websocket frame into the bytes of a gateway payload. Returns None for
+/// frames that do not complete a payload (control frames, partial zlib data).
+fn read_frame(msg: Message, zlib_stream: &mut Option<ZlibStream>) -> Result<Option<Vec<u8>>, GatewayError> {
+    match msg {
+        Message::Text(text) => Ok(Some(text.into_bytes())),
+        Message::Binary(data) => match zlib_stream {
+            Some(zlib_stream) => zlib_stream.push(&data),
+            None => Ok(Some(data))
+        },
+        Message::Close(frame) => {
+            let (code, reason) = frame
+                .map(|frame| (frame.code.into(), frame.reason.into_owned()))
+                .unwrap_or((0, String::new()));
+            Err(GatewayError::Closed(code, reason))
+        },
+        _ => Ok(None)
+    }
+}
+
+/// Parses a gateway payload. Its sequence number is recorded for heartbeats
+/// even if the rest of it doesn't parse, since the gateway counts it as sent.
+fn parse_message(value: serde_json::Value, heartbeat_state: &Mutex<HeartbeatState>) -> Result<GatewayMessage, serde_json::Error> {
+    if let Some(seq_num) = value.get("s").and_then(serde_json::Value::as_u64) {
+        heartbeat_state.lock().unwrap().seq_num = Some(seq_num);
+    }
+    serde_json::from_value::<GatewayMessage>(value)
+}
+
+/// Shared between the client and its heartbeat thread.
+struct HeartbeatState {
+    /// Last sequence number received; sent along with every heartbeat
+    seq_num: Option<u64>,
+    /// When the last heartbeat was sent
+    last_sent: Option<Instant>,
+    /// Whether the last heartbeat was acknowledged by the gateway
+    acked: bool,
+    /// Round trip time between the last heartbeat and its ACK
+    latency: Option<Duration>
+}
+impl HeartbeatState {
+    fn new() -> Self {
+        HeartbeatState {
+            seq_num: None,
+            last_sent: None,
+            acked: true,
+            latency: None
+        }
+    }
+
+    /// Records that a heartbeat is about to be sent and returns the command for it.
+    fn beat(&mut self) -> GatewayCommand {
+        self.acked = false;
+        self.last_sent = Some(Instant::now());
+        GatewayCommand {
+            op: GatewayOpcode::Heartbeat,
+            d: GatewayCommandType::Heartbeat(self.seq_num),
+        }
+    }
+
+    fn ack(&mut self) {
+        self.acked = true;
+        if let Some(last_sent) = self.last_sent {
+            self.latency = Some(last_sent.elapsed());
+        }
+    }
+}
+
 
 pub struct GatewayClient {
     token: String,
+    /// https://discord.com/developers/docs/topics/gateway#gateway-intents
+    intents: u32,
+    /// (shard_id, num_shards)
+    shard: Option<(u32, u32)>,
+    identify_limiter: Option<IdentifyLimiter>,
+    /// Whether to ask for zlib-stream transport compression
+    compress: bool,
+    encoding: Encoding,
+    /// Sent when identifying, and kept up to date by `set_presence`
+    presence: PresenceUpdatePayload,
     session_id: Option<String>,
+    /// Where to reconnect to when resuming, from READY
+    resume_gateway_url: Option<String>,
     seq_num: Option<u64>,
-    gateway_message_rx: Receiver<GatewayMessage>,
+    gateway_message_rx: Receiver<Result<GatewayMessage, GatewayError>>,
     gateway_message_tx: Sender<GatewayCommand>,
     heartbeat_exit_tx: Sender<bool>,
+    heartbeat_state: Arc<Mutex<HeartbeatState>>,
+    /// Lets the heartbeat thread push an error to `next` when the
+    /// connection stops acknowledging heartbeats.
+    local_message_tx: Sender<Result<GatewayMessage, GatewayError>>,
     state: GatewayState,
-    heartbeat_thread: Option<JoinHandle<()>>
+    heartbeat_thread: Option<JoinHandle<()>>,
+    /// Commands from outside the task driving this client. Unlike the
+    /// channels above, these live across reconnects.
+    command_tx: Sender<ClientCommand>,
+    command_rx: Receiver<ClientCommand>,
+    /// Request Guild Members responses being put back together, by nonce
+    member_requests: HashMap<String, MemberRequest>,
+    /// Member requests waiting for room under `MEMBER_REQUEST_LIMIT`
+    queued_member_requests: VecDeque<(GuildRequestPayload, Option<oneshot::Sender<Vec<discord::Member>>>)>,
+    /// When the member requests in the current window were sent, oldest first
+    member_requests_sent: VecDeque<Instant>,
+    /// For generating Request Guild Members nonces
+    nonce_count: u64
+}
+
+/// Requests that can be made of a `GatewayClient` while something else is
+/// busy calling `next` on it. See `GatewayClient::commands`.
+#[derive(Debug)]
+pub enum ClientCommand {
+    SetPresence(PresenceUpdatePayload),
+    /// Without a sender, the members only come back as GUILD_MEMBERS_CHUNK
+    /// events from `next`
+    RequestGuildMembers(GuildRequestPayload, Option<oneshot::Sender<Vec<discord::Member>>>)
+}
+
+/// The members from the chunks received so far for one request
+struct MemberRequest {
+    members: Vec<discord::Member>,
+    done_tx: oneshot::Sender<Vec<discord::Member>>
+}
+
+/// What `next` woke up for
+#[allow(clippy::large_enum_variant)]
+enum Received {
+    Message(Option<Result<GatewayMessage, GatewayError>>),
+    Command(ClientCommand),
+    /// There is room to send queued member requests
+    MemberRequestSlot
 }
 
 impl GatewayClient {
 
     pub fn new(token: String) -> Self {
-        let (_, rx) = channel::<GatewayMessage>(1);
+        let (local_message_tx, rx) = channel::<Result<GatewayMessage, GatewayError>>(1);
         let (tx, _) = channel::<GatewayCommand>(1);
         let (heartbeat_exit_tx, _) = channel::<bool>(1);
+        let (command_tx, command_rx) = channel::<ClientCommand>(1 << 4);
         GatewayClient {
             token,
+            intents: intents::to_bits(&[
+                Intent::GUILDS,
+                Intent::GUILD_MESSAGES,
+                Intent::GUILD_MESSAGE_REACTIONS
+            ]),
+            shard: None,
+            identify_limiter: None,
+            compress: false,
+            encoding: Encoding::Json,
+            presence: PresenceUpdatePayload::default(),
             state: GatewayState::New,
             session_id: None,
+            resume_gateway_url: None,
             seq_num: None,
             gateway_message_rx: rx,
             gateway_message_tx: tx,
             heartbeat_exit_tx,
-            heartbeat_thread: None
+            heartbeat_state: Arc::new(Mutex::new(HeartbeatState::new())),
+            local_message_tx,
+            heartbeat_thread: None,
+            command_tx,
+            command_rx,
+            member_requests: HashMap::new(),
+            queued_member_requests: VecDeque::new(),
+            member_requests_sent: VecDeque::new(),
+            nonce_count: 0
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Sets the intents sent when identifying
+    pub fn intents(mut self, intents: u32) -> Self {
+        self.intents = intents;
+        self
+    }
+
+    /// Identifies as shard `shard_id` of `num_shards`
+    pub fn shard(mut self, shard_id: u32, num_shards: u32) -> Self {
+        self.shard = Some((shard_id, num_shards));
+        self
+    }
+
+    /// Shares identify rate limiting with other shards
+    pub fn identify_limiter(mut self, limiter: IdentifyLimiter) -> Self {
+        self.identify_limiter = Some(limiter);
+        self
+    }
+
+    /// Asks the gateway for zlib-stream transport compression
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Sets the payload encoding used on the connection
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the presence sent when identifying
+    pub fn presence(mut self, presence: PresenceUpdatePayload) -> Self {
+        self.presence = presence;
+        self
+    }
+
+    /// A sender for `ClientCommand`s. They are picked up the next time
+    /// `next` is called.
+    pub fn commands(&self) -> Sender<ClientCommand> {
+        self.command_tx.clone()
+    }
+
+    pub async fn start(&mut self) -> Result<(), GatewayError> {
+        let encoding = self.encoding;
+        let mut url = format!(
+            "{}/?v={}&encoding={}",
+            self.resume_gateway_url.as_deref().filter(|_| self.can_resume()).unwrap_or(GATEWAY_URL),
+            discord::api_version(),
+            encoding.query()
+        );
+        if self.compress {
+            url.push_str("&compress=zlib-stream");
+        }
         let (socket, response) = connect_async(
-            Url::parse(format!("{}/?v=6&encoding=json", GATEWAY_URL).as_str()).unwrap().into_string()
-        ).await.expect("Could not connect to gateway");
+            Url::parse(url.as_str()).unwrap().into_string()
+        ).await.map_err(GatewayError::Connect)?;
 
         debug!("Connected to gateway server");
         debug!("Response code: {}", response.status());
+        // One inflate context for the lifetime of the connection
+        let mut zlib_stream = if self.compress {
+            Some(ZlibStream::new())
+        } else {
+            None
+        };
         // We should receive a Hello payload telling us how often to heartbeat.
         let (mut ws_tx, mut ws_rx) = socket.split();
-        let heartbeat_interval = if let Some(msg) = ws_rx.next().await {
-            let msg = msg.unwrap();
-            if msg.is_text() {
-                let text = msg.to_text().unwrap();
-                debug!("{}", text);
-                match de::from_str::<HelloMessage>(text) {
-                    Ok(payload) => {
-                        payload.d.heartbeat_interval
-                    },
-                    Err(err) => {
-                        panic!("Bad response from Gateway: {}", err)
-                    }
-                }
-
-            } else {
-                panic!("Bad response from Gateway")
-            }
-        } else {
-            panic!("Bad response from Gateway")
+        let heartbeat_interval = loop {
+            let data = match ws_rx.next().await {
+                Some(Ok(msg)) => match read_frame(msg, &mut zlib_stream) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => continue,
+                    Err(e) => return Err(GatewayError::BadHello(e.to_string()))
+                },
+                Some(Err(e)) => return Err(GatewayError::WebSocket(e)),
+                None => return Err(GatewayError::BadHello(String::from("connection closed before Hello")))
+            };
+            let value = encoding.decode(&data)
+                .map_err(|e| GatewayError::BadHello(e.to_string()))?;
+            debug!("{}", value);
+            break serde_json::from_value::<HelloMessage>(value)
+                .map_err(|e| GatewayError::BadHello(e.to_string()))?
+                .d.heartbeat_interval
         };
 
         // Check for messages from the gateway
-        let (mut from_local_to_gateway_tx, gateway_message_rx) = channel::<GatewayMessage>(1 << 8);
+        let (mut from_local_to_gateway_tx, gateway_message_rx) = channel::<Result<GatewayMessage, GatewayError>>(1 << 8);
+        let local_message_tx = from_local_to_gateway_tx.clone();
+        let (gateway_message_tx, mut from_local_to_gateway_rx) = channel::<GatewayCommand>(1 << 8);
+        let mut heartbeat_tx = gateway_message_tx.clone();
+        let heartbeat_state = self.heartbeat_state.clone();
         tokio::spawn(async move {
             loop {
-                if let Some(msg) = ws_rx.next().await {
-                    if let Err(e) = msg {
-                        debug!("Error from websocket: {}", e);
+                let msg = match ws_rx.next().await {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
                         error!("Could not receive message from websocket. Killing recv thread");
-                        from_local_to_gateway_tx.send(GatewayMessage {
-                            op: GatewayOpcode::Reconnect,
-                            d: Some(GatewayMessageType::Reconnect(())),
-                            s: None,
-                            t: None
-                        }).await.map_err(|_| {
-                            panic!("Could not send reconnect message.");
-                        });
+                        from_local_to_gateway_tx.send(Err(GatewayError::WebSocket(e))).await.ok();
+                        return;
+                    },
+                    None => {
+                        debug!("Websocket stream ended. Killing recv thread");
+                        from_local_to_gateway_tx.send(Err(GatewayError::ChannelClosed)).await.ok();
+                        return;
+                    }
+                };
+                let data = match read_frame(msg, &mut zlib_stream) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        from_local_to_gateway_tx.send(Err(e)).await.ok();
                         return;
                     }
-                    let text = msg.unwrap().into_text().unwrap();
-                    debug!("{}", text);
-                    let msg = de::from_str::<GatewayMessage>(text.as_str());
-                    if let Ok(msg) = msg {
+                };
+                let value = match encoding.decode(&data) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        warn!("Skipping undecodable gateway message: {}", err);
+                        continue
+                    }
+                };
+                debug!("{}", value);
+                match parse_message(value, &heartbeat_state) {
+                    Ok(msg) => {
+                        // Heartbeats are handled here rather than in `next`, so
+                        // they keep flowing while the consumer is busy
+                        match msg.d {
+                            Some(message::GatewayMessageType::HeartbeatAck(_)) => {
+                                let mut heartbeat_state = heartbeat_state.lock().unwrap();
+                                heartbeat_state.ack();
+                                debug!("Heartbeat acknowledged in {:?}", heartbeat_state.latency.unwrap_or_default());
+                                continue
+                            },
+                            Some(message::GatewayMessageType::Heartbeat(_)) => {
+                                // The gateway may ask for a heartbeat outside of our interval
+                                let heartbeat = heartbeat_state.lock().unwrap().beat();
+                                if let Err(e) = heartbeat_tx.send(heartbeat).await {
+                                    error!("Could not answer heartbeat request: {}", e);
+                                }
+                                continue
+                            },
+                            _ => {}
+                        }
                         let op = msg.op.clone();
-                        debug!("Hi {:?}", op);
-                        if let Err(err) = from_local_to_gateway_tx.send(msg).await {
+                        if let Err(err) = from_local_to_gateway_tx.send(Ok(msg)).await {
                             error!("Unable to communicate message from gateway: {}", err);
                         };
                         // Check if this is a Reconnecting message; we'll kill if so.
@@ -131,46 +441,47 @@ impl GatewayClient {
                             debug!("Closing gateway->local channel");
                             return;
                         }
-                    } else {
-                        debug!("Bad gateway message. Sending reconnect message");
-                        from_local_to_gateway_tx.send(GatewayMessage {
-                            op: GatewayOpcode::Reconnect,
-                            d: Some(GatewayMessageType::Reconnect(())),
-                            s: None,
-                            t: None
-                        }).await.map_err(|_| {
-                            panic!("Could not send reconnect message.");
-                        });
-                        return;
+                    },
+                    Err(err) => {
+                        // One bad payload is not worth dropping the connection over
+                        warn!("Skipping malformed gateway message: {}", err);
                     }
                 }
             }
         });
 
         // Send messages to the gateway
-        let (gateway_message_tx, mut from_local_to_gateway_rx) = channel::<GatewayCommand>(1 << 8);
         tokio::spawn(async move {
-            loop {
-                if let Some(msg) = from_local_to_gateway_rx.next().await {
-                    // Check if this is a Reconnecting message; we'll kill if so.
-                    match msg.d {
-                        GatewayCommandType::Reconnecting(_) => {
-                            debug!("Closing local->gateway channel");
-                            return;
-                        },
-                        _ => {}
+            while let Some(msg) = from_local_to_gateway_rx.next().await {
+                // Check if this is a Reconnecting message; we'll kill if so.
+                if let GatewayCommandType::Reconnecting(_) = msg.d {
+                    debug!("Closing local->gateway channel");
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CloseCode::from(RESUMABLE_CLOSE_CODE),
+                        reason: "Reconnecting".into()
+                    }));
+                    if let Err(e) = ws_tx.send(close).await {
+                        debug!("Could not close websocket: {}", e);
                     }
+                    return;
+                }
 
-                    debug!("Got some {:?}: {:?}", &msg.op, serde_json::ser::to_string(&msg));
-                    match ws_tx.send(serde_json::to_string(&msg).unwrap().into()).await {
-                        Ok(_) => {
-                            debug!("Sent!");
-                        },
-                        Err(e) => {
-                            error!("Got error sending to gateway: {}", e);
-                            error!("Killing send thread");
-                            return;
-                        }
+                debug!("Got some {:?}: {:?}", &msg.op, serde_json::ser::to_string(&msg));
+                let frame = match encoding.encode(&msg) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        error!("Dropping {:?} command: {}", &msg.op, e);
+                        continue;
+                    }
+                };
+                match ws_tx.send(frame).await {
+                    Ok(_) => {
+                        debug!("Sent!");
+                    },
+                    Err(e) => {
+                        error!("Got error sending to gateway: {}", e);
+                        error!("Killing send thread");
+                        return;
                     }
                 }
             }
@@ -178,25 +489,56 @@ impl GatewayClient {
 
         self.gateway_message_rx = gateway_message_rx;
         self.gateway_message_tx = gateway_message_tx;
+        self.local_message_tx = local_message_tx;
         self.heartbeat_exit_tx = self.start_heartbeat(heartbeat_interval);
-        if let Err(msg) = self.identify().await {
-            panic!("Could not identify self; {}", msg);
-        };
-        self.state = GatewayState::Connected;
+        if self.can_resume() {
+            info!("Resuming session {}", self.session_id.as_ref().unwrap());
+            self.attempt_resume().await
+                .map_err(|e| GatewayError::Handshake(e.to_string()))?;
+        } else {
+            self.identify().await
+                .map_err(|e| GatewayError::Handshake(e.to_string()))?;
+            self.state = GatewayState::Connected;
+        }
 
         Ok(())
     }
 
+    /// Whether we have enough state from a previous connection to send a Resume
+    /// instead of a fresh Identify.
+    pub fn can_resume(&self) -> bool {
+        self.session_id.is_some() && self.seq_num.is_some()
+    }
+
     /// Handles internal state updating.
     /// E.g. updating session IDs, reconnecting if we had a force disconnect
     async fn preprocess_gateway_message(&mut self, msg: &GatewayMessage) {
         if let Some(payload) = msg.d.as_ref() {
             match payload {
+                message::GatewayMessageType::GuildMembersChunk(chunk) => {
+                    self.collect_member_chunk(chunk);
+                },
                 message::GatewayMessageType::Ready(ready_msg) => {
                     self.session_id = Some(ready_msg.session_id.clone());
+                    self.resume_gateway_url = ready_msg.resume_gateway_url.clone();
+                    self.state = GatewayState::Connected;
+                },
+                message::GatewayMessageType::Resumed(_) => {
+                    // Discord replays every dispatch we missed before sending
+                    // RESUMED; they come through `next` like any other event.
+                    info!("Resumed session");
+                    self.state = GatewayState::Connected;
+                    // `set_presence` only stores the presence while we are
+                    // disconnected, and a Resume doesn't carry one.
+                    if let Err(e) = self.set_presence(self.presence.clone()).await {
+                        error!("Could not restore presence after resuming: {}", e);
+                    }
+                },
+                message::GatewayMessageType::InvalidSession(resumable) => {
+                    self.handle_invalid_session(*resumable).await;
                 },
                 message::GatewayMessageType::Reconnect(_) => {
-                    self.reconnect().await.unwrap();
+                    self.reconnect().await;
                 },
                 _ => {
                     // Pass it along 
@@ -208,26 +550,169 @@ impl GatewayClient {
         }
     }
 
+    /// Round trip time between the last heartbeat and its ACK.
+    pub fn latency(&self) -> Option<Duration> {
+        self.heartbeat_state.lock().unwrap().latency
+    }
+
     pub async fn stop_heartbeat(&mut self) {
         match self.heartbeat_exit_tx.send(true).await {
             Ok(_) => {
                 info!("Stopped heartbeat");
             },
-            Err(e) => {
-                error!("Could not stop heartbeat D: {:?}", e);
+            Err(_) => {
+                // e.g. it gave up on a zombie connection
+                debug!("Heartbeat already stopped");
             }
         }
     }
 
-    pub async fn next(&mut self) -> Option<GatewayMessage> {
-        if let Some(msg) = self.gateway_message_rx.next().await {
-            self.preprocess_gateway_message(&msg).await;
-            Some(msg)
-        } else {
-            None
+    /// Waits for the next message from the gateway. After an error the
+    /// connection is closed and `start` has to be called again.
+    pub async fn next(&mut self) -> Result<GatewayMessage, GatewayError> {
+        let received = loop {
+            let slot = self.member_request_wait();
+            let received = tokio::select! {
+                received = self.gateway_message_rx.recv() => Received::Message(received),
+                Some(command) = self.command_rx.recv() => Received::Command(command),
+                _ = delay_for(slot.unwrap_or_default()), if slot.is_some() => Received::MemberRequestSlot
+            };
+            match received {
+                Received::Message(received) => break received,
+                Received::Command(command) => self.run_command(command).await,
+                Received::MemberRequestSlot => self.send_queued_member_requests().await
+            }
+        };
+        match received {
+            Some(Ok(msg)) => {
+                self.preprocess_gateway_message(&msg).await;
+                Ok(msg)
+            },
+            Some(Err(e)) => {
+                self.reconnect().await;
+                Err(e)
+            },
+            None => {
+                self.reconnect().await;
+                Err(GatewayError::ChannelClosed)
+            }
+        }
+    }
+
+    async fn run_command(&mut self, command: ClientCommand) {
+        match command {
+            ClientCommand::SetPresence(presence) => {
+                if let Err(e) = self.set_presence(presence).await {
+                    error!("Could not update presence: {}", e);
+                }
+            },
+            ClientCommand::RequestGuildMembers(request, done_tx) => {
+                self.queued_member_requests.push_back((request, done_tx));
+                self.send_queued_member_requests().await;
+            }
+        }
+    }
+
+    /// Asks for the members of a guild over the gateway. The returned
+    /// receiver resolves once every GUILD_MEMBERS_CHUNK for the request has
+    /// arrived, which only happens while something keeps calling `next`.
+    /// Requests are queued while disconnected or over `MEMBER_REQUEST_LIMIT`.
+    ///
+    /// Requesting every member (an empty `query` and a `limit` of 0) needs
+    /// the GUILD_MEMBERS intent.
+    pub async fn request_guild_members(&mut self, request: GuildRequestPayload) -> oneshot::Receiver<Vec<discord::Member>> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.queued_member_requests.push_back((request, Some(done_tx)));
+        self.send_queued_member_requests().await;
+        done_rx
+    }
+
+    /// How long until a queued member request may be sent, if there is one
+    /// and we are connected
+    fn member_request_wait(&mut self) -> Option<Duration> {
+        if self.queued_member_requests.is_empty() || self.state != GatewayState::Connected {
+            return None
+        }
+        let now = Instant::now();
+        while self.member_requests_sent.front().is_some_and(|sent| now.duration_since(*sent) >= COMMAND_LIMIT_WINDOW) {
+            self.member_requests_sent.pop_front();
+        }
+        if self.member_requests_sent.len() < MEMBER_REQUEST_LIMIT {
+            return Some(Duration::from_secs(0))
+        }
+        self.member_requests_sent.front().map(|oldest| COMMAND_LIMIT_WINDOW - now.duration_since(*oldest))
+    }
+
+    async fn send_queued_member_requests(&mut self) {
+        while self.member_request_wait() == Some(Duration::from_secs(0)) {
+            let (request, done_tx) = match self.queued_member_requests.pop_front() {
+                Some(queued) => queued,
+                None => return
+            };
+            if let Err(e) = self.send_member_request(request, done_tx).await {
+                error!("Could not request guild members: {}", e);
+            }
+        }
+        if !self.queued_member_requests.is_empty() {
+            debug!("{} member request(s) queued", self.queued_member_requests.len());
+        }
+    }
+
+    async fn send_member_request(
+        &mut self, mut request: GuildRequestPayload, done_tx: Option<oneshot::Sender<Vec<discord::Member>>>
+    ) -> Result<(), GatewayError> {
+        self.member_requests_sent.push_back(Instant::now());
+        // Our nonce is how chunks find their way back to the request. Nobody
+        // is waiting on requests without a sender, so their chunks are not
+        // collected.
+        if let Some(done_tx) = done_tx {
+            self.nonce_count += 1;
+            let nonce = format!("{}-{}", self.shard.map(|(shard_id, _)| shard_id).unwrap_or(0), self.nonce_count);
+            request.nonce = Some(nonce.clone());
+            self.member_requests.insert(nonce, MemberRequest {
+                members: vec![],
+                done_tx
+            });
+        }
+        self.send(GatewayCommand {
+            op: GatewayOpcode::RequestGuildMembers,
+            d: GatewayCommandType::RequestGuildMembers(request)
+        }).await.map_err(|_| GatewayError::ChannelClosed)
+    }
+
+    fn collect_member_chunk(&mut self, chunk: &discord::GuildMembersChunk) {
+        let nonce = match chunk.nonce.as_ref() {
+            Some(nonce) => nonce,
+            None => return
+        };
+        let done = match self.member_requests.get_mut(nonce) {
+            Some(request) => {
+                request.members.extend(chunk.members.iter().cloned());
+                chunk.chunk_index + 1 >= chunk.chunk_count
+            },
+            None => return
+        };
+        if done {
+            if let Some(request) = self.member_requests.remove(nonce) {
+                debug!("Received {} member(s) for request {}", request.members.len(), nonce);
+                request.done_tx.send(request.members).ok();
+            }
         }
     }
 
+    /// Updates the bot's status and activity. If we are not connected yet it
+    /// will be sent when identifying.
+    pub async fn set_presence(&mut self, presence: PresenceUpdatePayload) -> Result<(), GatewayError> {
+        self.presence = presence.clone();
+        if self.state != GatewayState::Connected {
+            return Ok(())
+        }
+        self.send(GatewayCommand {
+            op: GatewayOpcode::PresenceUpdate,
+            d: GatewayCommandType::PresenceUpdate(presence)
+        }).await.map_err(|_| GatewayError::ChannelClosed)
+    }
+
     async fn send(&mut self, message: GatewayCommand) -> Result<(), tokio::sync::mpsc::error::SendError<GatewayCommand>> 
     where 
     {
@@ -235,80 +720,128 @@ impl GatewayClient {
         sender.send(message).await
     }
 
+    /// Starts a new session
     pub async fn identify(&mut self) -> Result<(), tokio::sync::mpsc::error::SendError<GatewayCommand>> {
-        let intents: u32 = 1 // GUILDS
-            //+ (1 << 8)   // GUILD_PRESENCES (privileged)
-            + (1 << 9)   // GUILD_MESSAGES
-            + (1 << 10); // GUILD_MESSAGE_REACTIONS
-
+        // Chunks for requests from the old session will never arrive. Dropping
+        // them lets whoever is waiting know.
+        self.member_requests.clear();
+        if let Some(limiter) = &self.identify_limiter {
+            limiter.wait(self.shard.map(|(shard_id, _)| shard_id).unwrap_or(0)).await;
+        }
         self.send(GatewayCommand {
             op: GatewayOpcode::Identify,
             d: GatewayCommandType::Identify(IdentifyPayload {
                 token: self.token.clone(),
-                presence: IdentifyPresencePayload {
-                    game: IdentifyPresenceGamePayload {
-                        name: String::from("GL2N"),
-                        _type: 0
-                    },
-                    afk: false,
-                    since: None,
-                    status: String::from("Got me a status")
-                },
+                presence: self.presence.clone(),
                 properties: IdentifyConnectionPropertiesPayload {
                     os: String::from("linux"),
                     browser: String::from("glennbot"),
                     device: String::from("glennbot"),
                 },
-                intents
+                intents: self.intents,
+                shard: self.shard.map(|(shard_id, num_shards)| [shard_id, num_shards])
             })
         }).await
     }
 
-    async fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Discord asks us to wait 1-5 seconds before trying again after an
+    /// Invalid Session.
+    async fn handle_invalid_session(&mut self, resumable: bool) {
+        self.state = GatewayState::InvalidSession;
+        let jitter = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_millis() as u64 * 4)
+            .unwrap_or(0);
+        delay_for(Duration::from_millis(1000 + jitter)).await;
+
+        if resumable && self.can_resume() {
+            warn!("Session invalidated, attempting to resume");
+            if let Err(e) = self.attempt_resume().await {
+                error!("Could not resume session: {}", e);
+            }
+        } else {
+            warn!("Session invalidated, identifying from scratch");
+            self.session_id = None;
+            self.resume_gateway_url = None;
+            self.seq_num = None;
+            self.heartbeat_state.lock().unwrap().seq_num = None;
+            if let Err(e) = self.identify().await {
+                error!("Could not identify: {}", e);
+            } else {
+                self.state = GatewayState::Connected;
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) {
         self.state = GatewayState::Flushing;
         debug!("Got reconnect signal...");
-        self.send(GatewayCommand {
+        let reconnecting = GatewayCommand {
             op: GatewayOpcode::Reconnect,
             d: GatewayCommandType::Reconnecting(())
-        }).await.unwrap();
-        Ok(())
+        };
+        if self.send(reconnecting).await.is_err() {
+            debug!("Send thread already stopped");
+        }
     }
 
-    pub fn attempt_resume(&mut self) -> Result<(), Box<dyn Error>> {
-        self.gateway_message_tx.send(GatewayCommand {
+    pub async fn attempt_resume(&mut self) -> Result<(), tokio::sync::mpsc::error::SendError<GatewayCommand>> {
+        self.state = GatewayState::Resuming;
+        self.send(GatewayCommand {
             op: GatewayOpcode::Resume,
             d: GatewayCommandType::Resume(message::ResumePayload {
                 token: self.token.clone(),
                 session_id: self.session_id.clone().unwrap(),
                 seq: self.seq_num.unwrap()
             })
-        });
-
-        Ok(())
+        }).await
     }
 
     pub fn start_heartbeat(&mut self, heartbeat_interval: u64) -> Sender<bool> {
         debug!("Starting heartbeat thread at {} ms interval", heartbeat_interval);
         let (exit_tx, mut exit_rx) = channel::<bool>(16);
         let mut gateway_message_tx = self.gateway_message_tx.clone();
+        let mut local_message_tx = self.local_message_tx.clone();
+        let heartbeat_state = self.heartbeat_state.clone();
+        {
+            // A fresh connection has nothing outstanding
+            let mut heartbeat_state = heartbeat_state.lock().unwrap();
+            heartbeat_state.acked = true;
+            heartbeat_state.last_sent = None;
+        }
+        // Discord wants the first heartbeat a random fraction of the way into
+        // the interval, so shards that reconnect together don't beat together
+        let first_beat = first_heartbeat_delay(heartbeat_interval, SystemTime::now());
         let heartbeat_thread = tokio::spawn(async move {
+            delay_for(first_beat).await;
             loop {
                 if let Ok(should_exit) = exit_rx.try_recv() {
                     if should_exit {
                         return;
                     }
                 };
-                let heartbeat = GatewayCommand {
-                    op: GatewayOpcode::Heartbeat,
-                    d: GatewayCommandType::Heartbeat(()),
+                let heartbeat = {
+                    let mut heartbeat_state = heartbeat_state.lock().unwrap();
+                    if heartbeat_state.acked {
+                        Some(heartbeat_state.beat())
+                    } else {
+                        None
+                    }
+                };
+                let heartbeat = match heartbeat {
+                    Some(heartbeat) => heartbeat,
+                    None => {
+                        warn!("No heartbeat ACK within {} ms; connection is a zombie. Reconnecting", heartbeat_interval);
+                        local_message_tx.send(Err(GatewayError::Zombie)).await.ok();
+                        return;
+                    }
                 };
-                if let Ok(_) = gateway_message_tx.send(heartbeat).await {
+                if gateway_message_tx.send(heartbeat).await.is_ok() {
                     debug!("Sent heartbeat");
                 } else {
                     debug!("Failed to send heartbeat. Stopping this thread");
                     return;
                 }
-                delay_for(Duration::from_millis(heartbeat_interval.into())).await;
+                delay_for(Duration::from_millis(heartbeat_interval)).await;
             }
         });
         self.heartbeat_thread = Some(heartbeat_thread);
@@ -317,3 +850,129 @@ impl GatewayClient {
 }
 
 
+
+/// `heartbeat_interval * jitter`, with the jitter taken from the clock
+fn first_heartbeat_delay(heartbeat_interval: u64, now: SystemTime) -> Duration {
+    let jitter = now.duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() as f64 / 1_000_000_000.0)
+        .unwrap_or(0.0);
+    Duration::from_millis((heartbeat_interval as f64 * jitter) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands_are_encoded() {
+        let heartbeat = GatewayCommand {
+            op: GatewayOpcode::Heartbeat,
+            d: GatewayCommandType::Heartbeat(Some(70000))
+        };
+        match Encoding::Json.encode(&heartbeat) {
+            Ok(Message::Text(text)) => assert_eq!(text, r#"{"d":70000,"op":1}"#),
+            _ => panic!("Encoded incorrectly")
+        }
+        match Encoding::Etf.encode(&heartbeat) {
+            Ok(Message::Binary(data)) => assert_eq!(etf::decode(&data).unwrap(), serde_json::json!({"op": 1, "d": 70000})),
+            _ => panic!("Encoded incorrectly")
+        }
+    }
+
+    #[test]
+    fn malformed_dispatches_still_count() {
+        let heartbeat_state = Mutex::new(HeartbeatState::new());
+        let malformed = serde_json::json!({"op": 0, "t": "MESSAGE_CREATE", "s": 42, "d": {"id": 1}});
+        assert!(parse_message(malformed, &heartbeat_state).is_err());
+        assert_eq!(heartbeat_state.lock().unwrap().seq_num, Some(42));
+
+        let ack = serde_json::json!({"op": 11, "s": null, "t": null, "d": null});
+        assert!(parse_message(ack, &heartbeat_state).is_ok());
+        assert_eq!(heartbeat_state.lock().unwrap().seq_num, Some(42));
+    }
+
+    #[test]
+    fn first_heartbeat_is_jittered() {
+        let at = |nanos: u64| UNIX_EPOCH + Duration::from_secs(1_600_000_000) + Duration::from_nanos(nanos);
+        assert_eq!(first_heartbeat_delay(41250, at(0)), Duration::from_millis(0));
+        assert_eq!(first_heartbeat_delay(41250, at(500_000_000)), Duration::from_millis(20625));
+        assert!(first_heartbeat_delay(41250, at(999_999_999)) < Duration::from_millis(41250));
+    }
+
+    #[test]
+    fn member_chunks_are_collected_by_nonce() {
+        let mut gw = GatewayClient::new(String::from("token"));
+        let (done_tx, mut done_rx) = oneshot::channel();
+        gw.member_requests.insert(String::from("0-1"), MemberRequest { members: vec![], done_tx });
+
+        let mut chunk = discord::GuildMembersChunk {
+            guild_id: String::from("1"),
+            members: vec![discord::Member::default()],
+            chunk_index: 0,
+            chunk_count: 2,
+            not_found: None,
+            nonce: Some(String::from("0-1"))
+        };
+        gw.collect_member_chunk(&chunk);
+        assert!(done_rx.try_recv().is_err());
+
+        chunk.chunk_index = 1;
+        gw.collect_member_chunk(&chunk);
+        assert_eq!(done_rx.try_recv().unwrap().len(), 2);
+        assert!(gw.member_requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn member_requests_end_with_the_session() {
+        let mut gw = GatewayClient::new(String::from("token"));
+        let (done_tx, mut done_rx) = oneshot::channel();
+        gw.member_requests.insert(String::from("0-1"), MemberRequest { members: vec![], done_tx });
+        gw.identify().await.ok();
+        assert!(gw.member_requests.is_empty());
+        assert!(matches!(done_rx.try_recv(), Err(oneshot::error::TryRecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn presence_is_sent_after_resuming() {
+        let mut gw = GatewayClient::new(String::from("token"));
+        let (tx, mut rx) = channel::<GatewayCommand>(4);
+        gw.gateway_message_tx = tx;
+        gw.state = GatewayState::Resuming;
+
+        let presence = PresenceUpdatePayload {
+            afk: true,
+            ..Default::default()
+        };
+        gw.set_presence(presence).await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        gw.preprocess_gateway_message(&GatewayMessage {
+            op: GatewayOpcode::Dispatch,
+            d: Some(message::GatewayMessageType::Resumed(())),
+            s: None,
+            t: Some(String::from("RESUMED"))
+        }).await;
+        assert!(gw.state == GatewayState::Connected);
+        match rx.try_recv() {
+            Ok(GatewayCommand { d: GatewayCommandType::PresenceUpdate(sent), .. }) => assert!(sent.afk),
+            _ => panic!("presence was not sent")
+        }
+    }
+
+    #[test]
+    fn member_requests_are_paced() {
+        let mut gw = GatewayClient::new(String::from("token"));
+        gw.queued_member_requests.push_back((GuildRequestPayload::default(), None));
+        assert_eq!(gw.member_request_wait(), None);
+
+        gw.state = GatewayState::Connected;
+        assert_eq!(gw.member_request_wait(), Some(Duration::from_secs(0)));
+
+        let now = Instant::now();
+        gw.member_requests_sent.push_back(now - COMMAND_LIMIT_WINDOW);
+        gw.member_requests_sent.extend(std::iter::repeat_n(now - Duration::from_secs(50), MEMBER_REQUEST_LIMIT));
+        let wait = gw.member_request_wait().unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        assert_eq!(gw.member_requests_sent.len(), MEMBER_REQUEST_LIMIT);
+    }
+}
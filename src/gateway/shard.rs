@@ -0,0 +1,251 @@
+use log::*;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{Sender, Receiver, channel};
+use tokio::sync::oneshot;
+use tokio::time::delay_for;
+
+use crate::http::HttpClient;
+use crate::gateway::{
+    ClientCommand,
+    Encoding,
+    GatewayClient,
+    GatewayConfig,
+    GatewayError,
+    GatewayMessage,
+    GatewayMessageType,
+    GuildRequestPayload,
+    HeartbeatState,
+    PresenceUpdatePayload
+};
+use crate::discord;
+
+/// Discord allows `max_concurrency` identifies per 5 seconds.
+const IDENTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spaces out Identify payloads across shards. Shards are bucketed by
+/// `shard_id % max_concurrency`; each bucket may identify once per interval.
+#[derive(Clone)]
+pub struct IdentifyLimiter {
+    buckets: Arc<Vec<Mutex<Option<Instant>>>>
+}
+
+impl IdentifyLimiter {
+    pub fn new(max_concurrency: u32) -> Self {
+        let buckets = (0..max_concurrency.max(1)).map(|_| Mutex::new(None)).collect();
+        IdentifyLimiter {
+            buckets: Arc::new(buckets)
+        }
+    }
+
+    /// Waits until `shard_id` is allowed to identify.
+    pub async fn wait(&self, shard_id: u32) {
+        let bucket = &self.buckets[shard_id as usize % self.buckets.len()];
+        let mut last_identify = bucket.lock().await;
+        if let Some(last) = *last_identify {
+            let elapsed = last.elapsed();
+            if elapsed < IDENTIFY_INTERVAL {
+                debug!("[shard {}] Waiting {:?} to identify", shard_id, IDENTIFY_INTERVAL - elapsed);
+                delay_for(IDENTIFY_INTERVAL - elapsed).await;
+            }
+        }
+        *last_identify = Some(Instant::now());
+    }
+}
+
+/// Runs one `GatewayClient` per shard and funnels all of their events into a
+/// single channel.
+pub struct ShardManager {
+    token: String,
+    intents: u32,
+    shard_count: u32,
+    compress: bool,
+    encoding: Encoding,
+    presence: PresenceUpdatePayload,
+    limiter: IdentifyLimiter
+}
+
+impl ShardManager {
+    /// Uses `gateway.shards` from the config if set, otherwise asks Discord
+    /// for the recommended shard count.
+    pub async fn new(token: String, intents: u32, config: &GatewayConfig, http_client: &HttpClient) -> Self {
+        let (recommended, max_concurrency) = match http_client.get_gateway_bot().await {
+            Ok(gateway_bot) => {
+                info!(
+                    "Gateway recommends {} shard(s); {} session starts remaining",
+                    gateway_bot.shards, gateway_bot.session_start_limit.remaining
+                );
+                (gateway_bot.shards, gateway_bot.session_start_limit.max_concurrency.unwrap_or(1))
+            },
+            Err(e) => {
+                warn!("Could not get recommended shard count: {}", e);
+                (1, 1)
+            }
+        };
+        let shard_count = config.shards.unwrap_or(recommended).max(1);
+        ShardManager {
+            token,
+            intents,
+            shard_count,
+            compress: config.compress,
+            encoding: config.encoding,
+            presence: config.presence.clone().unwrap_or_default(),
+            limiter: IdentifyLimiter::new(max_concurrency)
+        }
+    }
+
+    pub fn shard_count(&self) -> u32 {
+        self.shard_count
+    }
+
+    /// Spawns every shard. Events from all of them come out of the returned
+    /// channel; the handle sends commands to all of them.
+    pub fn start(self) -> (Receiver<GatewayMessage>, GatewayHandle) {
+        let (tx, rx) = channel::<GatewayMessage>(1 << 8);
+        let mut shards = vec![];
+        info!("Starting {} shard(s)", self.shard_count);
+        for shard_id in 0..self.shard_count {
+            let gw = GatewayClient::new(self.token.clone())
+                .intents(self.intents)
+                .shard(shard_id, self.shard_count)
+                .compress(self.compress)
+                .encoding(self.encoding)
+                .presence(self.presence.clone())
+                .identify_limiter(self.limiter.clone());
+            shards.push(ShardLink {
+                commands: gw.commands(),
+                heartbeat: gw.heartbeat_state.clone()
+            });
+            tokio::spawn(run_shard(shard_id, gw, tx.clone()));
+        }
+        (rx, GatewayHandle { shards: Arc::new(shards) })
+    }
+}
+
+/// What the handle keeps of a running shard
+struct ShardLink {
+    commands: Sender<ClientCommand>,
+    /// Shared with the shard's client, which keeps it across reconnects
+    heartbeat: Arc<StdMutex<HeartbeatState>>
+}
+
+/// Lets the rest of the bot talk to the running shards.
+#[derive(Clone, Default)]
+pub struct GatewayHandle {
+    shards: Arc<Vec<ShardLink>>
+}
+
+impl GatewayHandle {
+    /// Round trip time between the shard's last heartbeat and its ACK. None
+    /// for unknown shards and before the first ACK.
+    pub fn latency(&self, shard_id: u32) -> Option<Duration> {
+        self.shards.get(shard_id as usize)
+            .and_then(|shard| shard.heartbeat.lock().unwrap().latency)
+    }
+
+    /// Presence is per session, so this goes out on every shard.
+    pub async fn set_presence(&self, presence: PresenceUpdatePayload) -> Result<(), GatewayError> {
+        for shard in self.shards.iter() {
+            shard.commands.clone().send(ClientCommand::SetPresence(presence.clone())).await
+                .map_err(|_| GatewayError::ChannelClosed)?;
+        }
+        Ok(())
+    }
+
+    /// Requests guild members from the shard the guild lives on and waits
+    /// for all of the chunks to come in.
+    pub async fn request_guild_members(&self, request: GuildRequestPayload) -> Result<Vec<discord::Member>, GatewayError> {
+        if self.shards.is_empty() {
+            return Err(GatewayError::ChannelClosed)
+        }
+        let shard = &self.shards[shard_for_guild(&request.guild_id, self.shards.len())];
+        let (done_tx, done_rx) = oneshot::channel();
+        shard.commands.clone().send(ClientCommand::RequestGuildMembers(request, Some(done_tx))).await
+            .map_err(|_| GatewayError::ChannelClosed)?;
+        done_rx.await.map_err(|_| GatewayError::ChannelClosed)
+    }
+
+    /// Asks for every member of a guild without waiting for them. The
+    /// GUILD_MEMBERS_CHUNK events come out of the event channel.
+    pub async fn fetch_guild_members(&self, guild_id: String) -> Result<(), GatewayError> {
+        if self.shards.is_empty() {
+            return Err(GatewayError::ChannelClosed)
+        }
+        let shard = &self.shards[shard_for_guild(&guild_id, self.shards.len())];
+        let request = GuildRequestPayload {
+            guild_id,
+            query: Some(String::new()),
+            limit: 0,
+            ..Default::default()
+        };
+        shard.commands.clone().send(ClientCommand::RequestGuildMembers(request, None)).await
+            .map_err(|_| GatewayError::ChannelClosed)
+    }
+}
+
+/// https://discord.com/developers/docs/topics/gateway#sharding-sharding-formula
+fn shard_for_guild(guild_id: &str, shard_count: usize) -> usize {
+    let guild_id = guild_id.parse::<u64>().unwrap_or(0);
+    ((guild_id >> 22) % shard_count.max(1) as u64) as usize
+}
+
+/// Keeps a shard connected for as long as someone is listening for its events.
+/// The client is kept across reconnects so that it can resume the session
+/// instead of identifying from scratch.
+async fn run_shard(shard_id: u32, mut gw: GatewayClient, mut tx: Sender<GatewayMessage>) {
+    loop {
+        match gw.start().await {
+            Ok(_) => {}
+            Err(e) => {
+                error!("[shard {}] Could not start :( {}. Trying again...", shard_id, e);
+                gw.stop_heartbeat().await;
+                delay_for(Duration::from_secs(5)).await;
+                continue
+            }
+        }
+        info!("[shard {}] Connected to gateway", shard_id);
+        loop {
+            let msg = match gw.next().await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("[shard {}] Lost gateway connection: {}. Reconnecting...", shard_id, e);
+                    gw.stop_heartbeat().await;
+                    break;
+                }
+            };
+            if let Some(GatewayMessageType::Reconnect(_)) = msg.d {
+                gw.stop_heartbeat().await;
+                break;
+            }
+            if tx.send(msg).await.is_err() {
+                info!("[shard {}] Nobody is listening for events. Stopping", shard_id);
+                gw.stop_heartbeat().await;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guilds_map_to_shards() {
+        assert_eq!(shard_for_guild("368933402751008771", 1), 0);
+        assert_eq!(shard_for_guild("368933402751008771", 4), (368933402751008771u64 >> 22) as usize % 4);
+        assert_eq!(shard_for_guild("not a snowflake", 4), 0);
+    }
+
+    #[test]
+    fn latency_per_shard() {
+        let gw = GatewayClient::new(String::from("token"));
+        gw.heartbeat_state.lock().unwrap().latency = Some(Duration::from_millis(42));
+        let handle = GatewayHandle {
+            shards: Arc::new(vec![ShardLink { commands: gw.commands(), heartbeat: gw.heartbeat_state.clone() }])
+        };
+        assert_eq!(handle.latency(0), Some(Duration::from_millis(42)));
+        assert_eq!(handle.latency(1), None);
+    }
+}
@@ -1,6 +1,4 @@
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read};
+use serde::Serialize;
 use serde::de::{DeserializeOwned};
 use log::*;
 use std::sync::Arc;
@@ -11,7 +9,7 @@ use reqwest::multipart::{Part, Form};
 
 use crate::discord;
 
-const BASE: &'static str = "https://discord.com/api/v7";
+const BASE: &str = "https://discord.com/api";
 
 pub struct Route {
     path: &'static str,
@@ -36,7 +34,7 @@ pub struct RouteBuilder {
     inner: RouteInner
 }
 impl Route {
-    pub fn new() -> RouteBuilder {
+    pub fn builder() -> RouteBuilder {
         RouteBuilder {
             inner: RouteInner {
                 path: None,
@@ -86,13 +84,13 @@ impl RouteBuilder {
         self
     }
     pub fn build(self) -> Route {
-        if let None = self.inner.method {
+        if self.inner.method.is_none() {
             panic!("Must provide .method() to builder")
         }
-        if let None = self.inner.path {
+        if self.inner.path.is_none() {
             panic!("Must provide .path() to builder")
         }
-        let path = self.inner.path.clone().unwrap();
+        let path = self.inner.path.unwrap();
         let method = self.inner.method.clone().unwrap();
         Route {
             meta: Box::new(self.inner),
@@ -102,28 +100,29 @@ impl RouteBuilder {
     }
 }
 
-impl Into<Url> for Route {
-    fn into(self) -> Url {
-        let mut before_subst = String::from(format!("{}{}", 
+impl From<Route> for Url {
+    fn from(route: Route) -> Url {
+        let mut before_subst = format!("{}/v{}{}",
             BASE,
-            self.path
-        ));
-        if let Some(guild_id) = self.meta.guild_id {
+            discord::api_version(),
+            route.path
+        );
+        if let Some(guild_id) = route.meta.guild_id {
             before_subst = before_subst.replace("{guild_id}", guild_id.as_str());
         }
-        if let Some(channel_id) = self.meta.channel_id {
+        if let Some(channel_id) = route.meta.channel_id {
             before_subst = before_subst.replace("{channel_id}", channel_id.as_str());
         }
-        if let Some(emoji) = self.meta.emoji {
+        if let Some(emoji) = route.meta.emoji {
             before_subst = before_subst.replace("{emoji}", emoji.as_str());
         }
-        if let Some(message_id) = self.meta.message_id {
+        if let Some(message_id) = route.meta.message_id {
             before_subst = before_subst.replace("{message_id}", message_id.as_str());
         }
-        if let Some(role_id) = self.meta.role_id {
+        if let Some(role_id) = route.meta.role_id {
             before_subst = before_subst.replace("{role_id}", role_id.as_str());
         }
-        if let Some(user_id) = self.meta.user_id {
+        if let Some(user_id) = route.meta.user_id {
             before_subst = before_subst.replace("{user_id}", user_id.as_str());
         }
         let url = before_subst.as_str();
@@ -150,7 +149,6 @@ impl HttpClient {
     async fn request<P: Serialize>(&self, route: Route, payload: Option<P>) -> Result<Response, Error> {
         let mut headers = HeaderMap::new();
         headers.insert("User-Agent", HeaderValue::from_str("GlennBot").unwrap());
-        headers.insert("X-Ratelimit-Precision", HeaderValue::from_str("millisecond").unwrap());
 
         if let Some(token) = self.token.clone() {
             headers.insert(
@@ -160,7 +158,7 @@ impl HttpClient {
         }
         debug!("{:?}", headers);
 
-        if let None = payload {
+        if payload.is_none() {
             headers.insert("Content-Length", HeaderValue::from_str("0").unwrap());
         }
 
@@ -203,14 +201,13 @@ impl HttpClient {
         let mut form = Form::new();
         form = form.part("file", part);
 
-        let route = Route::new().path("/channels/{channel_id}/messages")
+        let route = Route::builder().path("/channels/{channel_id}/messages")
             .method(Method::POST)
             .channel_id(channel_id)
             .build();
 
         let mut headers = HeaderMap::new();
         headers.insert("User-Agent", HeaderValue::from_str("GlennBot").unwrap());
-        headers.insert("X-Ratelimit-Precision", HeaderValue::from_str("millisecond").unwrap());
 
         if let Some(token) = self.token.clone() {
             headers.insert(
@@ -224,7 +221,7 @@ impl HttpClient {
         request = request.headers(headers);
         let request = request.multipart(form);
         match request.send().await {
-            Ok(resp) => {
+            Ok(_resp) => {
                 Ok(())
             },
             Err(err) => {
@@ -254,7 +251,7 @@ impl HttpClient {
     ) -> Result<T, Error> {
         let resp = self.request::<P>(route, payload).await;
         match resp {
-            Ok(mut resp) => {
+            Ok(resp) => {
                 //debug!("{}", resp.text().unwrap());
                 resp.json::<T>().await
             },
@@ -266,13 +263,19 @@ impl HttpClient {
     }
 
     pub async fn get_me(&self) -> Result<discord::Me, Error> {
-        self.request_and_parse::<discord::Me, ()>(Route::new()
+        self.request_and_parse::<discord::Me, ()>(Route::builder()
             .path("/users/@me")
             .method(Method::GET).build(), None).await
     }
 
+    pub async fn get_gateway_bot(&self) -> Result<discord::GatewayBot, Error> {
+        self.request_and_parse::<discord::GatewayBot, ()>(Route::builder()
+            .path("/gateway/bot")
+            .method(Method::GET).build(), None).await
+    }
+
     pub async fn get_message(&self, guild_id: String, message_id: String) -> Result<discord::Message, Error> {
-        self.request_and_parse::<discord::Message, ()>(Route::new()
+        self.request_and_parse::<discord::Message, ()>(Route::builder()
             .path("/channels/{channel_id}/messages/{message_id}")
             .method(Method::GET)
             .guild_id(guild_id)
@@ -281,12 +284,12 @@ impl HttpClient {
     }
 
     pub async fn get_guilds(&self) -> Result<Vec<discord::Guild>, Error> {
-        self.request_and_parse::<Vec<discord::Guild>, ()>(Route::new()
+        self.request_and_parse::<Vec<discord::Guild>, ()>(Route::builder()
             .path("/users/@me/guilds").method(Method::GET).build(), None).await
     }
 
     pub async fn get_guild_channels(&self, guild_id: String) -> Result<Vec<discord::Channel>, Error> {
-        self.request_and_parse::<Vec<discord::Channel>, ()>(Route::new()
+        self.request_and_parse::<Vec<discord::Channel>, ()>(Route::builder()
             .path("/guilds/{guild_id}/channels")
             .method(Method::GET)
             .guild_id(guild_id)
@@ -294,7 +297,7 @@ impl HttpClient {
     }
 
     pub async fn get_guilds_with_channels(&self) -> Result<Vec<discord::Guild>, Error> {
-        let guilds = self.request_and_parse::<Vec<discord::Guild>, ()>(Route::new()
+        let guilds = self.request_and_parse::<Vec<discord::Guild>, ()>(Route::builder()
             .path("/users/@me/guilds").method(Method::GET).build(), None).await;
         if let Ok(guilds) = guilds {
             let mut new_guilds = Vec::<discord::Guild>::new();
@@ -312,26 +315,33 @@ impl HttpClient {
     }
 
     pub async fn get_members(&self, guild_id: String) -> Result<Vec<discord::Member>, Error> {
-        self.request_and_parse::<Vec<discord::Member>, ()>(Route::new()
+        self.request_and_parse::<Vec<discord::Member>, ()>(Route::builder()
             .path("/guilds/{guild_id}/members?limit=100")
             .method(Method::GET)
             .guild_id(guild_id)
             .build(), None).await
     }
 
-    pub async fn create_message(&self, channel_id: String, content: String) -> Result<discord::Message, Error> {
-        self.request_and_parse::<discord::Message, discord::CreateMessagePayload>(Route::new()
+    pub async fn create_message(&self, channel_id: String, content: String, allowed_mentions: discord::AllowedMentions) -> Result<discord::Message, Error> {
+        self.request_and_parse::<discord::Message, discord::CreateMessagePayload>(Route::builder()
             .path("/channels/{channel_id}/messages")
             .method(Method::POST)
             .channel_id(channel_id)
-            .build(), Some(discord::CreateMessagePayload {
-                content,
-                tts: false
+            .build(), Some(discord::CreateMessagePayload::new(content, allowed_mentions))).await
+    }
+
+    /// Opens (or returns the existing) DM channel with a user
+    pub async fn create_dm(&self, user_id: String) -> Result<discord::Channel, Error> {
+        self.request_and_parse::<discord::Channel, discord::CreateDMPayload>(Route::builder()
+            .path("/users/@me/channels")
+            .method(Method::POST)
+            .build(), Some(discord::CreateDMPayload {
+                recipient_id: user_id
             })).await
     }
 
     pub async fn create_reaction(&self, channel_id: String, message_id: String, emoji: String) -> Result<(), Error> {
-        let route = Route::new()
+        let route = Route::builder()
             .path("/channels/{channel_id}/messages/{message_id}/reactions/{emoji}/@me")
             .method(Method::PUT)
             .channel_id(channel_id)
@@ -342,7 +352,7 @@ impl HttpClient {
     }
 
     pub async fn add_guild_member_role(&self, guild_id: String, user_id: String, role_id: String) -> Result<(), Error> {
-        let route = Route::new()
+        let route = Route::builder()
             .path("/guilds/{guild_id}/members/{user_id}/roles/{role_id}")
             .method(Method::PUT)
             .guild_id(guild_id)
@@ -353,7 +363,7 @@ impl HttpClient {
     }
 
     pub async fn remove_guild_member_role(&self, guild_id: String, user_id: String, role_id: String) -> Result<(), Error> {
-        let route = Route::new()
+        let route = Route::builder()
             .path("/guilds/{guild_id}/members/{user_id}/roles/{role_id}")
             .method(Method::DELETE)
             .guild_id(guild_id)
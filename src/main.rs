@@ -14,37 +14,62 @@ extern crate async_trait;
 
 use log::*;
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
 use std::collections::HashMap;
-use tokio::time::{delay_for, Duration};
+use std::sync::{Arc, RwLock};
 
 pub mod discord;
 pub mod http;
 pub mod gateway;
 pub mod controller;
+pub mod config;
+pub mod cache;
+pub mod dispatch;
+pub mod reload;
 pub mod rpc;
 
 
+/// Shared by every worker. The caches are behind their own locks, which are
+/// only held while reading or updating them, never across an await.
 pub struct DiscordContext {
     /// The current bot user
     pub me: discord::Me,
     /// Map of guild ID to Guild object
-    pub guild_map: HashMap<String, discord::Guild>,
+    pub guild_map: RwLock<HashMap<String, Arc<discord::Guild>>>,
+    /// Map of guild ID to the members we have seen, by user ID
+    pub member_map: RwLock<HashMap<String, HashMap<String, discord::Member>>>,
+    /// Recent messages, for edits and deletes
+    pub message_cache: RwLock<cache::MessageCache>,
     /// The discord http client
-    pub http_client: http::HttpClient
+    pub http_client: http::HttpClient,
+    /// For sending commands to the gateway
+    pub gateway: gateway::GatewayHandle
 }
 impl DiscordContext {
-    pub fn get_guild(&self, guild_id: &String) -> Option<&discord::Guild> {
-        self.guild_map.get(guild_id)
+    /// A context that is not connected to anything, for tests
+    #[cfg(test)]
+    pub fn empty() -> Self {
+        DiscordContext {
+            me: discord::Me::default(),
+            guild_map: RwLock::new(HashMap::new()),
+            member_map: RwLock::new(HashMap::new()),
+            message_cache: RwLock::new(cache::MessageCache::new(cache::DEFAULT_MESSAGE_CACHE_SIZE)),
+            http_client: http::HttpClient::new(String::new()),
+            gateway: gateway::GatewayHandle::default()
+        }
+    }
+    pub fn get_guild(&self, guild_id: &String) -> Option<Arc<discord::Guild>> {
+        self.guild_map.read().unwrap().get(guild_id).cloned()
+    }
+    pub fn insert_guild(&self, guild: discord::Guild) {
+        self.guild_map.write().unwrap().insert(guild.id.clone(), Arc::new(guild));
     }
-    pub fn get_channel(&self, guild_id: &String, channel_id: &String) -> Option<&discord::Channel> {
+    pub fn get_channel(&self, guild_id: &String, channel_id: &String) -> Option<discord::Channel> {
         if let Some(guild) = self.get_guild(guild_id) {
             match &guild.channels {
                 Some(channels) => {
                     for channel in channels {
                         if channel.id == *channel_id {
-                            return Some(channel)
+                            return Some(channel.clone())
                         }
                     }
                     warn!("[guild_id: {}] Could not find channel id '{}'", guild_id, channel_id);
@@ -58,6 +83,177 @@ impl DiscordContext {
         }
         None
     }
+    pub fn get_member(&self, guild_id: &String, user_id: &String) -> Option<discord::Member> {
+        self.member_map.read().unwrap().get(guild_id).and_then(|members| members.get(user_id)).cloned()
+    }
+    /// Whether as many members are cached as GUILD_CREATE said the guild has
+    pub fn has_all_members(&self, guild: &discord::Guild) -> bool {
+        let cached = self.member_map.read().unwrap().get(&guild.id).map_or(0, |members| members.len());
+        cached > 0 && guild.member_count.is_none_or(|count| cached as u64 >= count)
+    }
+    /// Fills in the parts of an event that only we know, from the caches.
+    /// Has to run before `update` changes them.
+    pub fn annotate(&self, payload: &mut gateway::GatewayMessageType) {
+        match payload {
+            gateway::GatewayMessageType::GuildMemberUpdate(event) => {
+                event.previous_roles = self.get_member(&event.guild_id, &event.user.id)
+                    .map(|member| member.roles);
+            },
+            gateway::GatewayMessageType::GuildMemberRemove(event) => {
+                // `update` drops the member from the cache before rules run
+                event.roles = self.get_member(&event.guild_id, &event.user.id)
+                    .map(|member| member.roles);
+            },
+            gateway::GatewayMessageType::MessageUpdate(event) => {
+                event.previous = self.message_cache.read().unwrap().get(&event.id).cloned();
+            },
+            gateway::GatewayMessageType::MessageDelete(event) => {
+                event.message = self.message_cache.read().unwrap().get(&event.id).cloned();
+            },
+            gateway::GatewayMessageType::MessageDeleteBulk(event) => {
+                let message_cache = self.message_cache.read().unwrap();
+                event.messages = event.ids.iter()
+                    .filter_map(|id| message_cache.get(id).cloned())
+                    .collect();
+            },
+            _ => {}
+        }
+    }
+    /// Keeps the guild and member caches in line with gateway events
+    pub fn update(&self, payload: &gateway::GatewayMessageType) {
+        match payload {
+            gateway::GatewayMessageType::GuildCreate(guild) => {
+                // TODO I dont know if this is good. Might have more info in
+                // the get_guilds call above.
+                self.insert_guild(guild.clone());
+            },
+            gateway::GatewayMessageType::GuildUpdate(guild) => {
+                // GUILD_UPDATE does not carry channels
+                let mut guild_map = self.guild_map.write().unwrap();
+                let channels = guild_map.get(&guild.id).and_then(|old| old.channels.clone());
+                let mut guild = guild.clone();
+                guild.channels = guild.channels.or(channels);
+                guild_map.insert(guild.id.clone(), Arc::new(guild));
+            },
+            // Unavailable guilds come back with a GUILD_CREATE
+            gateway::GatewayMessageType::GuildDelete(guild) if !guild.unavailable => {
+                self.guild_map.write().unwrap().remove(&guild.id);
+                self.member_map.write().unwrap().remove(&guild.id);
+            },
+            gateway::GatewayMessageType::ChannelCreate(channel)
+            | gateway::GatewayMessageType::ChannelUpdate(channel) => {
+                if let Some(guild_id) = channel.guild_id.as_ref() {
+                    self.update_guild(guild_id, |guild| {
+                        let channels = guild.channels.get_or_insert_with(Vec::new);
+                        channels.retain(|c| c.id != channel.id);
+                        channels.push(channel.clone());
+                    });
+                }
+            },
+            gateway::GatewayMessageType::ChannelDelete(channel) => {
+                if let Some(guild_id) = channel.guild_id.as_ref() {
+                    self.update_guild(guild_id, |guild| {
+                        if let Some(channels) = guild.channels.as_mut() {
+                            channels.retain(|c| c.id != channel.id);
+                        }
+                    });
+                }
+            },
+            gateway::GatewayMessageType::GuildRoleCreate(event)
+            | gateway::GatewayMessageType::GuildRoleUpdate(event) => {
+                self.update_guild(&event.guild_id, |guild| {
+                    let roles = guild.roles.get_or_insert_with(Vec::new);
+                    roles.retain(|role| role.id != event.role.id);
+                    roles.push(event.role.clone());
+                });
+            },
+            gateway::GatewayMessageType::GuildRoleDelete(event) => {
+                self.update_guild(&event.guild_id, |guild| {
+                    if let Some(roles) = guild.roles.as_mut() {
+                        roles.retain(|role| role.id != event.role_id);
+                    }
+                });
+            },
+            gateway::GatewayMessageType::GuildEmojisUpdate(event) => {
+                self.update_guild(&event.guild_id, |guild| {
+                    guild.emojis = Some(event.emojis.clone());
+                });
+            },
+            gateway::GatewayMessageType::GuildMembersChunk(chunk) => {
+                for member in chunk.members.iter() {
+                    self.cache_member(&chunk.guild_id, member.clone());
+                }
+            },
+            gateway::GatewayMessageType::GuildMemberAdd(event) => {
+                self.cache_member(&event.guild_id, event.member.clone());
+            },
+            gateway::GatewayMessageType::GuildMemberUpdate(event) => {
+                let mut member = self.get_member(&event.guild_id, &event.user.id).unwrap_or_default();
+                member.user = Some(event.user.clone());
+                member.roles = event.roles.clone();
+                member.nick = event.nick.clone();
+                if event.joined_at.is_some() {
+                    member.joined_at = event.joined_at.clone();
+                }
+                member.premium_since = event.premium_since.clone();
+                member.pending = event.pending;
+                self.cache_member(&event.guild_id, member);
+            },
+            gateway::GatewayMessageType::GuildMemberRemove(event) => {
+                if let Some(members) = self.member_map.write().unwrap().get_mut(&event.guild_id) {
+                    members.remove(&event.user.id);
+                }
+            },
+            gateway::GatewayMessageType::MessageReactionAdd(react) => {
+                if let (Some(guild_id), Some(member)) = (react.guild_id.as_ref(), react.member.as_ref()) {
+                    self.cache_member(guild_id, member.clone());
+                }
+            },
+            gateway::GatewayMessageType::MessageCreate(msg) => {
+                if let (Some(guild_id), Some(member)) = (msg.guild_id.as_ref(), msg.member.as_ref()) {
+                    let member = discord::Member {
+                        user: Some(msg.author.clone()),
+                        ..member.clone()
+                    };
+                    self.cache_member(guild_id, member);
+                }
+                self.message_cache.write().unwrap().insert(msg.clone());
+            },
+            gateway::GatewayMessageType::MessageUpdate(event) => {
+                if let Some(message) = self.message_cache.write().unwrap().get_mut(&event.id) {
+                    if let Some(content) = event.content.as_ref() {
+                        message.content = content.clone();
+                    }
+                    if let Some(attachments) = event.attachments.as_ref() {
+                        message.attachments = attachments.clone();
+                    }
+                    message.edited_timestamp = event.edited_timestamp.clone().or(message.edited_timestamp.take());
+                }
+            },
+            gateway::GatewayMessageType::MessageDelete(event) => {
+                self.message_cache.write().unwrap().remove(&event.id);
+            },
+            gateway::GatewayMessageType::MessageDeleteBulk(event) => {
+                let mut message_cache = self.message_cache.write().unwrap();
+                for id in event.ids.iter() {
+                    message_cache.remove(id);
+                }
+            },
+            _ => {}
+        }
+    }
+    /// Changes a cached guild. Workers holding the old one keep it.
+    fn update_guild<F: FnOnce(&mut discord::Guild)>(&self, guild_id: &String, change: F) {
+        if let Some(guild) = self.guild_map.write().unwrap().get_mut(guild_id) {
+            change(Arc::make_mut(guild));
+        }
+    }
+    /// Members without a user can't be looked up, so they are skipped
+    pub fn cache_member(&self, guild_id: &str, member: discord::Member) {
+        if let Some(user_id) = member.user.as_ref().map(|user| user.id.clone()) {
+            self.member_map.write().unwrap().entry(guild_id.to_owned()).or_default().insert(user_id, member);
+        }
+    }
 }
 
 #[tokio::main]
@@ -65,12 +261,11 @@ async fn main() {
     dotenv::dotenv().ok();
     env_logger::init();
     let token = env::var("DISCORD_BOT_TOKEN").expect("Must supply DISCORD_BOT_TOKEN in env");
-    let mut guild_map: HashMap<String, discord::Guild> = HashMap::new();
+    let mut guild_map: HashMap<String, Arc<discord::Guild>> = HashMap::new();
 
     // Load config
-    let mut config_string = String::new();
-    File::open("./config.json").expect("Could not open config").read_to_string(&mut config_string).expect("Could not read config");
-    let config = serde_json::de::from_str::<Vec<controller::ConfigSchema>>(config_string.as_str()).expect("Could not parse config");
+    let config_path = config::config_path(env::args().skip(1), env::var(config::CONFIG_ENV).ok());
+    let config = config::Config::load(&config_path).unwrap_or_else(|e| panic!("{}", e));
 
     let discord = http::HttpClient::new(token.clone());
     let me = if let Ok(me) = discord.get_me().await {
@@ -89,65 +284,64 @@ async fn main() {
             for channel in guild.clone().channels.unwrap() {
                 info!("Found channel: {}", channel.name.unwrap());
             }
-            guild_map.insert(key, guild);
+            guild_map.insert(key, Arc::new(guild));
         }
     });
 
 
-    let mut context = DiscordContext {
-        guild_map,
-        me,
-        http_client: discord
-    };
-    let controller = controller::Controller::new(config);
+    let controller = controller::Controller::new(config.guilds.clone()).unwrap_or_else(|e| {
+        panic!("Invalid config: {}", e);
+    });
+    let intents = controller.intents(&config.gateway.intents);
+    let shards = gateway::ShardManager::new(token.clone(), intents, &config.gateway, &discord).await;
+    // Every shard feeds into the same controller
+    let (mut events, gateway) = shards.start();
 
+    let context = Arc::new(DiscordContext {
+        guild_map: RwLock::new(guild_map),
+        member_map: RwLock::new(HashMap::new()),
+        message_cache: RwLock::new(cache::MessageCache::new(
+            config.message_cache_size.unwrap_or(cache::DEFAULT_MESSAGE_CACHE_SIZE)
+        )),
+        me,
+        http_client: discord,
+        gateway
+    });
+    let controller = Arc::new(reload::SharedController::new(controller));
+    let mut dispatcher = dispatch::Dispatcher::start(&config.dispatch, context.clone(), controller.clone());
+    reload::Reloader::new(config_path, config, intents, controller).spawn();
 
-    loop {
-        let mut gw = gateway::GatewayClient::new(token.clone());
-        match gw.start().await {
-            Ok(_) => {}
-            Err(_) => {
-                error!("Could not start bot :( Trying again...");
-                delay_for(Duration::from_secs(5)).await;
-                continue
-            }
+    // Caches are updated here, in gateway order, before the event is handed
+    // to a worker
+    while let Some(mut msg) = events.recv().await {
+        if let Some(payload) = msg.d.as_mut() {
+            context.annotate(payload);
         }
-        info!("Connected to gateway");
-        loop {
-            if let Some(msg) = gw.next().await {
-                if let Some(payload) = msg.d.as_ref() {
-                    match payload {
-                        gateway::GatewayMessageType::Reconnect(_) => {
-                            gw.stop_heartbeat();
-                            break;
-                        },
-                        gateway::GatewayMessageType::GuildCreate(guild) => {
-                            let guild_in_map = context.guild_map.get_mut(&guild.id);
-                            match guild_in_map {
-                                Some(guild_in_map) => {
-                                    // TODO I dont know if this is good. Might have more info in
-                                    // the get_guilds call above.
-                                    *guild_in_map = guild.to_owned();
-                                },
-                                None => {
-                                    context.guild_map.insert(guild.id.clone(), guild.clone());
-                                }
-                            }
-                        },
-                        _ => {}
+        if let Some(payload) = msg.d.as_ref() {
+            context.update(payload);
+            match payload {
+                gateway::GatewayMessageType::Ready(ready) => {
+                    if let Some((shard_id, num_shards)) = ready.shard {
+                        info!("Shard {}/{} ready", shard_id + 1, num_shards);
                     }
-                }
-                controller.handle_event(&context, msg).await;
-                //match msg {
-                //    gateway::GatewayMessageType::READY(ready) => {
-                //        info!("READY: {}", ready.d.unwrap().user.username);
-                //    },
-                //    gateway::GatewayMessageType::MESSAGE_CREATE(message) => {
-                //        let message = message.d.unwrap();
-                //    },
-                //    _ => {}
-                //}
+                },
+                // Reconnects and guilds coming back from an outage send
+                // GUILD_CREATE again; their members are usually cached
+                gateway::GatewayMessageType::GuildCreate(guild)
+                    if intents & gateway::Intent::GUILD_MEMBERS.bit() != 0 && !context.has_all_members(guild) => {
+                    // The chunks come back through here and fill the member cache
+                    let gateway = context.gateway.clone();
+                    let guild_id = guild.id.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = gateway.fetch_guild_members(guild_id.clone()).await {
+                            warn!("[guild_id: {}] Could not fetch guild members: {}", guild_id, e);
+                        }
+                    });
+                },
+                _ => {}
             }
         }
+        dispatcher.dispatch(msg).await;
     }
+    error!("All shards stopped");
 }
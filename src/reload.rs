@@ -0,0 +1,246 @@
+/// Reloads the rules in the config file without restarting. The file is
+/// checked for changes every few seconds, and SIGHUP reloads it right away.
+///
+use log::*;
+use std::collections::HashMap;
+use std::fs;
+use std::panic::{self, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::interval;
+
+use crate::config::Config;
+use crate::controller::{ConfigSchema, Controller};
+
+/// How often the config file's modified time is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The controller the workers run events through. Swapped out whole on
+/// reload; events already being handled finish with the old one.
+pub struct SharedController {
+    current: RwLock<Arc<Controller>>
+}
+impl SharedController {
+    pub fn new(controller: Controller) -> Self {
+        SharedController {
+            current: RwLock::new(Arc::new(controller))
+        }
+    }
+    pub fn current(&self) -> Arc<Controller> {
+        self.current.read().unwrap().clone()
+    }
+    fn replace(&self, controller: Controller) {
+        *self.current.write().unwrap() = Arc::new(controller);
+    }
+}
+
+pub struct Reloader {
+    path: PathBuf,
+    /// The config the running controller was built from
+    config: Config,
+    modified: Option<SystemTime>,
+    /// What the gateway identified with
+    intents: u32,
+    controller: Arc<SharedController>
+}
+
+impl Reloader {
+    pub fn new(path: PathBuf, config: Config, intents: u32, controller: Arc<SharedController>) -> Self {
+        let modified = modified(&path);
+        Reloader { path, config, modified, intents, controller }
+    }
+
+    /// Watches for changes until the bot stops
+    pub fn spawn(mut self) {
+        tokio::spawn(async move {
+            let mut poll = interval(POLL_INTERVAL);
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => Some(hangup),
+                Err(e) => {
+                    warn!("Could not listen for SIGHUP, only watching {}: {}", self.path.display(), e);
+                    None
+                }
+            };
+            loop {
+                let reason = tokio::select! {
+                    _ = poll.tick() => {
+                        if modified(&self.path) == self.modified {
+                            continue
+                        }
+                        "file changed"
+                    },
+                    Some(_) = async { hangup.as_mut()?.recv().await } => "SIGHUP"
+                };
+                if let Err(e) = self.reload(reason) {
+                    error!("Not reloading {} ({}), keeping the running rules: {}", self.path.display(), reason, e);
+                }
+            }
+        });
+    }
+
+    /// Replaces the controller if the config parses and every rule is valid
+    fn reload(&mut self, reason: &str) -> Result<(), String> {
+        // Whatever the outcome, this version of the file has been seen
+        self.modified = modified(&self.path);
+        let path = self.path.as_path();
+        let (config, mut controller) = catch_panic(|| {
+            let config = Config::load(path)?;
+            let controller = Controller::new(config.guilds.clone()).map_err(|e| e.to_string())?;
+            Ok((config, controller))
+        })?;
+        controller.keep_limits(&self.controller.current());
+        for intent in controller.missing_intents(self.intents) {
+            warn!("Reloaded rules need the {:?} intent, which needs a restart to enable", intent);
+        }
+        if without_rules(&config) != without_rules(&self.config) {
+            warn!("Only rules are reloaded. Restart to apply the rest of {}", self.path.display());
+        }
+
+        let (added, removed) = diff(&self.config.guilds, &config.guilds);
+        self.controller.replace(controller);
+        info!(
+            "Reloaded {} ({}): {} rule(s) added, {} removed",
+            self.path.display(), reason, added.len(), removed.len()
+        );
+        for rule in added {
+            info!("  + {}", rule);
+        }
+        for rule in removed {
+            info!("  - {}", rule);
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+/// A panic while parsing would end the reload task, and with it reloading.
+/// It is an error like any other bad config instead.
+fn catch_panic<T>(parse: impl FnOnce() -> Result<T, String> + UnwindSafe) -> Result<T, String> {
+    panic::catch_unwind(parse).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(format!("Panicked while loading: {}", message))
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Everything in the config that can't be reloaded
+fn without_rules(config: &Config) -> serde_json::Value {
+    serde_json::to_value(Config {
+        guilds: vec![],
+        ..config.clone()
+    }).unwrap_or_default()
+}
+
+/// Every rule as `[guild_id: 1] rules[0] MESSAGE_CREATE`, by its JSON
+fn describe_rules(guilds: &[ConfigSchema]) -> Vec<(String, String)> {
+    let mut rules = vec![];
+    for schema in guilds {
+        let scope = match schema.guild_id.as_ref() {
+            Some(guild_id) => format!("guild_id: {}", guild_id),
+            None => String::from("global")
+        };
+        for (index, rule) in schema.rules.iter().enumerate() {
+            let value = serde_json::to_value(rule).unwrap_or_default();
+            let event = value.get("event").and_then(|event| event.as_str()).unwrap_or_default().to_owned();
+            rules.push((
+                format!("[{}] rules[{}] {}", scope, index, event),
+                format!("{}{}", scope, value)
+            ));
+        }
+    }
+    rules
+}
+
+/// The rules only in `new`, and the rules only in `old`. A rule that moved
+/// is neither.
+fn diff(old: &[ConfigSchema], new: &[ConfigSchema]) -> (Vec<String>, Vec<String>) {
+    let mut unmatched = HashMap::<String, Vec<String>>::new();
+    for (description, key) in describe_rules(old) {
+        unmatched.entry(key).or_default().push(description);
+    }
+    let mut added = vec![];
+    for (description, key) in describe_rules(new) {
+        match unmatched.get_mut(&key).and_then(|descriptions| descriptions.pop()) {
+            Some(_) => {},
+            None => added.push(description)
+        }
+    }
+    let mut removed: Vec<String> = unmatched.into_values().flatten().collect();
+    removed.sort();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ECHO: &str = r#"{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi","file":null}},"filters":{"content":"^!hi"}}"#;
+    const PONG: &str = r#"{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"pong","file":null}},"filters":{"content":"^!ping"}}"#;
+
+    fn guilds(rules: &[&str]) -> Vec<ConfigSchema> {
+        serde_json::from_str(&format!(r#"[{{"rules":[{}],"guild_id":"1"}}]"#, rules.join(","))).unwrap()
+    }
+
+    #[test]
+    fn diff_rules() {
+        let (added, removed) = diff(&guilds(&[ECHO]), &guilds(&[PONG, ECHO]));
+        assert_eq!(added, vec!["[guild_id: 1] rules[0] MESSAGE_CREATE"]);
+        assert!(removed.is_empty());
+
+        let (added, removed) = diff(&guilds(&[ECHO, PONG]), &guilds(&[PONG]));
+        assert!(added.is_empty());
+        assert_eq!(removed, vec!["[guild_id: 1] rules[0] MESSAGE_CREATE"]);
+    }
+
+    #[test]
+    fn changed_options_are_a_different_rule() {
+        let webhook = |header: &str| format!(
+            r#"{{"event":"MESSAGE_CREATE","action":{{"type":"Webhook","options":{{"url":"http://localhost","headers":{{"authorization":"{}"}}}}}},"filters":{{"content":"^!hook"}}}}"#,
+            header
+        );
+        let (added, removed) = diff(&guilds(&[&webhook("old"), ECHO]), &guilds(&[&webhook("new"), ECHO]));
+        assert_eq!(added, vec!["[guild_id: 1] rules[0] MESSAGE_CREATE"]);
+        assert_eq!(removed, vec!["[guild_id: 1] rules[0] MESSAGE_CREATE"]);
+
+        let templated = ECHO.replace(r#""content":"hi""#, r#""content":"hi {{author.mention}}""#);
+        let (added, removed) = diff(&guilds(&[PONG, ECHO]), &guilds(&[PONG, &templated]));
+        assert_eq!(added, vec!["[guild_id: 1] rules[1] MESSAGE_CREATE"]);
+        assert_eq!(removed, vec!["[guild_id: 1] rules[1] MESSAGE_CREATE"]);
+    }
+
+    #[test]
+    fn panics_are_load_errors() {
+        assert_eq!(catch_panic(|| Ok(1)), Ok(1));
+        assert_eq!(catch_panic::<()>(|| panic!("bad header")).unwrap_err(), "Panicked while loading: bad header");
+    }
+
+    #[test]
+    fn invalid_config_is_not_loaded() {
+        let path = std::env::temp_dir().join(format!("glennbot-reload-{}.json", std::process::id()));
+        let config = Config { guilds: guilds(&[ECHO]), ..Default::default() };
+        let controller = Arc::new(SharedController::new(Controller::new(config.guilds.clone()).unwrap()));
+        let mut reloader = Reloader::new(path.clone(), config, 0, controller.clone());
+
+        fs::write(&path, format!(r#"[{{"rules":[{}],"guild_id":"1"}}]"#, PONG)).unwrap();
+        let before = controller.current();
+        reloader.reload("test").unwrap();
+        assert!(!Arc::ptr_eq(&before, &controller.current()));
+        // The next poll doesn't load it again
+        assert_eq!(reloader.modified, modified(&path));
+
+        let before = controller.current();
+        fs::write(&path, r#"[{"rules":[{"event":"MESSAGE_CREATE","action":{"type":"Echo","options":{"content":"hi","file":null}},"filters":{"content":"("}}],"guild_id":"1"}]"#).unwrap();
+        assert!(reloader.reload("test").unwrap_err().contains("rules[0].filters.content"));
+        fs::write(&path, "{not json").unwrap();
+        assert!(reloader.reload("test").is_err());
+        assert!(Arc::ptr_eq(&before, &controller.current()));
+        fs::remove_file(&path).unwrap();
+    }
+}